@@ -0,0 +1,81 @@
+use mail::mock_gmail::{Fault, Mailbox, MockGmail};
+use std::path::Path;
+use std::process::exit;
+
+const USAGE: &str = "usage: mock_gmail <fixture directory> [--port <port>] [--fault <401|429|500|malformed|part-429>[:<times>]]...";
+
+/// Serves a fixture mailbox like the Gmail API, for working on the sync without a Google
+/// account. Point `api_url` and `oauth.token_uri` in the Gmail credentials at it.
+#[tokio::main]
+async fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    let Some((directory, mut options)) = arguments.split_first() else {
+        eprintln!("{USAGE}");
+        exit(1)
+    };
+
+    let mut port = 8025;
+    let mut faults = Vec::new();
+    while let [flag, value, rest @ ..] = options {
+        match flag.as_str() {
+            "--port" => match value.parse() {
+                Ok(value) => port = value,
+                Err(error) => {
+                    eprintln!("could not parse '{value}' as port: {error}");
+                    exit(1)
+                }
+            },
+            "--fault" => {
+                let (name, times) = value.split_once(':').unwrap_or((value, "1"));
+                match (Fault::from_name(name), times.parse()) {
+                    (Some(fault), Ok(times)) => faults.push((fault, times)),
+                    _ => {
+                        eprintln!("could not parse fault '{value}'\n{USAGE}");
+                        exit(1)
+                    }
+                }
+            }
+            _ => {
+                eprintln!("unknown option '{flag}'\n{USAGE}");
+                exit(1)
+            }
+        }
+        options = rest;
+    }
+    if !options.is_empty() {
+        eprintln!("{USAGE}");
+        exit(1)
+    }
+
+    let mailbox = match Mailbox::load(Path::new(directory)) {
+        Ok(mailbox) => mailbox,
+        Err(error) => {
+            eprintln!("could not load mailbox from '{directory}': {error}");
+            exit(1)
+        }
+    };
+    let messages = mailbox.len();
+    let email_address = mailbox.email_address().to_string();
+
+    let server = match MockGmail::bind(&format!("127.0.0.1:{port}"), mailbox).await {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!("could not start mock gmail: {error}");
+            exit(1)
+        }
+    };
+    for (fault, times) in faults {
+        server.inject(fault, times);
+    }
+
+    println!(
+        "serving {messages} messages of {email_address} on {}, tokens on {}",
+        server.url(),
+        server.token_url()
+    );
+
+    if let Err(error) = tokio::signal::ctrl_c().await {
+        eprintln!("could not wait for ctrl-c: {error}");
+        exit(1)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::dedupe::Duplicates;
+use crate::error::MailError;
+use crate::mime;
+use crate::threading::Threads;
+use crate::utils;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// The JSON returned by Gmail's `messages.get` with `format=full`.
+    Full,
+    /// RFC 822 bytes, as returned by Gmail's `format=raw` (base64 decoded) or any other source.
+    Raw,
+}
+
+/// What the source knows about a message outside of its content. For raw messages this is the
+/// only place the thread and labels are kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageMetadata {
+    #[serde(default = "default_source")]
+    pub source: String,
+    #[serde(default)]
+    pub account: String,
+    pub history_id: Option<String>,
+    #[serde(default)]
+    pub thread_id: Option<String>,
+    /// Labels as the source names them, e.g. Gmail label ids.
+    #[serde(default)]
+    pub label_ids: Vec<String>,
+    /// `label_ids` translated to `search::LABELS`.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// When the source received the message as unix timestamp, e.g. Gmail's internalDate.
+    #[serde(default)]
+    pub time: Option<i64>,
+}
+
+// entries cached before other sources existed all came from Gmail.
+fn default_source() -> String {
+    "gmail".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub metadata: MessageMetadata,
+    pub format: MessageFormat,
+    pub hash: String,
+}
+
+/// Messages exactly as their source returned them, gzipped and stored by the SHA-256 of their
+/// content under `objects/`. `index.json` maps every message id to its object and metadata,
+/// `threads.json` the threads of messages whose source has no thread ids and `duplicates.json`
+/// which messages are copies of each other. How far sources have been synced is kept in the
+/// `state::StateStore`.
+pub struct MessageCache {
+    path: PathBuf,
+    entries: BTreeMap<String, CacheEntry>,
+    threads: Threads,
+    duplicates: Duplicates,
+    /// Whether anything changed since the last `save`.
+    changed: bool,
+}
+
+impl MessageCache {
+    pub fn open(path: &Path) -> Result<Self, MailError> {
+        fs::create_dir_all(path.join("objects")).map_err(|error| {
+            MailError::Storage(format!(
+                "could not create cache in '{}': {error}",
+                path.display()
+            ))
+        })?;
+
+        let index_path = path.join("index.json");
+        let entries = if index_path.exists() {
+            utils::read_json(&index_path.display().to_string())
+                .map_err(|error| MailError::Storage(error.to_string()))?
+        } else {
+            BTreeMap::new()
+        };
+
+        let threads_path = path.join("threads.json");
+        let threads = if threads_path.exists() {
+            utils::read_json(&threads_path.display().to_string())
+                .map_err(|error| MailError::Storage(error.to_string()))?
+        } else {
+            Threads::default()
+        };
+
+        let duplicates_path = path.join("duplicates.json");
+        let duplicates = if duplicates_path.exists() {
+            utils::read_json(&duplicates_path.display().to_string())
+                .map_err(|error| MailError::Storage(error.to_string()))?
+        } else {
+            Duplicates::default()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+            threads,
+            duplicates,
+            changed: false,
+        })
+    }
+
+    /// Writes the index, threads and duplicates if they changed. They are rewritten in full,
+    /// so callers save once per run instead of after every message or batch.
+    pub fn save(&mut self) -> Result<(), MailError> {
+        if !self.changed {
+            return Ok(());
+        }
+        write_json(&self.path.join("index.json"), &self.entries)?;
+        write_json(&self.path.join("threads.json"), &self.threads)?;
+        write_json(&self.path.join("duplicates.json"), &self.duplicates)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &CacheEntry)> {
+        self.entries.iter()
+    }
+
+    pub fn entry(&self, message_id: &str) -> Option<&CacheEntry> {
+        self.entries.get(message_id)
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.entries.contains_key(message_id)
+    }
+
+    /// Drops a message from the index, for instance when Gmail reports it changed. The object
+    /// stays until `prune` is called.
+    pub fn invalidate(&mut self, message_id: &str) {
+        self.changed = true;
+        self.entries.remove(message_id);
+        self.threads.remove(message_id);
+    }
+
+    /// Drops a message that was deleted at its source. Returns the other copies of it, one of
+    /// them may have become the canonical copy and has to be indexed.
+    pub fn remove(&mut self, message_id: &str) -> Vec<String> {
+        self.invalidate(message_id);
+        self.duplicates
+            .remove(message_id)
+            .into_iter()
+            .filter(|duplicate| self.entries.contains_key(duplicate))
+            .collect()
+    }
+
+    /// Records the dedupe key of a message, see `dedupe::key`.
+    pub fn add_duplicate(&mut self, message_id: &str, key: &str) {
+        self.changed = true;
+        self.duplicates.add(message_id, key);
+    }
+
+    /// The copies of a message across sources and accounts, canonical first.
+    pub fn duplicates(&self, message_id: &str) -> Vec<String> {
+        self.duplicates.group(message_id)
+    }
+
+    /// The thread of a message whose source has no thread ids, see `threading::Threads`.
+    pub fn thread_id(&self, message_id: &str) -> Option<String> {
+        self.threads.thread_id(message_id)
+    }
+
+    /// Stores a message. Raw messages without a thread id from their source are threaded by
+    /// their headers, which can move other messages into a different thread; their ids are
+    /// returned, as they have to be indexed again.
+    pub fn put(
+        &mut self,
+        message_id: &str,
+        metadata: MessageMetadata,
+        format: MessageFormat,
+        content: &[u8],
+    ) -> Result<Vec<String>, MailError> {
+        let hash = format!("{:x}", Sha256::digest(content));
+        let object_path = self.object_path(&hash);
+
+        if !object_path.exists() {
+            if let Some(parent) = object_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            let compressed = encoder.finish()?;
+
+            write_atomically(&object_path, &compressed)?;
+        }
+
+        let regrouped = if format == MessageFormat::Raw && metadata.thread_id.is_none() {
+            self.threads.add(message_id, &mime::headers(content))
+        } else {
+            Vec::new()
+        };
+
+        self.changed = true;
+        self.entries.insert(
+            message_id.to_string(),
+            CacheEntry {
+                metadata,
+                format,
+                hash,
+            },
+        );
+
+        Ok(regrouped)
+    }
+
+    pub fn get(&self, message_id: &str) -> Result<Option<Vec<u8>>, MailError> {
+        let Some(entry) = self.entries.get(message_id) else {
+            return Ok(None);
+        };
+
+        let object_path = self.object_path(&entry.hash);
+        let compressed = fs::read(&object_path).map_err(|error| {
+            MailError::Storage(format!(
+                "could not read '{}': {error}",
+                object_path.display()
+            ))
+        })?;
+
+        let mut content = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut content)
+            .map_err(|error| {
+                MailError::Storage(format!(
+                    "could not decompress '{}': {error}",
+                    object_path.display()
+                ))
+            })?;
+
+        let hash = format!("{:x}", Sha256::digest(&content));
+        if hash != entry.hash {
+            return Err(MailError::Storage(format!(
+                "cached message {message_id} is corrupt, expected hash {} got {hash}",
+                entry.hash
+            )));
+        }
+
+        Ok(Some(content))
+    }
+
+    /// Deletes objects no message in the index refers to anymore.
+    pub fn prune(&self) -> Result<usize, MailError> {
+        let referenced: HashSet<&str> = self
+            .entries
+            .values()
+            .map(|entry| entry.hash.as_str())
+            .collect();
+
+        let mut removed = 0;
+        for directory in fs::read_dir(self.path.join("objects"))? {
+            for object in fs::read_dir(directory?.path())? {
+                let object_path = object?.path();
+                let hash = object_path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or_default();
+
+                if !referenced.contains(hash) {
+                    fs::remove_file(&object_path)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.path
+            .join("objects")
+            .join(&hash[..2])
+            .join(format!("{hash}.gz"))
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), MailError> {
+    let content = serde_json::to_vec_pretty(value)
+        .map_err(|error| MailError::Storage(format!("could not serialize cache: {error}")))?;
+    write_atomically(path, &content)
+}
+
+/// Writes next to `path` and renames, so a crash never leaves a truncated file behind.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<(), MailError> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    fs::write(&temporary_path, content).map_err(|error| {
+        MailError::Storage(format!(
+            "could not write '{}': {error}",
+            temporary_path.display()
+        ))
+    })?;
+    fs::rename(&temporary_path, path).map_err(|error| {
+        MailError::Storage(format!(
+            "could not rename '{}' to '{}': {error}",
+            temporary_path.display(),
+            path.display()
+        ))
+    })?;
+    Ok(())
+}
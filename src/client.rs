@@ -1,24 +1,78 @@
-use std::process::exit;
-use std::{collections::HashMap, error::Error};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use reqwest::{
-    StatusCode,
-    blocking::{Client as HttpClient, RequestBuilder, Response},
-};
-use serde::{Deserialize, Serialize};
+use reqwest::{Client as HttpClient, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
 
+use crate::error::MailError;
 use crate::{constants, utils};
 
+/// Shared by concurrent requests, so the credentials and the quota are behind locks.
 pub struct GmailClient {
     pub client: HttpClient,
-    credentials: Credentials,
+    credentials: Mutex<Credentials>,
+    /// Where refreshed access tokens are saved, they are only kept in memory without one.
+    credentials_path: Option<PathBuf>,
+    api_url: String,
+    limiter: Mutex<QuotaLimiter>,
+}
+
+/// A token bucket of Gmail quota units. It starts full, refills at the per-user rate and lets a
+/// request that costs more than the whole bucket, like a large batch, through once it is full
+/// by going into debt.
+struct QuotaLimiter {
+    units: f64,
+    last_refill: Instant,
+}
+
+impl QuotaLimiter {
+    fn new() -> Self {
+        Self {
+            units: constants::GMAIL_QUOTA_UNITS_PER_SECOND,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.units = (self.units + elapsed * constants::GMAIL_QUOTA_UNITS_PER_SECOND)
+            .min(constants::GMAIL_QUOTA_UNITS_PER_SECOND);
+        self.last_refill = now;
+    }
+
+    /// Waits until `units` can be spent. Holding the lock while waiting queues the requests
+    /// behind each other.
+    async fn acquire(&mut self, units: u32) {
+        let needed = f64::from(units).min(constants::GMAIL_QUOTA_UNITS_PER_SECOND);
+
+        self.refill();
+        if self.units < needed {
+            let wait = (needed - self.units) / constants::GMAIL_QUOTA_UNITS_PER_SECOND;
+            sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+        self.units -= f64::from(units);
+    }
+
+    /// Gmail says we went too fast, so start from an empty bucket.
+    fn drain(&mut self) {
+        self.refill();
+        self.units = self.units.min(0.0);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Credentials {
     pub oauth: CredentialsOAuth,
     pub token: CredentialsToken,
+    /// Where the Gmail API is, `GMAIL_API_URL` unless it is e.g. a mock server. Access tokens
+    /// are refreshed at `oauth.token_uri`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,81 +97,245 @@ pub struct CredentialsToken {
 }
 
 impl GmailClient {
-    pub fn new() -> Self {
+    /// Uses the credentials in `GMAIL_CREDENTIALS` and saves refreshed access tokens there.
+    pub fn new() -> Result<Self, MailError> {
+        let path = constants::GMAIL_CREDENTIALS.display().to_string();
+        let credentials = utils::read_json(&path).map_err(|error| {
+            MailError::Config(format!("could not read gmail credentials: {error}"))
+        })?;
+
+        let mut client = Self::with_credentials(credentials);
+        client.credentials_path = Some(constants::GMAIL_CREDENTIALS.to_path_buf());
+        Ok(client)
+    }
+
+    /// Keeps refreshed access tokens in memory only, e.g. for tests against a mock server.
+    pub fn with_credentials(credentials: Credentials) -> Self {
+        let api_url = credentials
+            .api_url
+            .as_deref()
+            .unwrap_or(constants::GMAIL_API_URL)
+            .trim_end_matches('/')
+            .to_string();
+
         Self {
             client: HttpClient::new(),
-            credentials: match utils::read_json(&constants::CREDENTIALS_PATH.display().to_string())
-            {
-                Ok(credentials) => credentials,
-                Err(error) => {
-                    eprintln!("could not get credentials: {}", error);
-                    exit(1);
-                }
-            },
+            credentials: Mutex::new(credentials),
+            credentials_path: None,
+            api_url,
+            limiter: Mutex::new(QuotaLimiter::new()),
         }
     }
 
-    pub fn refresh_access_token(&mut self) -> Result<(), Box<dyn Error>> {
-        let refresh_token = self
-            .credentials
+    /// `path` on the Gmail API, e.g. `/gmail/v1/users/me/profile`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.api_url)
+    }
+
+    pub async fn refresh_access_token(&self) -> Result<(), MailError> {
+        let mut credentials = self.credentials.lock().await;
+        self.refresh(&mut credentials).await
+    }
+
+    async fn refresh(&self, credentials: &mut Credentials) -> Result<(), MailError> {
+        let refresh_token = credentials
             .token
             .refresh_token
             .as_ref()
-            .expect("no refresh token in credentials")
+            .ok_or_else(|| MailError::Auth("no refresh token in gmail credentials".to_string()))?
             .to_string();
 
         let mut form: HashMap<&str, String> = HashMap::new();
-        form.insert("client_id", self.credentials.oauth.client_id.to_string());
-        form.insert(
-            "client_secret",
-            self.credentials.oauth.client_secret.to_string(),
-        );
+        form.insert("client_id", credentials.oauth.client_id.to_string());
+        form.insert("client_secret", credentials.oauth.client_secret.to_string());
         form.insert("refresh_token", refresh_token);
         form.insert("grant_type", "refresh_token".to_string());
 
-        let response: CredentialsToken = self
+        let response = self
             .client
-            .post("https://oauth2.googleapis.com/token")
+            .post(&credentials.oauth.token_uri)
             .header("Content-Type", "application/x-www-form-urlencoded")
             .form(&form)
-            .send()?
-            .error_for_status()?
-            .json()?;
+            .send()
+            .await?;
 
-        self.credentials.token.access_token = response.access_token;
+        // a revoked or expired refresh token is answered with 400 invalid_grant.
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(MailError::Auth(format!(
+                "could not refresh access token, {status}: {}",
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        let response: CredentialsToken = json(response).await?;
 
-        utils::write_struct_to_file(
-            &self.credentials,
-            &constants::GMAIL_CREDENTIALS.display().to_string(),
-        )?;
+        credentials.token.access_token = response.access_token;
+
+        if let Some(path) = &self.credentials_path {
+            utils::write_struct_to_file(&*credentials, &path.display().to_string()).map_err(
+                |error| MailError::Config(format!("could not save gmail credentials: {error}")),
+            )?;
+        }
 
         Ok(())
     }
 
-    pub fn send<F>(&mut self, build: F) -> Result<Response, Box<dyn Error>>
+    /// Sends the request built by `build` once `quota_units` of the Gmail quota are available
+    /// and returns the response if it was successful. An expired access token is refreshed
+    /// once. Rate limits (429 or 403 `rateLimitExceeded`), server errors and network errors are
+    /// retried with jittered exponential backoff, waiting at least as long as `Retry-After`
+    /// asks.
+    pub async fn send<F>(&self, quota_units: u32, build: F) -> Result<Response, MailError>
     where
         F: Fn(&HttpClient) -> RequestBuilder,
     {
-        let response = build(&self.client)
-            .bearer_auth(&self.credentials.token.access_token)
-            .send()?;
+        let mut refreshed = false;
+        let mut attempt = 0;
+
+        loop {
+            self.limiter.lock().await.acquire(quota_units).await;
+
+            let access_token = self.credentials.lock().await.token.access_token.to_string();
+            let response = match build(&self.client).bearer_auth(&access_token).send().await {
+                Ok(response) => response,
+                Err(error) if attempt < constants::GMAIL_MAXIMUM_RETRIES => {
+                    let delay = backoff(attempt);
+                    eprintln!("request failed, retrying in {delay:?}: {error}");
+                    sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(error) => Err(error)?,
+            };
+
+            println!(
+                "[REQUEST] {} {}",
+                response.url().as_str(),
+                response.status()
+            );
+
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            if status == StatusCode::UNAUTHORIZED && !refreshed {
+                let mut credentials = self.credentials.lock().await;
+                // a concurrent request may have refreshed the token already.
+                if credentials.token.access_token == access_token {
+                    self.refresh(&mut credentials).await?;
+                }
+                refreshed = true;
+                continue;
+            }
 
-        if response.status() == StatusCode::UNAUTHORIZED {
-            self.refresh_access_token()?;
+            let retry_after = retry_after(&response);
+            let url = response.url().to_string();
+            let body = response.text().await.unwrap_or_default();
 
-            let retry = build(&self.client)
-                .bearer_auth(&self.credentials.token.access_token)
-                .send()?;
+            let rate_limited = is_rate_limited(status, &body);
 
-            println!("[REQUEST] {} {}", retry.url().as_str(), retry.status());
-            return Ok(retry);
+            if status == StatusCode::UNAUTHORIZED {
+                return Err(MailError::Auth(format!(
+                    "{url} answered {status} with a fresh access token: {body}"
+                )));
+            }
+            if rate_limited && attempt >= constants::GMAIL_MAXIMUM_RETRIES {
+                return Err(MailError::Quota {
+                    url,
+                    retries: attempt,
+                });
+            }
+            if !rate_limited
+                && (!status.is_server_error() || attempt >= constants::GMAIL_MAXIMUM_RETRIES)
+            {
+                return Err(MailError::Http {
+                    url,
+                    status: status.as_u16(),
+                    body,
+                });
+            }
+
+            if rate_limited {
+                self.limiter.lock().await.drain();
+            }
+            let delay = backoff(attempt).max(retry_after.unwrap_or_default());
+            eprintln!("{url} answered {status}, retrying in {delay:?}");
+            sleep(delay).await;
+            attempt += 1;
         }
+    }
 
-        println!(
-            "[REQUEST] {} {}",
-            response.url().as_str(),
-            response.status()
-        );
-        Ok(response)
+    /// Waits like `send` does before the `attempt`th retry, for requests inside a batch that
+    /// were rate limited or failed on the server.
+    pub async fn back_off(&self, attempt: u32, rate_limited: bool) {
+        if rate_limited {
+            self.limiter.lock().await.drain();
+        }
+        sleep(backoff(attempt)).await;
     }
 }
+
+/// Gmail answers 429, or 403 with `rateLimitExceeded` or `userRateLimitExceeded`, when we go
+/// too fast. 403 is also used for real permission errors, those are not worth waiting for.
+pub fn is_rate_limited(status: StatusCode, body: &str) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN
+            && (body.contains("rateLimitExceeded") || body.contains("userRateLimitExceeded")))
+}
+
+/// The response if it was successful, otherwise the error it stands for: 401 means the
+/// credentials are no good, anything else is an HTTP error.
+pub async fn checked(response: Response) -> Result<Response, MailError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().to_string();
+    let body = response.text().await.unwrap_or_default();
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(MailError::Auth(format!("{url} answered {status}: {body}")));
+    }
+    Err(MailError::Http {
+        url,
+        status: status.as_u16(),
+        body,
+    })
+}
+
+/// Reads a JSON response body, a body that does not match `T` is a parse error rather than a
+/// network error.
+pub async fn json<T: DeserializeOwned>(response: Response) -> Result<T, MailError> {
+    let url = response.url().to_string();
+    let body = response.text().await?;
+    serde_json::from_str(&body)
+        .map_err(|error| MailError::parse_response(format!("response of {url}: {error}")))
+}
+
+/// Exponential backoff from one second up to `GMAIL_MAXIMUM_BACKOFF_SECONDS`, with up to a
+/// second of random jitter so parallel clients do not retry in lockstep.
+pub fn backoff(attempt: u32) -> Duration {
+    let seconds = 2u64
+        .saturating_pow(attempt)
+        .min(constants::GMAIL_MAXIMUM_BACKOFF_SECONDS);
+    Duration::from_secs(seconds) + Duration::from_millis(rand::random_range(0..1000))
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let time = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (time.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
@@ -5,8 +5,38 @@ pub static CREDENTIALS_PATH: Lazy<PathBuf> =
     Lazy::new(|| PathBuf::from("C:\\Users\\tompr\\mail\\credentials"));
 
 pub static GMAIL_CREDENTIALS: Lazy<PathBuf> = Lazy::new(|| CREDENTIALS_PATH.join("gmail.json"));
+pub static GRAPH_CREDENTIALS: Lazy<PathBuf> = Lazy::new(|| CREDENTIALS_PATH.join("graph.json"));
+pub static IMAP_CREDENTIALS: Lazy<PathBuf> = Lazy::new(|| CREDENTIALS_PATH.join("imap.json"));
+pub static JMAP_CREDENTIALS: Lazy<PathBuf> = Lazy::new(|| CREDENTIALS_PATH.join("jmap.json"));
+pub static CACHE_PATH: Lazy<PathBuf> = Lazy::new(|| PathBuf::from("C:\\Users\\tompr\\mail\\cache"));
+pub static STATE_PATH: Lazy<PathBuf> = Lazy::new(|| CACHE_PATH.join("state.sqlite3"));
+
 pub static TYPESENSE_CREDENTIALS: Lazy<PathBuf> =
     Lazy::new(|| CREDENTIALS_PATH.join("typesense.json"));
 
-pub const SEARCHABLE_MAIL_COLLECTION_NAME: &'static str = "mail";
+pub const GMAIL_API_URL: &str = "https://gmail.googleapis.com";
+
+// alias for the current versioned mail collection, e.g. mail_v3.
+pub const SEARCHABLE_MAIL_COLLECTION_NAME: &str = "mail";
+// bumped with every change to `Mail::collection_schema`: 2 added source and account, 3 added
+// accounts, 4 made subject, to and time optional.
+pub const SEARCHABLE_MAIL_SCHEMA_VERSION: i32 = 4;
+pub const SCHEMA_VERSIONS_COLLECTION_NAME: &str = "schema_versions";
 pub const MAXIMUM_MESSAGE_LIST_RESULTS: u32 = 500;
+pub const SYNC_MESSAGE_LIMIT: u32 = 3;
+pub const IMPORT_BATCH_SIZE: usize = 100;
+// gmail accepts at most 100 requests in one batch.
+pub const GMAIL_BATCH_SIZE: usize = 100;
+// requests, or gmail batches, a source keeps in flight while fetching.
+pub const CONCURRENT_FETCHES: usize = 4;
+// messages fetched and converted before they are handed to the search import.
+pub const SYNC_FETCH_BATCH_SIZE: usize = GMAIL_BATCH_SIZE * CONCURRENT_FETCHES;
+// converted batches that may wait for the search import.
+pub const IMPORT_QUEUE_BATCHES: usize = 4;
+// gmail's per-user limit is 250 quota units per second.
+pub const GMAIL_QUOTA_UNITS_PER_SECOND: f64 = 250.0;
+pub const GMAIL_MAXIMUM_RETRIES: u32 = 5;
+pub const GMAIL_MAXIMUM_BACKOFF_SECONDS: u64 = 64;
+pub const GRAPH_MAXIMUM_RETRIES: u32 = 5;
+// servers may drop idle connections after 30 minutes.
+pub const IMAP_IDLE_SECONDS: u64 = 25 * 60;
@@ -0,0 +1,110 @@
+use crate::search::Mail;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Cached messages that are the same message, seen through several sources or accounts, e.g. a
+/// CC to both a personal and a shared inbox, or a Takeout import of mail that is also synced
+/// from Gmail. Messages are grouped by `key`, the first message of a group is its canonical
+/// copy and the only one that gets indexed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Duplicates {
+    /// Key to the messages with that key, canonical first.
+    groups: BTreeMap<String, Vec<String>>,
+    /// Message id to its key.
+    keys: BTreeMap<String, String>,
+}
+
+impl Duplicates {
+    pub fn add(&mut self, message_id: &str, key: &str) {
+        if self.keys.get(message_id).map(String::as_str) == Some(key) {
+            return;
+        }
+
+        self.remove(message_id);
+        self.groups
+            .entry(key.to_string())
+            .or_default()
+            .push(message_id.to_string());
+        self.keys.insert(message_id.to_string(), key.to_string());
+    }
+
+    /// Takes a message out of its group and returns the messages left in it.
+    pub fn remove(&mut self, message_id: &str) -> Vec<String> {
+        let Some(key) = self.keys.remove(message_id) else {
+            return Vec::new();
+        };
+        let Some(group) = self.groups.get_mut(&key) else {
+            return Vec::new();
+        };
+
+        group.retain(|member| member != message_id);
+        let rest = group.clone();
+        if rest.is_empty() {
+            self.groups.remove(&key);
+        }
+        rest
+    }
+
+    /// The group of a message, canonical first, or only the message if it was never added.
+    pub fn group(&self, message_id: &str) -> Vec<String> {
+        self.keys
+            .get(message_id)
+            .and_then(|key| self.groups.get(key))
+            .cloned()
+            .unwrap_or_else(|| vec![message_id.to_string()])
+    }
+}
+
+/// The normalized Message-ID header, or a hash of the subject, time and text when a message
+/// has none.
+pub fn key(message_id_header: Option<&str>, mail: &Mail) -> String {
+    let message_id = message_id_header
+        .map(|header| {
+            let header = header.trim();
+            let header = match (header.find('<'), header.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &header[start + 1..end],
+                _ => header,
+            };
+            header.trim().to_lowercase()
+        })
+        .filter(|message_id| !message_id.is_empty());
+
+    if let Some(message_id) = message_id {
+        return format!("message-id:{message_id}");
+    }
+
+    let body = mail
+        .searchable_body
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    let hash = Sha256::digest(format!(
+        "{}\n{}\n{body}",
+        mail.subject.as_deref().unwrap_or_default().trim(),
+        mail.time.unwrap_or_default()
+    ));
+    format!("body:{hash:x}")
+}
+
+/// Collapses the copies of a message into its canonical copy, the first one, which then lists
+/// every label and account of all copies.
+pub fn merge(mails: Vec<Mail>) -> Option<Mail> {
+    let mut mails = mails.into_iter();
+    let mut canonical = mails.next()?;
+
+    for mail in mails {
+        for label in mail.labels {
+            if !canonical.labels.contains(&label) {
+                canonical.labels.push(label);
+            }
+        }
+        for account in mail.accounts {
+            if !canonical.accounts.contains(&account) {
+                canonical.accounts.push(account);
+            }
+        }
+    }
+
+    Some(canonical)
+}
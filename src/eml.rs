@@ -0,0 +1,142 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::error::MailError;
+use crate::mime;
+use crate::search;
+use crate::source::{FetchFailure, FetchedMessage};
+use crate::threading;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The `.eml` and `.emlx` files in `paths`, directories are searched recursively.
+pub fn files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, MailError> {
+    let mut files = Vec::new();
+    let mut pending = paths.to_vec();
+
+    while let Some(path) = pending.pop() {
+        if path.is_dir() {
+            let read_error =
+                |error| MailError::io(format!("could not read '{}'", path.display()), error);
+            for entry in fs::read_dir(&path).map_err(read_error)? {
+                pending.push(entry.map_err(read_error)?.path());
+            }
+        } else if is_message_file(&path) {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn is_message_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("eml") || extension.eq_ignore_ascii_case("emlx")
+        })
+}
+
+/// Apple Mail's `.emlx` starts with the length of the message on its own line, followed by the
+/// message and a property list with its flags. Returns where the message is in `content`.
+fn emlx_message(content: &[u8]) -> Result<Range<usize>, MailError> {
+    let newline = content
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or_else(|| MailError::parse_response("missing emlx length"))?;
+    let length: usize = std::str::from_utf8(&content[..newline])
+        .ok()
+        .and_then(|length| length.trim().parse().ok())
+        .ok_or_else(|| MailError::parse_response("invalid emlx length"))?;
+
+    let start = newline + 1;
+    if content.len() < start + length {
+        Err(MailError::parse_response("emlx is shorter than its length"))?
+    }
+    Ok(start..start + length)
+}
+
+/// Reads the `flags` integer of an emlx property list: bit 0 is read, bit 1 deleted and bit 4
+/// flagged.
+fn emlx_labels(property_list: &[u8]) -> Vec<String> {
+    let property_list = String::from_utf8_lossy(property_list);
+    let flags = property_list
+        .split_once("<key>flags</key>")
+        .and_then(|(_, rest)| rest.split_once("<integer>"))
+        .and_then(|(_, rest)| rest.split_once("</integer>"))
+        .and_then(|(flags, _)| flags.trim().parse::<u64>().ok());
+
+    let Some(flags) = flags else {
+        return Vec::new();
+    };
+
+    let mut labels = Vec::new();
+    if flags & 1 == 0 {
+        labels.push(search::LABEL_UNREAD.to_string());
+    }
+    if flags & (1 << 1) != 0 {
+        labels.push(search::LABEL_BIN.to_string());
+    }
+    if flags & (1 << 4) != 0 {
+        labels.push(search::LABEL_STARRED.to_string());
+    }
+    labels
+}
+
+fn hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))[..32].to_string()
+}
+
+/// Reads an `.eml` or `.emlx` file. The id is derived from its Message-ID, or its content when
+/// it has none, so importing a file twice updates the same document. A file that cannot be read
+/// fails under its path, a body that does not parse fails when the message is converted.
+pub fn fetched_message(path: &Path, account: &str) -> Result<FetchedMessage, FetchFailure> {
+    let failure = |error| FetchFailure {
+        message_id: path.display().to_string(),
+        error,
+    };
+    let content = fs::read(path).map_err(|error| {
+        failure(MailError::io(
+            format!("could not read '{}'", path.display()),
+            error,
+        ))
+    })?;
+
+    let is_emlx = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("emlx"));
+    let (raw, labels) = if is_emlx {
+        let message = emlx_message(&content)
+            .map_err(|error| failure(error.with_message_id(&path.display().to_string())))?;
+        let labels = emlx_labels(&content[message.end..]);
+        (content[message].to_vec(), labels)
+    } else {
+        (content, Vec::new())
+    };
+
+    let message_id = mime::headers(&raw)
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Message-ID"))
+        .and_then(|header| threading::message_ids(&header.value).into_iter().next());
+
+    let id = match &message_id {
+        Some(message_id) => format!("eml:{}", hash(message_id.as_bytes())),
+        None => format!("eml:{}", hash(&raw)),
+    };
+
+    Ok(FetchedMessage {
+        id,
+        metadata: MessageMetadata {
+            source: "eml".to_string(),
+            account: account.to_string(),
+            history_id: None,
+            thread_id: None,
+            label_ids: Vec::new(),
+            labels,
+            time: None,
+        },
+        format: MessageFormat::Raw,
+        content: raw,
+    })
+}
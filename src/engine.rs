@@ -0,0 +1,347 @@
+use crate::cache::MessageCache;
+use crate::constants;
+use crate::error::MailError;
+use crate::index::SearchIndex;
+use crate::search::{ImportFailure, ImportReport, Mail};
+use crate::source::{FetchFailure, FetchedMessage, MailSource};
+use crate::state::{self, StateStore};
+use crate::sync;
+use tokio::sync::mpsc;
+
+/// Called as a sync goes along, e.g. to log or to collect metrics. Every method does nothing by
+/// default.
+pub trait SyncHooks {
+    /// A batch of documents went to the index, `report` says which of them it rejected.
+    fn imported(&self, _report: &ImportReport) {}
+
+    /// A batch of documents could not be imported at all.
+    fn import_failed(&self, _error: &MailError) {}
+
+    /// Messages deleted at the source were removed from the index.
+    fn deleted(&self, _message_ids: &[String]) {}
+
+    /// A message of an import could not be read, it is recorded as a fetch failure.
+    fn read_failed(&self, _failure: &FetchFailure) {}
+}
+
+/// Hooks that do nothing.
+pub struct NoHooks;
+
+impl SyncHooks for NoHooks {}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Documents the index accepted.
+    pub indexed: usize,
+    /// Documents the index rejected.
+    pub failures: Vec<ImportFailure>,
+    /// Messages that were deleted at the source and removed from the index.
+    pub deleted: Vec<String>,
+}
+
+/// Keeps a search index in sync with a mail source, with the message cache in between, so
+/// only new and changed messages are fetched, and the state store, so only new and changed
+/// documents are imported. Build one with `SyncEngine::builder()`.
+///
+/// Fetching, converting and importing overlap: while one batch is imported, the next one is
+/// fetched. Without a source, `S` is `()` and the engine can still import messages from files.
+pub struct SyncEngine<S> {
+    source: S,
+    index: Box<dyn SearchIndex>,
+    cache: MessageCache,
+    state: StateStore,
+    hooks: Box<dyn SyncHooks>,
+    limit: u32,
+}
+
+pub struct SyncEngineBuilder<S> {
+    source: S,
+    index: Option<Box<dyn SearchIndex>>,
+    cache: Option<MessageCache>,
+    state: Option<StateStore>,
+    hooks: Box<dyn SyncHooks>,
+    limit: u32,
+}
+
+impl SyncEngine<()> {
+    pub fn builder() -> SyncEngineBuilder<()> {
+        SyncEngineBuilder {
+            source: (),
+            index: None,
+            cache: None,
+            state: None,
+            hooks: Box::new(NoHooks),
+            limit: constants::SYNC_MESSAGE_LIMIT,
+        }
+    }
+}
+
+impl<S> SyncEngineBuilder<S> {
+    pub fn source<T: MailSource>(self, source: T) -> SyncEngineBuilder<T> {
+        SyncEngineBuilder {
+            source,
+            index: self.index,
+            cache: self.cache,
+            state: self.state,
+            hooks: self.hooks,
+            limit: self.limit,
+        }
+    }
+
+    pub fn index(mut self, index: impl SearchIndex + 'static) -> Self {
+        self.index = Some(Box::new(index));
+        self
+    }
+
+    pub fn cache(mut self, cache: MessageCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn state(mut self, state: StateStore) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn hooks(mut self, hooks: impl SyncHooks + 'static) -> Self {
+        self.hooks = Box::new(hooks);
+        self
+    }
+
+    /// How many of the most recent messages a sync looks at, besides what changed since the
+    /// last one. Defaults to `SYNC_MESSAGE_LIMIT`.
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn build(self) -> Result<SyncEngine<S>, MailError> {
+        Ok(SyncEngine {
+            source: self.source,
+            index: self
+                .index
+                .ok_or_else(|| MailError::Config("sync engine needs an index".to_string()))?,
+            cache: self
+                .cache
+                .ok_or_else(|| MailError::Config("sync engine needs a cache".to_string()))?,
+            state: self
+                .state
+                .ok_or_else(|| MailError::Config("sync engine needs a state store".to_string()))?,
+            hooks: self.hooks,
+            limit: self.limit,
+        })
+    }
+}
+
+impl<S> SyncEngine<S> {
+    pub fn index(&self) -> &dyn SearchIndex {
+        self.index.as_ref()
+    }
+
+    pub fn cache(&self) -> &MessageCache {
+        &self.cache
+    }
+
+    pub fn cache_mut(&mut self) -> &mut MessageCache {
+        &mut self.cache
+    }
+
+    pub fn state(&self) -> &StateStore {
+        &self.state
+    }
+
+    /// Caches and indexes messages from a file based import in batches, so archives larger
+    /// than memory can be imported. Messages that could not be read end up in the state store's
+    /// failures, like messages a source could not fetch.
+    pub async fn import(
+        &mut self,
+        messages: impl Iterator<Item = Result<FetchedMessage, FetchFailure>>,
+    ) -> Result<SyncReport, MailError> {
+        self.index.prepare().await?;
+
+        let (documents, batches) = mpsc::channel(constants::IMPORT_QUEUE_BATCHES);
+        let cache = &mut self.cache;
+        let state = &self.state;
+        let hooks = self.hooks.as_ref();
+
+        let caching = async move {
+            let mut batch = Vec::new();
+            let mut messages = messages.peekable();
+
+            while let Some(message) = messages.next() {
+                match message {
+                    Ok(message) => {
+                        let regrouped = cache.put(
+                            &message.id,
+                            message.metadata,
+                            message.format,
+                            &message.content,
+                        )?;
+                        batch.extend(regrouped);
+                        state.clear_failure(&message.id, state::STAGE_FETCH)?;
+                        batch.push(message.id);
+                    }
+                    Err(failure) => {
+                        state.record_failure(
+                            &failure.message_id,
+                            state::STAGE_FETCH,
+                            &failure.error.to_string(),
+                        )?;
+                        hooks.read_failed(&failure);
+                    }
+                }
+
+                if batch.len() < constants::IMPORT_BATCH_SIZE && messages.peek().is_some() {
+                    continue;
+                }
+
+                let mails = sync::cached_mails(cache, state, &batch)?;
+                send(&documents, state, mails).await?;
+                batch.clear();
+            }
+            // once, rewriting the index after every batch makes large imports quadratic.
+            cache.save()?;
+
+            Ok::<(), MailError>(())
+        };
+
+        let (cached, report) =
+            tokio::join!(caching, import(self.index.as_ref(), state, hooks, batches));
+        cached?;
+
+        report
+    }
+
+    /// Converts and imports cached messages again, e.g. the failures in the state store after
+    /// a parser fix. Messages that are no longer cached are left out.
+    pub async fn retry(&mut self, message_ids: &[String]) -> Result<SyncReport, MailError> {
+        self.index.prepare().await?;
+
+        let (documents, batches) = mpsc::channel(constants::IMPORT_QUEUE_BATCHES);
+        let cache = &mut self.cache;
+        let state = &self.state;
+
+        let caching = async move {
+            for batch in message_ids.chunks(constants::IMPORT_BATCH_SIZE) {
+                let mails = sync::cached_mails(cache, state, batch)?;
+                send(&documents, state, mails).await?;
+            }
+            cache.save()?;
+
+            Ok::<(), MailError>(())
+        };
+
+        let (cached, report) = tokio::join!(
+            caching,
+            import(self.index.as_ref(), state, self.hooks.as_ref(), batches)
+        );
+        cached?;
+
+        report
+    }
+}
+
+impl<S: MailSource> SyncEngine<S> {
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// For what the source can do besides syncing, like waiting for new mail.
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Syncs the `limit` most recent messages of the source and everything that changed into
+    /// the cache and brings the index up to date with it.
+    pub async fn sync(&mut self) -> Result<SyncReport, MailError> {
+        self.index.prepare().await?;
+
+        let (documents, batches) = mpsc::channel(constants::IMPORT_QUEUE_BATCHES);
+        let source = &mut self.source;
+        let cache = &mut self.cache;
+        let state = &self.state;
+        let limit = self.limit;
+
+        let caching = async move {
+            let result = sync::sync_source(source, cache, state, limit, &documents)
+                .await
+                .map_err(|error| MailError::Sync {
+                    source_name: source.name().to_string(),
+                    account: source.account().to_string(),
+                    error: Box::new(error),
+                })?;
+
+            let mails = sync::cached_mails(cache, state, &result.message_ids)?;
+            cache.save()?;
+            send(&documents, state, mails).await?;
+
+            Ok::<Vec<String>, MailError>(result.deleted)
+        };
+
+        let hooks = self.hooks.as_ref();
+        let (deleted, report) =
+            tokio::join!(caching, import(self.index.as_ref(), state, hooks, batches));
+
+        let mut report = report?;
+        report.deleted = deleted?;
+        if !report.deleted.is_empty() {
+            self.index.delete(&report.deleted).await?;
+            state.remove_indexed(&report.deleted)?;
+            hooks.deleted(&report.deleted);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Hands documents to the import, leaving out those the index already has as they are.
+async fn send(
+    documents: &mpsc::Sender<Vec<Mail>>,
+    state: &StateStore,
+    mails: Vec<Mail>,
+) -> Result<(), MailError> {
+    let mut changed = Vec::new();
+    for mail in mails {
+        if !state.is_indexed(&mail)? {
+            changed.push(mail);
+        }
+    }
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    documents
+        .send(changed)
+        .await
+        .map_err(|_| MailError::Index("the import stopped".to_string()))?;
+    Ok(())
+}
+
+/// Imports batches as they come in, until the sender is dropped.
+async fn import(
+    index: &dyn SearchIndex,
+    state: &StateStore,
+    hooks: &dyn SyncHooks,
+    mut batches: mpsc::Receiver<Vec<Mail>>,
+) -> Result<SyncReport, MailError> {
+    let mut report = SyncReport::default();
+
+    while let Some(mails) = batches.recv().await {
+        match index.import(&mails).await {
+            Ok(import_report) => {
+                state.record_import(&mails, &import_report)?;
+                hooks.imported(&import_report);
+                report.indexed += import_report.imported;
+                report.failures.extend(import_report.failures);
+            }
+            Err(error) => {
+                for mail in &mails {
+                    state.record_failure(&mail.id, state::STAGE_INDEX, &error.to_string())?;
+                }
+                hooks.import_failed(&error);
+            }
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,151 @@
+use thiserror::Error;
+use typesense::apis::Error as ApiError;
+
+/// What went wrong talking to a mail source or Typesense, so callers can tell a revoked token
+/// from a flaky network from a message we could not read.
+#[derive(Debug, Error)]
+pub enum MailError {
+    /// Credentials are missing, expired or were revoked, retrying will not help.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+
+    /// The server answered with an error status that is not worth retrying.
+    #[error("{url} answered {status}: {body}")]
+    Http {
+        url: String,
+        status: u16,
+        body: String,
+    },
+
+    /// Still rate limited after every retry.
+    #[error("quota exceeded for {url} after {retries} retries")]
+    Quota { url: String, retries: u32 },
+
+    /// A response or message that does not have the shape we expect.
+    #[error("could not parse {}: {reason}", .message_id.as_ref().map_or("response".to_string(), |id| format!("message {id}")))]
+    Parse {
+        message_id: Option<String>,
+        reason: String,
+    },
+
+    /// Typesense rejected a request.
+    #[error("index error: {0}")]
+    Index(String),
+
+    /// A request we refuse to send, like a label the source does not know.
+    #[error("{0}")]
+    Invalid(String),
+
+    /// A configuration or credentials file is missing or invalid.
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    /// The source no longer accepts a sync checkpoint, e.g. an expired Gmail history id, so
+    /// the account has to be synced from scratch.
+    #[error("checkpoint is no longer valid: {0}")]
+    Checkpoint(String),
+
+    /// The message cache or the state store could not be read or written.
+    #[error("storage error: {0}")]
+    Storage(String),
+
+    /// A mail source failed in a way none of the other variants describe, e.g. an IMAP
+    /// command the server rejected.
+    #[error("{0}")]
+    Source(String),
+
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Which source and account a sync failed for, `error` says why.
+    #[error("could not sync {source_name}/{account}: {error}")]
+    Sync {
+        source_name: String,
+        account: String,
+        #[source]
+        error: Box<MailError>,
+    },
+}
+
+impl MailError {
+    pub fn parse(message_id: &str, reason: impl Into<String>) -> Self {
+        MailError::Parse {
+            message_id: Some(message_id.to_string()),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn parse_response(reason: impl Into<String>) -> Self {
+        MailError::Parse {
+            message_id: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Says which message a parse error is about, when whoever parsed it could not know.
+    pub fn with_message_id(self, message_id: &str) -> Self {
+        match self {
+            MailError::Parse {
+                message_id: None,
+                reason,
+            } => MailError::parse(message_id, reason),
+            error => error,
+        }
+    }
+
+    /// An i/o error with what was attempted in front, e.g. "could not read 'a.eml'".
+    pub fn io(context: impl std::fmt::Display, error: std::io::Error) -> Self {
+        MailError::Io(std::io::Error::new(
+            error.kind(),
+            format!("{context}: {error}"),
+        ))
+    }
+
+    /// Keeps what kind of failure a Typesense call ran into, `action` says what was attempted,
+    /// e.g. "create mail_v2 collection".
+    pub fn typesense<T>(action: &str, error: ApiError<T>) -> Self {
+        match error {
+            // typesense is built on another reqwest version, so this can not be a `Network`.
+            ApiError::Reqwest(error) => {
+                MailError::Index(format!("could not reach typesense to {action}: {error}"))
+            }
+            ApiError::Io(error) => MailError::Io(error),
+            ApiError::Serde(error) => {
+                MailError::parse_response(format!("typesense answer to {action}: {error}"))
+            }
+            ApiError::ResponseError(response) if matches!(response.status.as_u16(), 401 | 403) => {
+                MailError::Auth(format!(
+                    "typesense refused to {action}: {}",
+                    response.content
+                ))
+            }
+            ApiError::ResponseError(response) => MailError::Index(format!(
+                "could not {action}: {} {}",
+                response.status, response.content
+            )),
+        }
+    }
+
+    /// The error without the context of `Sync`, to decide what to do about it.
+    pub fn root(&self) -> &MailError {
+        match self {
+            MailError::Sync { error, .. } => error.root(),
+            error => error,
+        }
+    }
+}
+
+impl From<serde_json::Error> for MailError {
+    fn from(error: serde_json::Error) -> Self {
+        MailError::parse_response(error.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for MailError {
+    fn from(error: rusqlite::Error) -> Self {
+        MailError::Storage(error.to_string())
+    }
+}
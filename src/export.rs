@@ -0,0 +1,214 @@
+use crate::cache::MessageMetadata;
+use crate::error::MailError;
+use crate::maildir;
+use crate::mbox;
+use crate::mime;
+use crate::search::Query;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    /// One mboxrd file, `messages.mbox`.
+    Mbox,
+    /// A Maildir with every message in `cur/`, flagged by its labels.
+    Maildir,
+    /// One `.eml` file per message.
+    Eml,
+}
+
+impl ExportFormat {
+    pub fn from_name(format: &str) -> Option<Self> {
+        match format {
+            "mbox" => Some(ExportFormat::Mbox),
+            "maildir" => Some(ExportFormat::Maildir),
+            "eml" => Some(ExportFormat::Eml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub id: String,
+    /// Of the exported RFC 822 bytes, so the export can be verified later.
+    pub sha256: String,
+    pub size: usize,
+    pub source: String,
+    pub account: String,
+    pub labels: Vec<String>,
+    /// Where the message was written, relative to the export directory.
+    pub file: String,
+}
+
+/// A message the query selected that could not be exported.
+#[derive(Debug, Serialize)]
+pub struct MissingEntry {
+    pub id: String,
+    pub error: String,
+}
+
+/// `manifest.json`, what was exported and how it was selected. An export is only complete if
+/// nothing is `missing`.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub query: String,
+    pub filter_by: Option<String>,
+    pub exported_at: String,
+    pub messages: Vec<ManifestEntry>,
+    pub missing: Vec<MissingEntry>,
+}
+
+/// Writes messages exactly as they were received into an export directory, along with a
+/// manifest. An export never writes into a directory that already holds one.
+pub struct Exporter {
+    directory: PathBuf,
+    format: ExportFormat,
+    mbox: Option<BufWriter<File>>,
+    manifest: Manifest,
+}
+
+impl Exporter {
+    pub fn create(
+        directory: &Path,
+        format: ExportFormat,
+        query: &Query,
+    ) -> Result<Self, MailError> {
+        if directory.join("manifest.json").exists() {
+            Err(MailError::Invalid(format!(
+                "'{}' already contains an export",
+                directory.display()
+            )))?
+        }
+        fs::create_dir_all(directory).map_err(|error| {
+            MailError::io(format!("could not create '{}'", directory.display()), error)
+        })?;
+
+        let mbox = match format {
+            ExportFormat::Mbox => {
+                let path = directory.join("messages.mbox");
+                let file = File::create(&path).map_err(|error| {
+                    MailError::io(format!("could not create '{}'", path.display()), error)
+                })?;
+                Some(BufWriter::new(file))
+            }
+            ExportFormat::Maildir | ExportFormat::Eml => None,
+        };
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            format,
+            mbox,
+            manifest: Manifest {
+                query: query.text.to_string(),
+                filter_by: query.filter_by.clone(),
+                exported_at: Utc::now().to_rfc3339(),
+                messages: Vec::new(),
+                missing: Vec::new(),
+            },
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        message_id: &str,
+        raw: &[u8],
+        metadata: &MessageMetadata,
+    ) -> Result<(), MailError> {
+        let sha256 = format!("{:x}", Sha256::digest(raw));
+
+        let file = match self.format {
+            ExportFormat::Mbox => {
+                let mbox = self
+                    .mbox
+                    .as_mut()
+                    .ok_or_else(|| MailError::Invalid("mbox export is not open".to_string()))?;
+                mbox::write_message(mbox, raw, received_time(raw))?;
+                "messages.mbox".to_string()
+            }
+            ExportFormat::Maildir => {
+                let unique = format!(
+                    "{}.M{}.{}",
+                    received_time(raw).timestamp(),
+                    self.manifest.messages.len(),
+                    &sha256[..16]
+                );
+                let path = maildir::deliver(&self.directory, &unique, raw, &metadata.labels)?;
+                path.strip_prefix(&self.directory)
+                    .unwrap_or(&path)
+                    .display()
+                    .to_string()
+            }
+            ExportFormat::Eml => {
+                // ids like `imap:account:1:2:INBOX` are not valid file names everywhere. Ids that
+                // only differ in those characters, like folders `Work/Sub` and `Work:Sub`, keep
+                // apart by a hash of the id.
+                let name: String = message_id
+                    .chars()
+                    .map(|character| match character {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => character,
+                        _ => '_',
+                    })
+                    .collect();
+                let id_hash = format!("{:x}", Sha256::digest(message_id.as_bytes()));
+                let file = format!("{name}-{}.eml", &id_hash[..8]);
+                let path = self.directory.join(&file);
+                fs::write(&path, raw).map_err(|error| {
+                    MailError::io(format!("could not write '{}'", path.display()), error)
+                })?;
+                file
+            }
+        };
+
+        self.manifest.messages.push(ManifestEntry {
+            id: message_id.to_string(),
+            sha256,
+            size: raw.len(),
+            source: metadata.source.to_string(),
+            account: metadata.account.to_string(),
+            labels: metadata.labels.clone(),
+            file,
+        });
+
+        Ok(())
+    }
+
+    /// Records a selected message that could not be exported in the manifest.
+    pub fn add_missing(&mut self, message_id: &str, error: &str) {
+        self.manifest.missing.push(MissingEntry {
+            id: message_id.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn missing(&self) -> &[MissingEntry] {
+        &self.manifest.missing
+    }
+
+    /// Flushes the messages and writes the manifest, returns how many messages were exported.
+    pub fn finish(mut self) -> Result<usize, MailError> {
+        if let Some(mbox) = self.mbox.as_mut() {
+            mbox.flush()?;
+        }
+
+        let path = self.directory.join("manifest.json");
+        fs::write(&path, serde_json::to_string_pretty(&self.manifest)?).map_err(|error| {
+            MailError::io(format!("could not write '{}'", path.display()), error)
+        })?;
+
+        Ok(self.manifest.messages.len())
+    }
+}
+
+/// The Date header of a message, or now if it has none that parses.
+fn received_time(raw: &[u8]) -> DateTime<Utc> {
+    mime::headers(raw)
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Date"))
+        .and_then(|header| mime::parse_date(&header.value))
+        .unwrap_or_else(Utc::now)
+}
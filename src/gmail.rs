@@ -1,24 +1,28 @@
+use crate::cache::{MessageFormat, MessageMetadata};
 use crate::client;
 use crate::constants;
+use crate::error::MailError;
+use crate::mime;
 use crate::search;
+use crate::source::{Changes, FetchFailure, Fetched, FetchedMessage, MailSource};
+use async_trait::async_trait;
 use base64::Engine;
 use base64::engine::general_purpose;
-use chrono::DateTime;
-use chrono::Utc;
-use reqwest::blocking::Client;
+use futures::{StreamExt, stream};
+use reqwest::{Client, StatusCode};
 use search::Searchable;
-use serde;
-use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::error::Error;
 
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct MessagesList {
+    // left out when there are no messages.
+    #[serde(default)]
     pub messages: Vec<MessageListMessage>,
+    // left out on the last page.
     #[serde(rename = "nextPageToken")]
-    pub next_page_token: String,
+    pub next_page_token: Option<String>,
     #[serde(rename = "resultSizeEstimate")]
     pub result_size_estimate: u64,
 }
@@ -31,6 +35,44 @@ pub struct MessageListMessage {
     pub thread_id: String,
 }
 
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct HistoryList {
+    pub history: Option<Vec<History>>,
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
+    #[serde(rename = "historyId")]
+    pub history_id: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct History {
+    pub id: String,
+    #[serde(rename = "messagesAdded", default)]
+    pub messages_added: Vec<HistoryMessage>,
+    #[serde(rename = "messagesDeleted", default)]
+    pub messages_deleted: Vec<HistoryMessage>,
+    #[serde(rename = "labelsAdded", default)]
+    pub labels_added: Vec<HistoryMessage>,
+    #[serde(rename = "labelsRemoved", default)]
+    pub labels_removed: Vec<HistoryMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryMessage {
+    pub message: MessageListMessage,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    #[serde(rename = "emailAddress")]
+    pub email_address: String,
+    #[serde(rename = "historyId")]
+    pub history_id: String,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Message {
@@ -42,12 +84,35 @@ pub struct Message {
     #[serde(rename(deserialize = "labelIds", serialize = "label_ids"))]
     pub label_ids: Vec<String>,
 
+    #[serde(rename(deserialize = "historyId", serialize = "history_id"))]
+    pub history_id: Option<String>,
+
+    /// Milliseconds since the epoch of when Gmail received the message.
+    #[serde(
+        rename(deserialize = "internalDate", serialize = "internal_date"),
+        default
+    )]
+    pub internal_date: Option<String>,
+
     pub payload: MessagePayload,
 }
 
+impl Message {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.payload
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MessagePayload {
+    #[serde(rename = "mimeType", default)]
+    mime_type: String,
+
     filename: String,
     headers: Vec<MessagePayloadHeaderPair>,
     body: MessagePayloadBody,
@@ -61,11 +126,15 @@ pub struct MessagePayloadHeaderPair {
     value: String,
 }
 
+/// Attachments only come with an `attachmentId` to fetch them by, multiparts with neither.
 #[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MessagePayloadBody {
     size: u64,
     data: Option<String>,
+
+    #[serde(rename = "attachmentId")]
+    attachment_id: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -78,210 +147,687 @@ pub struct MessagePayloadPart {
     mime_type: String,
 
     filename: String,
-    body: MessagePayloadPartBody,
+
+    #[serde(default)]
+    headers: Vec<MessagePayloadHeaderPair>,
+
+    body: MessagePayloadBody,
+    parts: Option<Vec<MessagePayloadPart>>,
 }
 
+/// A message fetched with `format=raw`, `raw` holds the base64url encoded RFC 822 message.
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Serialize)]
-pub struct MessagePayloadPartBody {
-    size: u64,
-    data: String,
+#[derive(Debug, Deserialize)]
+pub struct RawMessage {
+    pub id: String,
+    #[serde(rename = "threadId")]
+    pub thread_id: String,
+    #[serde(rename = "labelIds", default)]
+    pub label_ids: Vec<String>,
+    #[serde(rename = "historyId")]
+    pub history_id: Option<String>,
+    #[serde(rename = "internalDate", default)]
+    pub internal_date: Option<String>,
+    pub raw: String,
 }
 
-impl Searchable for Message {
-    fn to_searchable_mail(&self) -> Result<search::Mail, Box<dyn Error>> {
-        let mut subject: Option<String> = None;
-        let mut from: Option<String> = None;
-        let mut to: Option<String> = None;
-        let mut time: Option<i64> = None;
-        let mut raw_body: Option<String> = None;
-        let mut searchable_body: Option<String> = None;
-        let mut labels: Vec<String> = Vec::new();
-
-        let mut label_conversion: HashMap<&str, &str> = HashMap::new();
-        label_conversion.insert("INBOX", search::LABEL_INBOX);
-        label_conversion.insert("STARRED", search::LABEL_STARRED);
-        label_conversion.insert("IMPORTANT", search::LABEL_IMPORTANT);
-        label_conversion.insert("SENT", search::LABEL_SENT);
-        label_conversion.insert("SCHEDULED", search::LABEL_SCHEDULED);
-        label_conversion.insert("SPAM", search::LABEL_SPAM);
-        label_conversion.insert("BIN", search::LABEL_BIN);
-
-        for header in &self.payload.headers {
-            match header.name.as_str() {
-                "Subject" => subject = Some(header.value.to_string()),
-                "From" => from = Some(header.value.to_string()),
-                "To" => to = Some(header.value.to_string()),
-                "Date" => {
-                    time = Some(
-                        DateTime::parse_from_rfc2822(&header.value)
-                            .map_err(|error| {
-                                format!("could not parse {} to datetime: {error}", &header.value)
-                            })?
-                            .with_timezone(&Utc)
-                            .timestamp(),
-                    )
-                }
-                _ => {}
-            }
-        }
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Full,
+    Raw,
+}
 
-        if let Some(parts) = &self.payload.parts {
-            for part in parts {
-                match part.mime_type.as_str() {
-                    "text/html" => {
-                        let decoded_raw_body = general_purpose::URL_SAFE
-                            .decode(part.body.data.as_str())
-                            .map_err(|error| format!("could not decode raw body: {error}"))?;
-                        raw_body = Some(String::from_utf8(decoded_raw_body).map_err(|error| {
-                            format!("could not create string from UTF-8 encoded raw body: {error}")
-                        })?);
-                    }
-                    "text/plain" => {
-                        let decoded_searchable_body = general_purpose::URL_SAFE
-                            .decode(part.body.data.as_str())
-                            .map_err(|error| format!("could not decode searchable body {error}"))?;
-                        searchable_body = Some(String::from_utf8(decoded_searchable_body).map_err(|error|format!("could not create string from UTF-8 encoded searchable body: {error}"))?);
-                    }
-                    _ => {}
-                }
-            }
+impl Format {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Format::Full => "full",
+            Format::Raw => "raw",
         }
+    }
+}
 
-        labels.extend(
-            self.label_ids
+fn label_conversion() -> HashMap<&'static str, &'static str> {
+    let mut label_conversion: HashMap<&str, &str> = HashMap::new();
+    label_conversion.insert("INBOX", search::LABEL_INBOX);
+    label_conversion.insert("STARRED", search::LABEL_STARRED);
+    label_conversion.insert("IMPORTANT", search::LABEL_IMPORTANT);
+    label_conversion.insert("SENT", search::LABEL_SENT);
+    label_conversion.insert("SCHEDULED", search::LABEL_SCHEDULED);
+    label_conversion.insert("SPAM", search::LABEL_SPAM);
+    label_conversion.insert("BIN", search::LABEL_BIN);
+    label_conversion.insert("UNREAD", search::LABEL_UNREAD);
+    label_conversion
+}
+
+pub fn convert_labels(label_ids: &[String]) -> Vec<String> {
+    let label_conversion = label_conversion();
+
+    label_ids
+        .iter()
+        .filter_map(|label| label_conversion.get(label.as_str()))
+        .map(|label| label.to_string())
+        .collect()
+}
+
+/// Translates labels named like `search::LABELS` back to Gmail label ids.
+fn gmail_label_ids(labels: &[String]) -> Result<Vec<String>, MailError> {
+    let label_conversion = label_conversion();
+
+    labels
+        .iter()
+        .map(|label| {
+            label_conversion
                 .iter()
-                .filter_map(|label| label_conversion.get(label.as_str()))
-                .map(|label| label.to_string()),
-        );
+                .find(|(_, name)| *name == label)
+                .map(|(label_id, _)| label_id.to_string())
+                .ok_or_else(|| MailError::Invalid(format!("unknown label '{label}'")))
+        })
+        .collect()
+}
 
-        let subject = subject.ok_or("missing subject")?;
-        let from = from.ok_or("missing from")?;
-        let to = to.ok_or("missing to")?;
-        let time = time.ok_or("missing time")?;
-        let raw_body = raw_body.ok_or("missing raw body")?;
-        let searchable_body = searchable_body.ok_or("missing searchable body")?;
+/// Gmail's internalDate in seconds. It is when Gmail received the message, so unlike the Date
+/// header it is always there and can be trusted.
+fn internal_time(id: &str, internal_date: Option<&str>, warnings: &mut Vec<String>) -> Option<i64> {
+    let internal_date = internal_date?;
+    match internal_date.parse::<i64>() {
+        Ok(milliseconds) => Some(milliseconds / 1000),
+        Err(error) => {
+            warnings.push(format!(
+                "could not parse internal date '{internal_date}' of {id}: {error}"
+            ));
+            None
+        }
+    }
+}
 
-        let mail = search::Mail {
+impl RawMessage {
+    pub fn decode_raw(&self) -> Result<Vec<u8>, MailError> {
+        let raw = general_purpose::URL_SAFE
+            .decode(self.raw.as_str())
+            .map_err(|error| MailError::parse(&self.id, format!("invalid base64: {error}")))?;
+        Ok(raw)
+    }
+}
+
+impl Searchable for RawMessage {
+    fn to_searchable_mail(&self) -> Result<search::Conversion, MailError> {
+        let message =
+            mime::parse(&self.decode_raw()?).map_err(|error| error.with_message_id(&self.id))?;
+        let mut warnings = Vec::new();
+        let parsed = mime::ParsedMail {
+            id: self.id.to_string(),
+            source: "gmail".to_string(),
+            account: String::new(),
+            thread_id: self.thread_id.to_string(),
+            labels: convert_labels(&self.label_ids),
+            time: internal_time(&self.id, self.internal_date.as_deref(), &mut warnings),
+            message,
+        };
+
+        let mut conversion = parsed.to_searchable_mail()?;
+        warnings.append(&mut conversion.warnings);
+        conversion.warnings = warnings;
+        Ok(conversion)
+    }
+}
+
+/// Gmail's parsed payload as a `mime::Part`, so it converts like a raw message does. Gmail
+/// undoes the transfer encoding of bodies, but leaves their charset and the encoded words in
+/// headers alone.
+fn mime_part(
+    id: &str,
+    mime_type: &str,
+    headers: &[MessagePayloadHeaderPair],
+    body: &MessagePayloadBody,
+    parts: Option<&Vec<MessagePayloadPart>>,
+) -> Result<mime::Part, MailError> {
+    let headers: Vec<mime::Header> = headers
+        .iter()
+        .map(|header| mime::Header {
+            name: header.name.to_string(),
+            value: mime::decode_encoded_words(&header.value),
+        })
+        .collect();
+
+    let mut content_type = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Type"))
+        .map(|header| mime::parse_content_type(&header.value))
+        .unwrap_or_default();
+    if !mime_type.is_empty() {
+        content_type.mime_type = mime_type.to_ascii_lowercase();
+    }
+
+    let body = match &body.data {
+        Some(data) => general_purpose::URL_SAFE
+            .decode(data.as_str())
+            .map_err(|error| MailError::parse(id, format!("could not decode body: {error}")))?,
+        None => Vec::new(),
+    };
+
+    let parts = parts
+        .into_iter()
+        .flatten()
+        .map(|part| {
+            mime_part(
+                id,
+                &part.mime_type,
+                &part.headers,
+                &part.body,
+                part.parts.as_ref(),
+            )
+        })
+        .collect::<Result<Vec<mime::Part>, MailError>>()?;
+
+    Ok(mime::Part {
+        headers,
+        content_type,
+        body,
+        parts,
+        decode_error: None,
+    })
+}
+
+impl Searchable for Message {
+    fn to_searchable_mail(&self) -> Result<search::Conversion, MailError> {
+        let payload = &self.payload;
+        let message = mime_part(
+            &self.id,
+            &payload.mime_type,
+            &payload.headers,
+            &payload.body,
+            payload.parts.as_ref(),
+        )?;
+        let mut warnings = Vec::new();
+        let parsed = mime::ParsedMail {
             id: self.id.to_string(),
+            source: "gmail".to_string(),
+            // the JSON only knows the account as "me", the sync pipeline fills it in.
+            account: String::new(),
             thread_id: self.thread_id.to_string(),
-            subject: subject,
-            from,
-            to,
-            labels: labels,
-            time: time,
-            raw_body: raw_body,
-            searchable_body: searchable_body,
+            labels: convert_labels(&self.label_ids),
+            time: internal_time(&self.id, self.internal_date.as_deref(), &mut warnings),
+            message,
         };
 
-        Ok(mail)
+        let mut conversion = parsed.to_searchable_mail()?;
+        warnings.append(&mut conversion.warnings);
+        conversion.warnings = warnings;
+        Ok(conversion)
     }
 }
 
+// quota units per method, see https://developers.google.com/gmail/api/reference/quota.
+const QUOTA_MESSAGES_LIST: u32 = 5;
+const QUOTA_MESSAGES_GET: u32 = 5;
+const QUOTA_MESSAGES_MODIFY: u32 = 5;
+const QUOTA_HISTORY_LIST: u32 = 2;
+const QUOTA_GET_PROFILE: u32 = 1;
+
 // TODO combine messages_list and message, just specify the amount, this should do the rest.
-pub fn messages_list(
-    client: &mut client::GmailClient,
+pub async fn messages_list(
+    client: &client::GmailClient,
     results: Option<u32>,
-) -> Result<MessagesList, Box<dyn Error>> {
+) -> Result<MessagesList, MailError> {
     let results = results.unwrap_or(3);
 
     if results > constants::MAXIMUM_MESSAGE_LIST_RESULTS {
-        Err(format!(
+        Err(MailError::Invalid(format!(
             "maximum number of messages results is {}",
             constants::MAXIMUM_MESSAGE_LIST_RESULTS
-        ))?
+        )))?
     }
 
-    let url = format!(
-        "https://gmail.googleapis.com/gmail/v1/users/me/messages?maxResults={}",
+    let url = client.url(&format!(
+        "/gmail/v1/users/me/messages?maxResults={}",
         results
-    );
+    ));
 
-    let messages_list: MessagesList = client.send(|client: &Client| client.get(&url))?.json()?;
+    let messages_list: MessagesList = client::json(
+        client
+            .send(QUOTA_MESSAGES_LIST, |client: &Client| client.get(&url))
+            .await?,
+    )
+    .await?;
 
     Ok(messages_list)
 }
 
-pub fn get_messages_batched(
-    client: &mut client::GmailClient,
+/// Collects the ids of messages that were added, relabeled or deleted since `start_history_id`.
+pub async fn history_list(
+    client: &client::GmailClient,
+    start_history_id: u64,
+) -> Result<Changes, MailError> {
+    let mut changes = Changes::default();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut url = client.url(&format!(
+            "/gmail/v1/users/me/history?startHistoryId={}&maxResults={}",
+            start_history_id,
+            constants::MAXIMUM_MESSAGE_LIST_RESULTS
+        ));
+        if let Some(page_token) = &page_token {
+            url = format!("{}&pageToken={}", url, page_token);
+        }
+
+        let history_list: HistoryList = client::json(
+            client
+                .send(QUOTA_HISTORY_LIST, |client: &Client| client.get(&url))
+                .await?,
+        )
+        .await?;
+        changes.checkpoint = history_list.history_id;
+
+        for history in history_list.history.unwrap_or_default() {
+            for deleted in history.messages_deleted {
+                changes.changed.retain(|id| id != &deleted.message.id);
+                changes.deleted.push(deleted.message.id);
+            }
+
+            for changed in history
+                .messages_added
+                .into_iter()
+                .chain(history.labels_added)
+                .chain(history.labels_removed)
+            {
+                let id = changed.message.id;
+                if !changes.changed.contains(&id) && !changes.deleted.contains(&id) {
+                    changes.changed.push(id);
+                }
+            }
+        }
+
+        match history_list.next_page_token {
+            Some(next_page_token) => page_token = Some(next_page_token),
+            None => break,
+        }
+    }
+
+    Ok(changes)
+}
+
+/// One response inside a batch response.
+#[derive(Debug)]
+struct BatchPart {
+    message_id: String,
+    status: StatusCode,
+    body: String,
+}
+
+/// Fetches messages in batch requests and returns the JSON of every message untouched, so it
+/// can be cached, with its message id. Messages that were rate limited or failed on the server
+/// are fetched again in another batch after a backoff, messages that are gone are left out and
+/// any other failure is returned with the message.
+pub async fn get_raw_messages_batched(
+    client: &client::GmailClient,
     message_ids: &[String],
-) -> Result<Vec<Message>, Box<dyn Error>> {
-    // refresh token before doing batch requests.
-    client.refresh_access_token()?;
+    format: Format,
+) -> Result<(Vec<(String, String)>, Vec<FetchFailure>), MailError> {
+    let mut messages = Vec::new();
+    let mut failures = Vec::new();
+    let mut pending = message_ids.to_vec();
+    let mut attempt = 0;
+
+    loop {
+        let mut retry = Vec::new();
+        let mut rate_limited = false;
+
+        for part in batch_request(client, &pending, format).await? {
+            let url = client.url(&format!("/gmail/v1/users/me/messages/{}", part.message_id));
+            let status = part.status;
+            let part_rate_limited = client::is_rate_limited(status, &part.body);
+
+            let error = match status.as_u16() {
+                200 => {
+                    messages.push((part.message_id, part.body));
+                    continue;
+                }
+                // deleted since it was listed.
+                404 => continue,
+                _ if (part_rate_limited || status.is_server_error())
+                    && attempt < constants::GMAIL_MAXIMUM_RETRIES =>
+                {
+                    rate_limited |= part_rate_limited;
+                    retry.push(part.message_id);
+                    continue;
+                }
+                _ if part_rate_limited => MailError::Quota {
+                    url,
+                    retries: attempt,
+                },
+                status => MailError::Http {
+                    url,
+                    status,
+                    body: part.body,
+                },
+            };
+            failures.push(FetchFailure {
+                message_id: part.message_id,
+                error,
+            });
+        }
 
+        if retry.is_empty() {
+            return Ok((messages, failures));
+        }
+        eprintln!(
+            "{} messages of a batch failed, retrying them (attempt {})",
+            retry.len(),
+            attempt + 1
+        );
+        client.back_off(attempt, rate_limited).await;
+        attempt += 1;
+        pending = retry;
+    }
+}
+
+/// Sends one batch request for `message_ids`.
+async fn batch_request(
+    client: &client::GmailClient,
+    message_ids: &[String],
+    format: Format,
+) -> Result<Vec<BatchPart>, MailError> {
     let boundary = "batch_boundary";
     let mut body = String::new();
 
-    for index in 0..message_ids.len() {
-        match message_ids.get(index) {
-            Some(message_id) => {
-                body = format!(
-                    "{}--{}\nContent-Type: application/http\n\nGET /gmail/v1/users/me/messages/{}?full HTTP/1.1\n",
-                    body, boundary, message_id,
-                );
-            }
-            None => return Err(format!("could not get message_id with index {}", index).into()),
-        }
+    // the Content-ID comes back with the response, which tells the responses apart.
+    for message_id in message_ids {
+        body = format!(
+            "{}--{}\nContent-Type: application/http\nContent-ID: <{}>\n\nGET /gmail/v1/users/me/messages/{}?format={} HTTP/1.1\n",
+            body,
+            boundary,
+            message_id,
+            message_id,
+            format.as_str(),
+        );
     }
 
     body = format!("{}\n--{}--", body, boundary);
 
+    let url = client.url("/batch/gmail/v1");
     let raw_batch_resonse: String = client
-        .send(|client: &Client| {
-            client
-                .post("https://gmail.googleapis.com/batch/gmail/v1")
-                .header(
-                    "Content-Type",
-                    format!("multipart/mixed; boundary={}", boundary),
-                )
-                .body(body.to_string())
-        })?
-        .text()?;
-
-    let messages: Vec<Message> = deserialize_batch_response(&raw_batch_resonse)?;
-
-    Ok(messages)
+        // every request in the batch counts against the quota on its own.
+        .send(
+            QUOTA_MESSAGES_GET * message_ids.len() as u32,
+            |client: &Client| {
+                client
+                    .post(&url)
+                    .header(
+                        "Content-Type",
+                        format!("multipart/mixed; boundary={}", boundary),
+                    )
+                    .body(body.to_string())
+            },
+        )
+        .await?
+        .text()
+        .await?;
+
+    split_batch_response(&raw_batch_resonse, message_ids)
 }
 
-fn deserialize_batch_response<T>(raw_batch_response: &str) -> Result<Vec<T>, Box<dyn Error>>
-where
-    T: DeserializeOwned,
-{
+/// Splits a batch response into its parts. A part is matched to its request by its
+/// `Content-ID`, or by its position when it has none we know.
+fn split_batch_response(
+    raw_batch_response: &str,
+    message_ids: &[String],
+) -> Result<Vec<BatchPart>, MailError> {
     let batch_boundary = raw_batch_response
         .split("\r\n")
-        .filter(|line| line.len() > 0)
-        .next()
-        .ok_or_else(|| Box::<dyn Error>::from("could not get batch boundary from resonse"))?;
+        .find(|line| !line.is_empty())
+        .ok_or_else(|| MailError::parse_response("could not get batch boundary from resonse"))?;
 
-    let serialized_objects: Vec<String> = raw_batch_response
+    let parts = raw_batch_response
         .split(batch_boundary)
-        .filter_map(|line| {
-            let json_string = line
-                .split_once("{")
-                .map(|(_, after_bracket)| after_bracket)
-                .unwrap_or("");
-
-            if json_string.is_empty() {
-                None
-            } else {
-                // Add the '{' used for splitting back.
-                Some(format!("{{{}", json_string))
-            }
+        .map(|part| part.trim_start_matches("\r\n"))
+        .filter(|part| !part.is_empty() && !part.starts_with("--"));
+
+    let mut batch_parts = Vec::new();
+    for (index, part) in parts.enumerate() {
+        let (headers, response) = split_head(part);
+        let (status_line, body) = split_head(response);
+        let status = status_line
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| {
+                MailError::parse_response(format!(
+                    "batch response part {} has no HTTP status line",
+                    index + 1
+                ))
+            })?;
+
+        let content_id = headers.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("Content-ID").then(|| {
+                value
+                    .trim()
+                    .trim_matches(['<', '>'])
+                    .trim_start_matches("response-")
+            })
+        });
+        let message_id = content_id
+            .and_then(|content_id| message_ids.iter().find(|id| *id == content_id))
+            .or_else(|| message_ids.get(index))
+            .ok_or_else(|| {
+                MailError::parse_response(format!(
+                    "batch response has more parts than the {} requests",
+                    message_ids.len()
+                ))
+            })?;
+
+        batch_parts.push(BatchPart {
+            message_id: message_id.to_string(),
+            status,
+            body: body.trim_end().to_string(),
+        });
+    }
+
+    Ok(batch_parts)
+}
+
+/// Splits at the first empty line, into the headers and what follows them.
+fn split_head(text: &str) -> (&str, &str) {
+    text.split_once("\r\n\r\n")
+        .or_else(|| text.split_once("\n\n"))
+        .unwrap_or((text, ""))
+}
+
+pub async fn profile(client: &client::GmailClient) -> Result<Profile, MailError> {
+    let url = client.url("/gmail/v1/users/me/profile");
+    let profile: Profile = client::json(
+        client
+            .send(QUOTA_GET_PROFILE, |client: &Client| client.get(&url))
+            .await?,
+    )
+    .await?;
+
+    Ok(profile)
+}
+
+pub async fn modify_labels(
+    client: &client::GmailClient,
+    message_id: &str,
+    add_label_ids: &[String],
+    remove_label_ids: &[String],
+) -> Result<(), MailError> {
+    let url = client.url(&format!(
+        "/gmail/v1/users/me/messages/{}/modify",
+        message_id
+    ));
+    let body = serde_json::json!({
+        "addLabelIds": add_label_ids,
+        "removeLabelIds": remove_label_ids,
+    });
+
+    client
+        .send(QUOTA_MESSAGES_MODIFY, |client: &Client| {
+            client.post(&url).json(&body)
         })
-        .collect();
+        .await?;
 
-    let deserialised_objects: Vec<T> = serialized_objects
-        .iter()
-        .filter_map(|serialized_object: &String| {
-            match serde_json::from_str::<T>(serialized_object) {
-                Ok(deserialized_object) => Some(deserialized_object),
-                Err(_) => {
-                    eprintln!("could not deserialize '{:?}'", serialized_object);
-                    None
+    Ok(())
+}
+
+pub struct GmailSource {
+    client: client::GmailClient,
+    account: String,
+    format: Format,
+}
+
+impl GmailSource {
+    pub async fn new(client: client::GmailClient, format: Format) -> Result<Self, MailError> {
+        let account = profile(&client).await?.email_address;
+
+        Ok(Self {
+            client,
+            account,
+            format,
+        })
+    }
+
+    /// Full messages are kept as the JSON Gmail returned, raw messages as the RFC 822 bytes with
+    /// the thread and labels in the metadata.
+    fn fetched_message(&self, raw_message: &str) -> Result<FetchedMessage, MailError> {
+        match self.format {
+            Format::Full => {
+                let message: Message = serde_json::from_str(raw_message)
+                    .map_err(|error| MailError::parse_response(error.to_string()))?;
+                Ok(FetchedMessage {
+                    metadata: self.metadata(
+                        message.history_id,
+                        message.thread_id,
+                        message.label_ids,
+                        message.internal_date.as_deref(),
+                    ),
+                    id: message.id,
+                    format: MessageFormat::Full,
+                    content: raw_message.trim().as_bytes().to_vec(),
+                })
+            }
+            Format::Raw => {
+                let message: RawMessage = serde_json::from_str(raw_message)
+                    .map_err(|error| MailError::parse_response(error.to_string()))?;
+                Ok(FetchedMessage {
+                    content: message.decode_raw()?,
+                    metadata: self.metadata(
+                        message.history_id,
+                        message.thread_id,
+                        message.label_ids,
+                        message.internal_date.as_deref(),
+                    ),
+                    id: message.id,
+                    format: MessageFormat::Raw,
+                })
+            }
+        }
+    }
+
+    fn metadata(
+        &self,
+        history_id: Option<String>,
+        thread_id: String,
+        label_ids: Vec<String>,
+        internal_date: Option<&str>,
+    ) -> MessageMetadata {
+        MessageMetadata {
+            source: self.name().to_string(),
+            account: self.account.to_string(),
+            history_id,
+            thread_id: Some(thread_id),
+            labels: convert_labels(&label_ids),
+            label_ids,
+            // a message that cannot be read this far is reported when it is converted.
+            time: internal_date
+                .and_then(|internal_date| internal_date.parse::<i64>().ok())
+                .map(|milliseconds| milliseconds / 1000),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl MailSource for GmailSource {
+    fn name(&self) -> &str {
+        "gmail"
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError> {
+        let messages_list = messages_list(&self.client, Some(limit)).await?;
+
+        Ok(messages_list
+            .messages
+            .into_iter()
+            .map(|message| message.id)
+            .collect())
+    }
+
+    /// Splits the messages into batches of `GMAIL_BATCH_SIZE` and keeps up to
+    /// `CONCURRENT_FETCHES` of them in flight.
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError> {
+        let client = &self.client;
+        let format = self.format;
+        let batches: Vec<Vec<String>> = message_ids
+            .chunks(constants::GMAIL_BATCH_SIZE)
+            .map(<[String]>::to_vec)
+            .collect();
+        let mut batches = stream::iter(batches)
+            .map(|batch| async move { get_raw_messages_batched(client, &batch, format).await })
+            .buffered(constants::CONCURRENT_FETCHES);
+
+        let mut fetched = Fetched::default();
+        while let Some(batch) = batches.next().await {
+            let (raw_messages, failures) = batch?;
+            fetched.failures.extend(failures);
+            for (message_id, raw_message) in raw_messages {
+                match self.fetched_message(&raw_message) {
+                    Ok(message) => fetched.messages.push(message),
+                    Err(error) => fetched.failures.push(FetchFailure { message_id, error }),
                 }
             }
-        })
-        .collect();
+        }
+
+        Ok(fetched)
+    }
 
-    Ok(deserialised_objects)
+    async fn checkpoint(&mut self) -> Result<String, MailError> {
+        Ok(profile(&self.client).await?.history_id)
+    }
+
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError> {
+        let history_id = checkpoint.parse().map_err(|error| {
+            MailError::Checkpoint(format!(
+                "could not parse history id '{checkpoint}': {error}"
+            ))
+        })?;
+
+        // Gmail only keeps about a week of history, older history ids are not found.
+        history_list(&self.client, history_id)
+            .await
+            .map_err(|error| match error {
+                MailError::Http { status: 404, .. } => MailError::Checkpoint(error.to_string()),
+                error => error,
+            })
+    }
+
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError> {
+        Ok(modify_labels(
+            &self.client,
+            message_id,
+            &gmail_label_ids(add)?,
+            &gmail_label_ids(remove)?,
+        )
+        .await?)
+    }
 }
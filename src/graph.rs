@@ -0,0 +1,627 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::client::{self, CredentialsToken};
+use crate::constants;
+use crate::error::MailError;
+use crate::search;
+use crate::source::{Changes, FetchFailure, Fetched, FetchedMessage, MailSource};
+use crate::utils;
+use async_trait::async_trait;
+use futures::{StreamExt, stream};
+use reqwest::StatusCode;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+const GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+const GRAPH_SCOPE: &str = "offline_access https://graph.microsoft.com/Mail.ReadWrite";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphCredentials {
+    pub oauth: GraphOAuth,
+    pub token: CredentialsToken,
+    /// A shared mailbox to read instead of the signed in user's, e.g. `support@example.com`.
+    #[serde(default)]
+    pub mailbox: Option<String>,
+    /// Where the Graph API lives, only set to point at a mock server.
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphOAuth {
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    pub tenant: String,
+    /// Defaults to the Microsoft identity platform token endpoint of `tenant`.
+    #[serde(default)]
+    pub token_uri: Option<String>,
+}
+
+/// A Graph API client that refreshes its access token like `GmailClient` does, writing the new
+/// token back to the file the credentials came from. Shared by concurrent requests, so the
+/// credentials are behind a lock.
+pub struct GraphClient {
+    pub client: Client,
+    credentials: Mutex<GraphCredentials>,
+    credentials_path: PathBuf,
+    base_url: String,
+    mailbox: Option<String>,
+}
+
+impl GraphClient {
+    pub fn new() -> Result<Self, MailError> {
+        Self::from_file(&constants::GRAPH_CREDENTIALS)
+    }
+
+    pub fn from_file(credentials_path: &Path) -> Result<Self, MailError> {
+        let credentials: GraphCredentials =
+            utils::read_json(&credentials_path.display().to_string()).map_err(|error| {
+                MailError::Config(format!("could not read graph credentials: {error}"))
+            })?;
+
+        Ok(Self {
+            client: Client::new(),
+            base_url: credentials
+                .base_url
+                .as_deref()
+                .unwrap_or(GRAPH_URL)
+                .to_string(),
+            mailbox: credentials.mailbox.clone(),
+            credentials: Mutex::new(credentials),
+            credentials_path: credentials_path.to_path_buf(),
+        })
+    }
+
+    /// The url of `path` in the mailbox, e.g. `messages` becomes `.../me/messages`.
+    pub fn url(&self, path: &str) -> String {
+        match &self.mailbox {
+            Some(mailbox) => format!("{}/users/{mailbox}/{path}", self.base_url),
+            None => format!("{}/me/{path}", self.base_url),
+        }
+    }
+
+    async fn refresh(&self, credentials: &mut GraphCredentials) -> Result<(), MailError> {
+        let refresh_token = credentials
+            .token
+            .refresh_token
+            .as_ref()
+            .ok_or_else(|| MailError::Auth("no refresh token in graph credentials".to_string()))?
+            .to_string();
+
+        let oauth = &credentials.oauth;
+        let token_uri = oauth.token_uri.clone().unwrap_or_else(|| {
+            format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                oauth.tenant
+            )
+        });
+
+        let mut form: HashMap<&str, String> = HashMap::new();
+        form.insert("client_id", oauth.client_id.to_string());
+        if let Some(client_secret) = &oauth.client_secret {
+            form.insert("client_secret", client_secret.to_string());
+        }
+        form.insert("refresh_token", refresh_token);
+        form.insert("grant_type", "refresh_token".to_string());
+        form.insert("scope", GRAPH_SCOPE.to_string());
+
+        let response = self.client.post(&token_uri).form(&form).send().await?;
+
+        // a revoked or expired refresh token is answered with 400 invalid_grant.
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(MailError::Auth(format!(
+                "could not refresh access token, {status}: {}",
+                response.text().await.unwrap_or_default()
+            )));
+        }
+        let response: CredentialsToken = client::json(client::checked(response).await?).await?;
+
+        // unlike Google, Microsoft hands out a new refresh token with every access token.
+        if response.refresh_token.is_some() {
+            credentials.token.refresh_token = response.refresh_token;
+        }
+        credentials.token.access_token = response.access_token;
+
+        utils::write_struct_to_file(&*credentials, &self.credentials_path.display().to_string())
+            .map_err(|error| {
+                MailError::Config(format!("could not save graph credentials: {error}"))
+            })?;
+
+        Ok(())
+    }
+
+    /// Sends the request with immutable ids, so a message keeps its id when it moves folders.
+    /// An expired access token is refreshed once. Throttled requests, 429 or 503, are retried
+    /// after as long as `Retry-After` asks, or with backoff when it is missing.
+    pub async fn send<F>(&self, build: F) -> Result<Response, MailError>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut refreshed = false;
+        let mut attempt = 0;
+
+        loop {
+            let access_token = self.credentials.lock().await.token.access_token.to_string();
+            let response = build(&self.client)
+                .bearer_auth(&access_token)
+                .header("Prefer", "IdType=\"ImmutableId\"")
+                .send()
+                .await?;
+
+            let status = response.status();
+            println!("[REQUEST] {} {status}", response.url().as_str());
+
+            if status == StatusCode::UNAUTHORIZED && !refreshed {
+                let mut credentials = self.credentials.lock().await;
+                // a concurrent request may have refreshed the token already.
+                if credentials.token.access_token == access_token {
+                    self.refresh(&mut credentials).await?;
+                }
+                refreshed = true;
+                continue;
+            }
+            if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE
+            {
+                return Ok(response);
+            }
+
+            let url = response.url().to_string();
+            if attempt >= constants::GRAPH_MAXIMUM_RETRIES {
+                return Err(MailError::Quota {
+                    url,
+                    retries: attempt,
+                });
+            }
+            let delay = client::retry_after(&response).unwrap_or_else(|| client::backoff(attempt));
+            eprintln!("{url} answered {status}, retrying in {delay:?}");
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T, MailError> {
+        let response = self.send(|client: &Client| client.get(url)).await?;
+        client::json(client::checked(response).await?).await
+    }
+
+    /// The MIME content of a message.
+    async fn get_bytes(&self, url: &str) -> Result<Vec<u8>, MailError> {
+        let response = self.send(|client: &Client| client.get(url)).await?;
+        Ok(client::checked(response).await?.bytes().await?.to_vec())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Page<T> {
+    pub value: Vec<T>,
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    pub delta_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub mail: Option<String>,
+    pub user_principal_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MailFolder {
+    pub id: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub child_folder_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub id: String,
+    pub conversation_id: Option<String>,
+    pub change_key: Option<String>,
+    pub parent_folder_id: Option<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    pub is_read: Option<bool>,
+    pub importance: Option<String>,
+    pub flag: Option<FollowupFlag>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FollowupFlag {
+    pub flag_status: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeltaMessage {
+    pub id: String,
+    /// Set when the message was deleted or moved out of the folder.
+    #[serde(rename = "@removed")]
+    pub removed: Option<serde_json::Value>,
+}
+
+const FOLDER_FIELDS: &str = "id,displayName,childFolderCount";
+
+const MESSAGE_FIELDS: &str =
+    "id,conversationId,changeKey,parentFolderId,categories,isRead,importance,flag";
+
+/// Folders that Outlook creates for every mailbox and the labels they map to.
+const WELL_KNOWN_FOLDERS: [(&str, &str); 4] = [
+    ("inbox", search::LABEL_INBOX),
+    ("sentitems", search::LABEL_SENT),
+    ("junkemail", search::LABEL_SPAM),
+    ("deleteditems", search::LABEL_BIN),
+];
+
+/// Reads a Microsoft 365 mailbox through the Graph API. Messages are fetched as MIME and parsed
+/// by `mime`; folders, categories, flags and the read state end up in the labels.
+pub struct GraphSource {
+    client: GraphClient,
+    account: String,
+    /// Folder id to label, ours for the folders in `WELL_KNOWN_FOLDERS` and the display name
+    /// for any other folder.
+    folder_labels: HashMap<String, String>,
+}
+
+impl GraphSource {
+    pub async fn new(client: GraphClient) -> Result<Self, MailError> {
+        let account = match &client.mailbox {
+            Some(mailbox) => mailbox.to_string(),
+            None => {
+                let user: User = client.get(&format!("{}/me", client.base_url)).await?;
+                user.mail.unwrap_or(user.user_principal_name)
+            }
+        };
+
+        let mut folder_labels = HashMap::new();
+        for (well_known_name, label) in WELL_KNOWN_FOLDERS {
+            let url = client.url(&format!("mailFolders/{well_known_name}?$select=id"));
+            let folder: MailFolder = client.get(&url).await?;
+            folder_labels.insert(folder.id, label.to_string());
+        }
+
+        Ok(Self {
+            client,
+            account,
+            folder_labels,
+        })
+    }
+
+    fn message_id(graph_id: &str) -> String {
+        format!("graph:{graph_id}")
+    }
+
+    fn graph_id(message_id: &str) -> Result<&str, MailError> {
+        message_id
+            .strip_prefix("graph:")
+            .ok_or_else(|| MailError::Invalid(format!("'{message_id}' is not a graph message id")))
+    }
+
+    /// Every folder of the mailbox, child folders at any depth included.
+    async fn folders(&mut self) -> Result<Vec<MailFolder>, MailError> {
+        let mut folders = Vec::new();
+        let mut pending = vec![
+            self.client
+                .url(&format!("mailFolders?$top=100&$select={FOLDER_FIELDS}")),
+        ];
+
+        while let Some(mut url) = pending.pop() {
+            loop {
+                let page: Page<MailFolder> = self.client.get(&url).await?;
+                for folder in page.value {
+                    self.folder_labels
+                        .entry(folder.id.to_string())
+                        .or_insert_with(|| folder.display_name.to_string());
+                    if folder.child_folder_count > 0 {
+                        pending.push(self.client.url(&format!(
+                            "mailFolders/{}/childFolders?$top=100&$select={FOLDER_FIELDS}",
+                            folder.id
+                        )));
+                    }
+                    folders.push(folder);
+                }
+                match page.next_link {
+                    Some(next_link) => url = next_link,
+                    None => break,
+                }
+            }
+        }
+
+        Ok(folders)
+    }
+
+    /// Follows a delta query from `url` until its delta link, collecting what changed on the way.
+    async fn delta(&mut self, url: String, changes: &mut Changes) -> Result<String, MailError> {
+        let mut url = url;
+
+        loop {
+            let page: Page<DeltaMessage> = self.client.get(&url).await?;
+
+            for message in page.value {
+                let message_id = Self::message_id(&message.id);
+                // a move shows up in two folders, the caller reconciles them once it saw all.
+                let list = match message.removed {
+                    Some(_) => &mut changes.deleted,
+                    None => &mut changes.changed,
+                };
+                if !list.contains(&message_id) {
+                    list.push(message_id);
+                }
+            }
+
+            match (page.next_link, page.delta_link) {
+                (Some(next_link), _) => url = next_link,
+                (None, Some(delta_link)) => return Ok(delta_link),
+                (None, None) => {
+                    return Err(MailError::parse_response(
+                        "graph delta query ended without a delta link",
+                    ));
+                }
+            }
+        }
+    }
+
+    fn labels(&self, message: &Message) -> Vec<String> {
+        let mut labels: Vec<String> = message
+            .parent_folder_id
+            .as_ref()
+            .and_then(|folder_id| self.folder_labels.get(folder_id))
+            .filter(|label| !label.is_empty())
+            .cloned()
+            .into_iter()
+            .collect();
+
+        if message.is_read == Some(false) {
+            labels.push(search::LABEL_UNREAD.to_string());
+        }
+        if message
+            .flag
+            .as_ref()
+            .is_some_and(|flag| flag.flag_status == "flagged")
+        {
+            labels.push(search::LABEL_STARRED.to_string());
+        }
+        if message.importance.as_deref() == Some("high") {
+            labels.push(search::LABEL_IMPORTANT.to_string());
+        }
+        labels.extend(message.categories.iter().cloned());
+
+        labels
+    }
+}
+
+/// The metadata and MIME content of a message, `None` when it was deleted before either could be
+/// read.
+async fn fetch_message(
+    client: &GraphClient,
+    message_id: &str,
+) -> Result<Option<(Message, Vec<u8>)>, MailError> {
+    let graph_id = GraphSource::graph_id(message_id)?;
+
+    let url = client.url(&format!("messages/{graph_id}?$select={MESSAGE_FIELDS}"));
+    let message: Message = match client.get(&url).await {
+        Ok(message) => message,
+        Err(MailError::Http { status: 404, .. }) => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    let url = client.url(&format!("messages/{graph_id}/$value"));
+    match client.get_bytes(&url).await {
+        Ok(content) => Ok(Some((message, content))),
+        Err(MailError::Http { status: 404, .. }) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[async_trait(?Send)]
+impl MailSource for GraphSource {
+    fn name(&self) -> &str {
+        "graph"
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError> {
+        let url = self.client.url(&format!(
+            "messages?$top={limit}&$select=id&$orderby=receivedDateTime desc"
+        ));
+        let page: Page<Message> = self.client.get(&url).await?;
+
+        Ok(page
+            .value
+            .iter()
+            .map(|message| Self::message_id(&message.id))
+            .collect())
+    }
+
+    /// Keeps up to `CONCURRENT_FETCHES` messages in flight, each its metadata and then its MIME
+    /// content.
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError> {
+        let client = &self.client;
+        let mut results = stream::iter(message_ids)
+            .map(|message_id| async move { (message_id, fetch_message(client, message_id).await) })
+            .buffered(constants::CONCURRENT_FETCHES);
+
+        let mut fetched = Fetched::default();
+        let mut messages = Vec::new();
+        while let Some((message_id, result)) = results.next().await {
+            match result {
+                Ok(Some(message)) => messages.push((message_id, message)),
+                // deleted in the meantime.
+                Ok(None) => {}
+                Err(error) => fetched.failures.push(FetchFailure {
+                    message_id: message_id.to_string(),
+                    error,
+                }),
+            }
+        }
+        drop(results);
+
+        // a folder created since the folders were last listed has no label yet.
+        let unknown_folder = messages.iter().any(|(_, (message, _))| {
+            message
+                .parent_folder_id
+                .as_ref()
+                .is_some_and(|folder_id| !self.folder_labels.contains_key(folder_id))
+        });
+        if unknown_folder {
+            self.folders().await?;
+        }
+
+        for (message_id, (message, content)) in messages {
+            let mut label_ids: Vec<String> = message.parent_folder_id.iter().cloned().collect();
+            label_ids.extend(message.categories.iter().cloned());
+
+            fetched.messages.push(FetchedMessage {
+                id: message_id.to_string(),
+                metadata: MessageMetadata {
+                    source: self.name().to_string(),
+                    account: self.account.to_string(),
+                    history_id: message.change_key.clone(),
+                    thread_id: message.conversation_id.clone(),
+                    labels: self.labels(&message),
+                    label_ids,
+                    time: None,
+                },
+                format: MessageFormat::Raw,
+                content,
+            });
+        }
+
+        Ok(fetched)
+    }
+
+    /// Graph only has delta queries per folder, so the checkpoint is a JSON map from folder id
+    /// to its delta link. Getting the first delta link pages through every message id once.
+    async fn checkpoint(&mut self) -> Result<String, MailError> {
+        let mut delta_links = BTreeMap::new();
+
+        for folder in self.folders().await? {
+            let url = self.client.url(&format!(
+                "mailFolders/{}/messages/delta?$select=id",
+                folder.id
+            ));
+            let delta_link = self.delta(url, &mut Changes::default()).await?;
+            delta_links.insert(folder.id, delta_link);
+        }
+
+        Ok(serde_json::to_string(&delta_links)?)
+    }
+
+    /// A message that moves between folders shows up as removed in one and added in the other,
+    /// with immutable ids both are the same message, which then counts as changed.
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError> {
+        let previous: BTreeMap<String, String> =
+            serde_json::from_str(checkpoint).map_err(|error| {
+                MailError::Checkpoint(format!("could not parse graph checkpoint: {error}"))
+            })?;
+
+        let mut changes = Changes::default();
+        let mut delta_links = BTreeMap::new();
+
+        for folder in self.folders().await? {
+            // folders that are new since the checkpoint start from scratch.
+            let url = previous.get(&folder.id).cloned().unwrap_or_else(|| {
+                self.client.url(&format!(
+                    "mailFolders/{}/messages/delta?$select=id",
+                    folder.id
+                ))
+            });
+            // an expired delta link is answered with 410 Gone.
+            let delta_link = self
+                .delta(url, &mut changes)
+                .await
+                .map_err(|error| match error {
+                    MailError::Http { status: 410, .. } => MailError::Checkpoint(error.to_string()),
+                    error => error,
+                })?;
+            delta_links.insert(folder.id, delta_link);
+        }
+
+        // a removal in one folder and an addition in another is a move.
+        changes
+            .deleted
+            .retain(|message_id| !changes.changed.contains(message_id));
+        changes.checkpoint = serde_json::to_string(&delta_links)?;
+
+        Ok(changes)
+    }
+
+    /// Starred, unread and important map onto the flag, read state and importance, every other
+    /// label is an Outlook category. Folder labels would need a move and are not supported.
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError> {
+        let graph_id = Self::graph_id(message_id)?;
+        let url = self
+            .client
+            .url(&format!("messages/{graph_id}?$select={MESSAGE_FIELDS}"));
+        let message: Message = self.client.get(&url).await?;
+
+        let mut patch = serde_json::Map::new();
+        let mut categories = message.categories.clone();
+
+        let changes = add
+            .iter()
+            .map(|label| (label, true))
+            .chain(remove.iter().map(|label| (label, false)));
+        for (label, add) in changes {
+            match label.as_str() {
+                search::LABEL_STARRED => {
+                    let status = if add { "flagged" } else { "notFlagged" };
+                    patch.insert("flag".into(), serde_json::json!({ "flagStatus": status }));
+                }
+                search::LABEL_UNREAD => {
+                    patch.insert("isRead".into(), serde_json::json!(!add));
+                }
+                search::LABEL_IMPORTANT => {
+                    let importance = if add { "high" } else { "normal" };
+                    patch.insert("importance".into(), serde_json::json!(importance));
+                }
+                search::LABEL_INBOX
+                | search::LABEL_SENT
+                | search::LABEL_SPAM
+                | search::LABEL_BIN
+                | search::LABEL_SCHEDULED => {
+                    return Err(MailError::Invalid(format!(
+                        "label '{label}' can not be changed over graph"
+                    )));
+                }
+                category => {
+                    categories.retain(|existing| existing != category);
+                    if add {
+                        categories.push(category.to_string());
+                    }
+                }
+            }
+        }
+        if categories != message.categories {
+            patch.insert("categories".into(), serde_json::json!(categories));
+        }
+
+        let url = self.client.url(&format!("messages/{graph_id}"));
+        let response = self
+            .client
+            .send(|client: &Client| client.patch(&url).json(&patch))
+            .await?;
+        client::checked(response).await?;
+
+        Ok(())
+    }
+}
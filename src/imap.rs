@@ -0,0 +1,1131 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::constants;
+use crate::error::MailError;
+use crate::search;
+use crate::source::{Changes, FetchFailure, Fetched, FetchedMessage, MailSource};
+use crate::utils;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+use rustls_platform_verifier::ConfigVerifierExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImapCredentials {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: ImapAuth,
+    /// Plain TCP without TLS, only meant for a local Dovecot or a fake server.
+    #[serde(default)]
+    pub insecure: bool,
+    /// Folders to index, all folders when empty.
+    #[serde(default)]
+    pub folders: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImapAuth {
+    Password(String),
+    /// An OAuth access token, sent with SASL XOAUTH2.
+    OAuthToken(String),
+}
+
+/// A parsed piece of an IMAP response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Atom(String),
+    String(Vec<u8>),
+    List(Vec<Value>),
+    Nil,
+}
+
+impl Value {
+    fn as_text(&self) -> Option<String> {
+        match self {
+            Value::Atom(atom) => Some(atom.to_string()),
+            Value::String(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<u64> {
+        match self {
+            Value::Atom(atom) => atom.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// One untagged response, e.g. `* 3 FETCH (...)`, without the leading `*`.
+#[derive(Debug, Clone)]
+pub struct Untagged {
+    pub values: Vec<Value>,
+    /// The line as text, literals left out, for reading response codes like `[UIDVALIDITY 1]`.
+    pub text: String,
+}
+
+impl Untagged {
+    fn keyword(&self, index: usize) -> Option<String> {
+        self.values
+            .get(index)
+            .and_then(Value::as_text)
+            .map(|keyword| keyword.to_ascii_uppercase())
+    }
+
+    /// Reads the number in a response code like `OK [UIDVALIDITY 3857529045]`.
+    fn response_code(&self, code: &str) -> Option<u64> {
+        let start = self.text.find(&format!("[{code} "))? + code.len() + 2;
+        let end = self.text[start..].find(']')? + start;
+        self.text[start..end].trim().parse().ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Folder {
+    pub name: String,
+    pub attributes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct FolderState {
+    pub uid_validity: u64,
+    pub uid_next: u64,
+    pub highest_modseq: Option<u64>,
+    /// The uids in the folder as a uid set, to find deleted messages.
+    #[serde(default)]
+    pub uids: String,
+    /// The flags of every message, only kept without CONDSTORE to find flag changes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub flags: BTreeMap<u64, Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchedUid {
+    pub uid: u64,
+    pub flags: Vec<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// A minimal IMAP4rev1 client session over any stream, so it can run over TLS, plain TCP or an
+/// in-memory fake server.
+pub struct ImapSession<S: Read + Write> {
+    stream: BufReader<S>,
+    tag: u32,
+    capabilities: Vec<String>,
+}
+
+impl ImapSession<StreamOwned<ClientConnection, TcpStream>> {
+    pub fn connect_tls(host: &str, port: u16) -> Result<Self, MailError> {
+        let tls_error =
+            |error: rustls::Error| MailError::Source(format!("could not set up tls: {error}"));
+        let config = ClientConfig::with_platform_verifier().map_err(tls_error)?;
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|error| MailError::Config(format!("invalid imap host '{host}': {error}")))?;
+        let connection = ClientConnection::new(Arc::new(config), server_name).map_err(tls_error)?;
+        let tcp = connect(host, port)?;
+
+        ImapSession::new(StreamOwned::new(connection, tcp))
+    }
+}
+
+impl ImapSession<TcpStream> {
+    pub fn connect_plain(host: &str, port: u16) -> Result<Self, MailError> {
+        ImapSession::new(connect(host, port)?)
+    }
+}
+
+impl<S: Read + Write> ImapSession<S> {
+    pub fn new(stream: S) -> Result<Self, MailError> {
+        let mut session = Self {
+            stream: BufReader::new(stream),
+            tag: 0,
+            capabilities: Vec::new(),
+        };
+
+        let greeting = session.read_response_line()?;
+        if !greeting.starts_with(b"* OK") && !greeting.starts_with(b"* PREAUTH") {
+            Err(MailError::Source(format!(
+                "unexpected imap greeting: {}",
+                String::from_utf8_lossy(&greeting).trim()
+            )))?
+        }
+
+        session.refresh_capabilities()?;
+        Ok(session)
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(capability))
+    }
+
+    fn refresh_capabilities(&mut self) -> Result<(), MailError> {
+        let responses = self.command("CAPABILITY")?;
+        self.capabilities = responses
+            .iter()
+            .filter(|response| response.keyword(0).as_deref() == Some("CAPABILITY"))
+            .flat_map(|response| response.values[1..].iter().filter_map(Value::as_text))
+            .collect();
+        Ok(())
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) -> Result<(), MailError> {
+        self.command(&format!("LOGIN {} {}", quote(username), quote(password)))
+            .map_err(refused_login)?;
+        self.refresh_capabilities()
+    }
+
+    pub fn authenticate_xoauth2(
+        &mut self,
+        username: &str,
+        access_token: &str,
+    ) -> Result<(), MailError> {
+        let token = general_purpose::STANDARD.encode(format!(
+            "user={username}\x01auth=Bearer {access_token}\x01\x01"
+        ));
+
+        let tag = self.next_tag();
+        self.write_line(&format!("{tag} AUTHENTICATE XOAUTH2 {token}"))?;
+
+        loop {
+            let line = self.read_response_line()?;
+            if line.starts_with(b"+") {
+                // the server sends the error as a challenge, an empty answer ends the exchange.
+                self.write_line("")?;
+                continue;
+            }
+            if line.starts_with(tag.as_bytes()) {
+                check_tagged(&tag, &line).map_err(refused_login)?;
+                break;
+            }
+        }
+
+        self.refresh_capabilities()
+    }
+
+    pub fn enable(&mut self, capability: &str) -> Result<(), MailError> {
+        self.command(&format!("ENABLE {capability}"))?;
+        Ok(())
+    }
+
+    pub fn list(&mut self) -> Result<Vec<Folder>, MailError> {
+        let responses = self.command("LIST \"\" \"*\"")?;
+
+        Ok(responses
+            .iter()
+            .filter(|response| response.keyword(0).as_deref() == Some("LIST"))
+            .filter_map(|response| {
+                let attributes = match response.values.get(1)? {
+                    Value::List(attributes) => {
+                        attributes.iter().filter_map(Value::as_text).collect()
+                    }
+                    _ => Vec::new(),
+                };
+                let name = response.values.get(3)?.as_text()?;
+                Some(Folder { name, attributes })
+            })
+            .filter(|folder| {
+                !folder
+                    .attributes
+                    .iter()
+                    .any(|attribute| attribute.eq_ignore_ascii_case("\\Noselect"))
+            })
+            .collect())
+    }
+
+    pub fn select(&mut self, folder: &str) -> Result<FolderState, MailError> {
+        let command = if self.has_capability("CONDSTORE") {
+            format!("SELECT {} (CONDSTORE)", quote(folder))
+        } else {
+            format!("SELECT {}", quote(folder))
+        };
+        let responses = self.command(&command)?;
+
+        let mut state = FolderState::default();
+        for response in &responses {
+            if let Some(uid_validity) = response.response_code("UIDVALIDITY") {
+                state.uid_validity = uid_validity;
+            }
+            if let Some(uid_next) = response.response_code("UIDNEXT") {
+                state.uid_next = uid_next;
+            }
+            if let Some(highest_modseq) = response.response_code("HIGHESTMODSEQ") {
+                state.highest_modseq = Some(highest_modseq);
+            }
+        }
+
+        Ok(state)
+    }
+
+    pub fn uid_search(&mut self, criteria: &str) -> Result<Vec<u64>, MailError> {
+        let responses = self.command(&format!("UID SEARCH {criteria}"))?;
+
+        Ok(responses
+            .iter()
+            .filter(|response| response.keyword(0).as_deref() == Some("SEARCH"))
+            .flat_map(|response| response.values[1..].iter().filter_map(Value::as_number))
+            .collect())
+    }
+
+    /// Fetches flags, and the full message when `body` is set, of the messages in `uid_set`.
+    /// `changed_since` restricts the result to messages whose MODSEQ is higher (CONDSTORE).
+    pub fn uid_fetch(
+        &mut self,
+        uid_set: &str,
+        body: bool,
+        changed_since: Option<u64>,
+    ) -> Result<(Vec<FetchedUid>, Vec<u64>), MailError> {
+        let items = if body {
+            "(UID FLAGS BODY.PEEK[])"
+        } else {
+            "(UID FLAGS)"
+        };
+        let modifiers = match changed_since {
+            Some(modseq) if self.has_capability("QRESYNC") => {
+                format!(" (CHANGEDSINCE {modseq} VANISHED)")
+            }
+            Some(modseq) => format!(" (CHANGEDSINCE {modseq})"),
+            None => String::new(),
+        };
+
+        let responses = self.command(&format!("UID FETCH {uid_set} {items}{modifiers}"))?;
+
+        let mut fetched = Vec::new();
+        let mut vanished = Vec::new();
+
+        for response in &responses {
+            match (
+                response.keyword(0).as_deref(),
+                response.keyword(1).as_deref(),
+            ) {
+                (Some("VANISHED"), _) => {
+                    if let Some(uid_set) = response.values.last().and_then(Value::as_text) {
+                        vanished.extend(parse_uid_set(&uid_set));
+                    }
+                }
+                (_, Some("FETCH")) => {
+                    let Some(Value::List(items)) = response.values.get(2) else {
+                        continue;
+                    };
+                    if let Some(message) = parse_fetch_items(items) {
+                        fetched.push(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok((fetched, vanished))
+    }
+
+    pub fn uid_store(&mut self, uid: u64, change: &str) -> Result<(), MailError> {
+        self.command(&format!("UID STORE {uid} {change}"))?;
+        Ok(())
+    }
+
+    /// Waits in IDLE until the server reports a change in the selected folder or `timeout`
+    /// passes, returns whether something changed.
+    pub fn idle(&mut self, timeout: Duration) -> Result<bool, MailError>
+    where
+        S: IdleTimeout,
+    {
+        let tag = self.next_tag();
+        self.write_line(&format!("{tag} IDLE"))?;
+
+        let continuation = self.read_response_line()?;
+        if !continuation.starts_with(b"+") {
+            Err(MailError::Source(format!(
+                "server refused idle: {}",
+                String::from_utf8_lossy(&continuation).trim()
+            )))?
+        }
+
+        self.stream.get_mut().set_idle_timeout(Some(timeout))?;
+        let changed = loop {
+            match self.read_response_line() {
+                Ok(line) => {
+                    let line = String::from_utf8_lossy(&line).to_ascii_uppercase();
+                    if line.contains("EXISTS")
+                        || line.contains("EXPUNGE")
+                        || line.contains("FETCH")
+                        || line.contains("VANISHED")
+                    {
+                        break true;
+                    }
+                }
+                Err(error) if is_timeout(&error) => break false,
+                Err(error) => return Err(error),
+            }
+        };
+        self.stream.get_mut().set_idle_timeout(None)?;
+
+        self.write_line("DONE")?;
+        loop {
+            let line = self.read_response_line()?;
+            if line.starts_with(tag.as_bytes()) {
+                check_tagged(&tag, &line)?;
+                break;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    pub fn logout(&mut self) -> Result<(), MailError> {
+        self.command("LOGOUT")?;
+        Ok(())
+    }
+
+    fn next_tag(&mut self) -> String {
+        self.tag += 1;
+        format!("A{:04}", self.tag)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), MailError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Sends a command and collects the untagged responses until its tagged completion.
+    pub fn command(&mut self, command: &str) -> Result<Vec<Untagged>, MailError> {
+        let tag = self.next_tag();
+        self.write_line(&format!("{tag} {command}"))?;
+
+        let mut responses = Vec::new();
+        loop {
+            let line = self.read_response_line()?;
+
+            if let Some(untagged) = line.strip_prefix(b"* ") {
+                responses.push(Untagged {
+                    values: parse_values(untagged)?,
+                    text: String::from_utf8_lossy(&strip_literals(untagged)).into_owned(),
+                });
+            } else if line.starts_with(tag.as_bytes()) {
+                check_tagged(&tag, &line)?;
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Reads a response line, with the content of literals (`{n}`) it announces included.
+    fn read_response_line(&mut self) -> Result<Vec<u8>, MailError> {
+        let mut response = Vec::new();
+
+        loop {
+            let mut line = Vec::new();
+            let read = self.stream.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                Err(MailError::Source("imap connection closed".to_string()))?
+            }
+            response.extend_from_slice(&line);
+
+            match literal_length(&line) {
+                Some(length) => {
+                    let mut literal = vec![0; length];
+                    self.stream.read_exact(&mut literal)?;
+                    response.extend_from_slice(&literal);
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+}
+
+/// Streams that can interrupt a blocking read, so IDLE can give up after a while.
+pub trait IdleTimeout {
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl IdleTimeout for TcpStream {
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+impl IdleTimeout for StreamOwned<ClientConnection, TcpStream> {
+    fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+fn connect(host: &str, port: u16) -> Result<TcpStream, MailError> {
+    TcpStream::connect((host, port))
+        .map_err(|error| MailError::io(format!("could not connect to {host}:{port}"), error))
+}
+
+fn is_timeout(error: &MailError) -> bool {
+    matches!(
+        error,
+        MailError::Io(error) if matches!(
+            error.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    )
+}
+
+/// A failed LOGIN or AUTHENTICATE means the credentials were rejected.
+fn refused_login(error: MailError) -> MailError {
+    match error {
+        MailError::Source(reason) => MailError::Auth(reason),
+        error => error,
+    }
+}
+
+fn check_tagged(tag: &str, line: &[u8]) -> Result<(), MailError> {
+    let line = String::from_utf8_lossy(line);
+    let status = line[tag.len()..].trim_start();
+
+    if status.to_ascii_uppercase().starts_with("OK") {
+        Ok(())
+    } else {
+        Err(MailError::Source(format!(
+            "imap command failed: {}",
+            status.trim()
+        )))
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The length of the literal announced at the end of a line, like `{12}` or `{12+}`.
+pub fn literal_length(line: &[u8]) -> Option<usize> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))?;
+    let line = line.strip_suffix(b"}")?;
+    let start = line.iter().rposition(|&byte| byte == b'{')?;
+    let length = std::str::from_utf8(&line[start + 1..]).ok()?;
+    length.trim_end_matches('+').parse().ok()
+}
+
+fn strip_literals(response: &[u8]) -> Vec<u8> {
+    let mut text = Vec::new();
+    let mut rest = response;
+
+    while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+        let line = &rest[..=end];
+        text.extend_from_slice(line);
+        rest = &rest[end + 1..];
+        if let Some(length) = literal_length(line) {
+            rest = &rest[length.min(rest.len())..];
+        }
+    }
+    text.extend_from_slice(rest);
+
+    text
+}
+
+/// Parses the values of a response, with literals already read into it.
+pub fn parse_values(input: &[u8]) -> Result<Vec<Value>, MailError> {
+    let mut position = 0;
+    let values = parse_sequence(input, &mut position, false)?;
+    Ok(values)
+}
+
+fn parse_sequence(
+    input: &[u8],
+    position: &mut usize,
+    in_list: bool,
+) -> Result<Vec<Value>, MailError> {
+    let mut values = Vec::new();
+
+    while *position < input.len() {
+        match input[*position] {
+            b' ' | b'\r' | b'\n' => *position += 1,
+            b'(' => {
+                *position += 1;
+                values.push(Value::List(parse_sequence(input, position, true)?));
+            }
+            b')' if in_list => {
+                *position += 1;
+                return Ok(values);
+            }
+            b'"' => {
+                *position += 1;
+                let mut string = Vec::new();
+                while *position < input.len() && input[*position] != b'"' {
+                    if input[*position] == b'\\' {
+                        *position += 1;
+                    }
+                    if let Some(&byte) = input.get(*position) {
+                        string.push(byte);
+                    }
+                    *position += 1;
+                }
+                *position += 1;
+                values.push(Value::String(string));
+            }
+            b'{' => {
+                let end = input[*position..]
+                    .iter()
+                    .position(|&byte| byte == b'}')
+                    .ok_or_else(|| MailError::parse_response("unterminated imap literal"))?
+                    + *position;
+                let length: usize = std::str::from_utf8(&input[*position + 1..end])
+                    .ok()
+                    .and_then(|length| length.trim_end_matches('+').parse().ok())
+                    .ok_or_else(|| MailError::parse_response("invalid imap literal length"))?;
+                let mut start = end + 1;
+                if input[start..].starts_with(b"\r\n") {
+                    start += 2;
+                } else if input[start..].starts_with(b"\n") {
+                    start += 1;
+                }
+                let literal = input.get(start..start + length).ok_or_else(|| {
+                    MailError::parse_response("imap literal is shorter than announced")
+                })?;
+                values.push(Value::String(literal.to_vec()));
+                *position = start + length;
+            }
+            _ => {
+                let start = *position;
+                let mut depth = 0;
+                while *position < input.len() {
+                    match input[*position] {
+                        b'[' => depth += 1,
+                        b']' => depth -= 1,
+                        b' ' | b')' | b'\r' | b'\n' if depth <= 0 => break,
+                        _ => {}
+                    }
+                    *position += 1;
+                }
+                let atom = String::from_utf8_lossy(&input[start..*position]).into_owned();
+                if atom.eq_ignore_ascii_case("NIL") {
+                    values.push(Value::Nil);
+                } else {
+                    values.push(Value::Atom(atom));
+                }
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+fn parse_fetch_items(items: &[Value]) -> Option<FetchedUid> {
+    let mut message = FetchedUid {
+        uid: 0,
+        flags: Vec::new(),
+        body: None,
+    };
+
+    for pair in items.chunks(2) {
+        let [name, value] = pair else {
+            continue;
+        };
+        match name.as_text()?.to_ascii_uppercase().as_str() {
+            "UID" => message.uid = value.as_number()?,
+            "FLAGS" => {
+                if let Value::List(flags) = value {
+                    message.flags = flags.iter().filter_map(Value::as_text).collect();
+                }
+            }
+            "BODY[]" => {
+                if let Value::String(body) = value {
+                    message.body = Some(body.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (message.uid != 0).then_some(message)
+}
+
+/// Expands a uid set like `1:3,7` into its uids.
+pub fn parse_uid_set(uid_set: &str) -> Vec<u64> {
+    uid_set
+        .split(',')
+        .flat_map(|range| match range.split_once(':') {
+            Some((start, end)) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) => (start.min(end)..=start.max(end)).collect(),
+                _ => Vec::new(),
+            },
+            None => range.parse().into_iter().collect(),
+        })
+        .collect()
+}
+
+/// Collapses `uids` into a uid set like `1:3,7`, the inverse of `parse_uid_set`.
+pub fn format_uid_set(uids: &[u64]) -> String {
+    let mut uids = uids.to_vec();
+    uids.sort_unstable();
+    uids.dedup();
+
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}:{end}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Translates the special-use attributes of a folder (RFC 6154) to our labels.
+fn folder_label(folder: &Folder) -> Option<&'static str> {
+    if folder.name.eq_ignore_ascii_case("INBOX") {
+        return Some(search::LABEL_INBOX);
+    }
+
+    folder
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute.to_ascii_lowercase().as_str() {
+            "\\sent" => Some(search::LABEL_SENT),
+            "\\junk" => Some(search::LABEL_SPAM),
+            "\\trash" => Some(search::LABEL_BIN),
+            "\\flagged" => Some(search::LABEL_STARRED),
+            _ => None,
+        })
+}
+
+fn flag_labels(flags: &[String]) -> Vec<&'static str> {
+    let mut labels = Vec::new();
+
+    if !flags.iter().any(|flag| flag.eq_ignore_ascii_case("\\Seen")) {
+        labels.push(search::LABEL_UNREAD);
+    }
+    for flag in flags {
+        match flag.to_ascii_lowercase().as_str() {
+            "\\flagged" => labels.push(search::LABEL_STARRED),
+            "\\answered" => labels.push(search::LABEL_REPLIED),
+            "\\deleted" => labels.push(search::LABEL_BIN),
+            "$important" => labels.push(search::LABEL_IMPORTANT),
+            "$junk" => labels.push(search::LABEL_SPAM),
+            _ => {}
+        }
+    }
+
+    labels
+}
+
+/// `+FLAGS`/`-FLAGS` changes that correspond to adding and removing a label. Labels that are
+/// folders in IMAP, like inbox or sent, would need a move and are not supported.
+fn flag_change(label: &str, add: bool) -> Result<String, MailError> {
+    let (flag, add) = match label {
+        search::LABEL_STARRED => ("\\Flagged", add),
+        search::LABEL_BIN => ("\\Deleted", add),
+        search::LABEL_REPLIED => ("\\Answered", add),
+        search::LABEL_IMPORTANT => ("$Important", add),
+        search::LABEL_UNREAD => ("\\Seen", !add),
+        _ => Err(MailError::Invalid(format!(
+            "label '{label}' can not be changed over imap"
+        )))?,
+    };
+
+    Ok(format!(
+        "{}FLAGS.SILENT ({flag})",
+        if add { "+" } else { "-" }
+    ))
+}
+
+/// Parses message ids of the form `imap:<account>:<uidvalidity>:<uid>:<folder>`. The folder
+/// goes last, as it may contain colons itself.
+pub fn parse_message_id(message_id: &str) -> Option<(u64, u64, &str)> {
+    let mut parts = message_id.strip_prefix("imap:")?.splitn(4, ':').skip(1);
+    let uid_validity = parts.next()?.parse().ok()?;
+    let uid = parts.next()?.parse().ok()?;
+    let folder = parts.next()?;
+    Some((uid_validity, uid, folder))
+}
+
+pub enum ImapStream {
+    Tls(Box<ImapSession<StreamOwned<ClientConnection, TcpStream>>>),
+    Plain(ImapSession<TcpStream>),
+}
+
+pub struct ImapSource {
+    session: ImapStream,
+    account: String,
+    /// The folders to index, all folders when empty.
+    folder_names: Vec<String>,
+    folders: Vec<Folder>,
+}
+
+macro_rules! with_session {
+    ($source:expr, $session:ident => $body:expr) => {
+        match &mut $source.session {
+            ImapStream::Tls($session) => $body,
+            ImapStream::Plain($session) => $body,
+        }
+    };
+}
+
+impl ImapSource {
+    pub fn connect() -> Result<Self, MailError> {
+        let credentials: ImapCredentials = utils::read_json(
+            &constants::IMAP_CREDENTIALS.display().to_string(),
+        )
+        .map_err(|error| MailError::Config(format!("could not read imap credentials: {error}")))?;
+
+        Self::connect_with(&credentials)
+    }
+
+    pub fn connect_with(credentials: &ImapCredentials) -> Result<Self, MailError> {
+        let session = if credentials.insecure {
+            ImapStream::Plain(ImapSession::connect_plain(
+                &credentials.host,
+                credentials.port,
+            )?)
+        } else {
+            ImapStream::Tls(Box::new(ImapSession::connect_tls(
+                &credentials.host,
+                credentials.port,
+            )?))
+        };
+
+        let mut source = Self {
+            session,
+            account: credentials.username.to_string(),
+            folder_names: credentials.folders.clone(),
+            folders: Vec::new(),
+        };
+
+        with_session!(source, session => {
+            match &credentials.auth {
+                ImapAuth::Password(password) => session.login(&credentials.username, password)?,
+                ImapAuth::OAuthToken(token) => {
+                    session.authenticate_xoauth2(&credentials.username, token)?
+                }
+            }
+            if session.has_capability("QRESYNC") {
+                session.enable("QRESYNC")?;
+            } else if session.has_capability("CONDSTORE") {
+                session.enable("CONDSTORE")?;
+            }
+        });
+
+        source.refresh_folders()?;
+        Ok(source)
+    }
+
+    /// Lists the folders again, so folders created or deleted since connecting are noticed.
+    fn refresh_folders(&mut self) -> Result<(), MailError> {
+        let folders = with_session!(self, session => session.list()?);
+        self.folders = folders
+            .into_iter()
+            .filter(|folder| {
+                self.folder_names.is_empty() || self.folder_names.contains(&folder.name)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Selects `folder` and records its uids, with their flags when the server can not report
+    /// flag changes through CONDSTORE.
+    fn snapshot(&mut self, folder: &str) -> Result<FolderState, MailError> {
+        with_session!(self, session => {
+            let mut state = session.select(folder)?;
+            let uids = session.uid_search("ALL")?;
+            if state.highest_modseq.is_none() && !uids.is_empty() {
+                let (fetched, _) = session.uid_fetch(&format_uid_set(&uids), false, None)?;
+                state.flags = fetched
+                    .into_iter()
+                    .map(|message| (message.uid, message.flags))
+                    .collect();
+            }
+            state.uids = format_uid_set(&uids);
+            Ok(state)
+        })
+    }
+
+    fn message_id(&self, folder: &str, uid_validity: u64, uid: u64) -> String {
+        format!("imap:{}:{uid_validity}:{uid}:{folder}", self.account)
+    }
+
+    /// Waits for changes in `folder` with IDLE, returns whether something changed before the
+    /// timeout.
+    pub fn idle(&mut self, folder: &str, timeout: Duration) -> Result<bool, MailError> {
+        with_session!(self, session => {
+            session.select(folder)?;
+            session.idle(timeout)
+        })
+    }
+
+    pub fn logout(&mut self) -> Result<(), MailError> {
+        with_session!(self, session => session.logout())
+    }
+}
+
+#[async_trait(?Send)]
+impl MailSource for ImapSource {
+    fn name(&self) -> &str {
+        "imap"
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError> {
+        utils::blocking(|| {
+            let mut message_ids = Vec::new();
+
+            for folder in self.folders.clone() {
+                let (state, uids) = with_session!(self, session => {
+                    let state = session.select(&folder.name)?;
+                    (state, session.uid_search("ALL")?)
+                });
+
+                let skip = uids.len().saturating_sub(limit as usize);
+                message_ids.extend(
+                    uids[skip..]
+                        .iter()
+                        .map(|uid| self.message_id(&folder.name, state.uid_validity, *uid)),
+                );
+            }
+
+            Ok(message_ids)
+        })
+    }
+
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError> {
+        utils::blocking(|| {
+            let mut by_folder: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
+            let mut failures = Vec::new();
+            for message_id in message_ids {
+                match parse_message_id(message_id) {
+                    Some((uid_validity, uid, folder)) => by_folder
+                        .entry(folder)
+                        .or_default()
+                        .push((uid_validity, uid)),
+                    None => failures.push(FetchFailure {
+                        message_id: message_id.to_string(),
+                        error: MailError::Invalid(format!(
+                            "'{message_id}' is not an imap message id"
+                        )),
+                    }),
+                }
+            }
+
+            let mut fetched_messages = Vec::new();
+
+            for (folder_name, uids) in by_folder {
+                let folder = self
+                    .folders
+                    .iter()
+                    .find(|folder| folder.name == folder_name)
+                    .cloned()
+                    .unwrap_or(Folder {
+                        name: folder_name.to_string(),
+                        attributes: Vec::new(),
+                    });
+
+                let (state, fetched) = with_session!(self, session => {
+                    let state = session.select(&folder.name)?;
+                    let uid_set = uids
+                        .iter()
+                        .filter(|(uid_validity, _)| *uid_validity == state.uid_validity)
+                        .map(|(_, uid)| uid.to_string())
+                        .collect::<Vec<String>>()
+                        .join(",");
+                    if uid_set.is_empty() {
+                        continue;
+                    }
+                    (state, session.uid_fetch(&uid_set, true, None)?.0)
+                });
+
+                for message in fetched {
+                    let Some(body) = message.body else {
+                        continue;
+                    };
+
+                    let mut labels: Vec<String> = folder_label(&folder)
+                        .into_iter()
+                        .chain(flag_labels(&message.flags))
+                        .map(str::to_string)
+                        .collect();
+                    labels.dedup();
+
+                    let mut label_ids = vec![folder.name.to_string()];
+                    label_ids.extend(message.flags);
+
+                    fetched_messages.push(FetchedMessage {
+                        id: self.message_id(&folder.name, state.uid_validity, message.uid),
+                        metadata: MessageMetadata {
+                            source: self.name().to_string(),
+                            account: self.account.to_string(),
+                            history_id: state.highest_modseq.map(|modseq| modseq.to_string()),
+                            thread_id: None,
+                            label_ids,
+                            labels,
+                            time: None,
+                        },
+                        format: MessageFormat::Raw,
+                        content: body,
+                    });
+                }
+            }
+
+            Ok(Fetched {
+                messages: fetched_messages,
+                failures,
+            })
+        })
+    }
+
+    /// The checkpoint is the state of every folder, as JSON.
+    async fn checkpoint(&mut self) -> Result<String, MailError> {
+        utils::blocking(|| {
+            let mut states: BTreeMap<String, FolderState> = BTreeMap::new();
+
+            self.refresh_folders()?;
+            for folder in self.folders.clone() {
+                states.insert(folder.name.to_string(), self.snapshot(&folder.name)?);
+            }
+
+            Ok(serde_json::to_string(&states)?)
+        })
+    }
+
+    /// Deleted messages are found by comparing the uids with the checkpoint. Flag changes come
+    /// from CHANGEDSINCE when the server supports CONDSTORE, otherwise from comparing the flags.
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError> {
+        utils::blocking(|| {
+            let mut previous: BTreeMap<String, FolderState> = serde_json::from_str(checkpoint)
+                .map_err(|error| {
+                    MailError::Checkpoint(format!("could not parse imap checkpoint: {error}"))
+                })?;
+
+            let mut changes = Changes::default();
+            let mut states: BTreeMap<String, FolderState> = BTreeMap::new();
+
+            self.refresh_folders()?;
+            for folder in self.folders.clone() {
+                let state = self.snapshot(&folder.name)?;
+                let uids = parse_uid_set(&state.uids);
+
+                let mut changed = BTreeSet::new();
+                let mut deleted = BTreeSet::new();
+                match previous.remove(&folder.name) {
+                    // the folder was recreated, its old messages are gone and every message is new.
+                    Some(previous) if previous.uid_validity != state.uid_validity => {
+                        changes.deleted.extend(
+                            parse_uid_set(&previous.uids).into_iter().map(|uid| {
+                                self.message_id(&folder.name, previous.uid_validity, uid)
+                            }),
+                        );
+                        changed.extend(uids);
+                    }
+                    None => changed.extend(uids),
+                    Some(previous) => {
+                        let previous_uids: BTreeSet<u64> =
+                            parse_uid_set(&previous.uids).into_iter().collect();
+                        deleted.extend(
+                            previous_uids
+                                .iter()
+                                .filter(|uid| !uids.contains(uid))
+                                .copied(),
+                        );
+                        changed.extend(uids.iter().filter(|uid| **uid >= previous.uid_next));
+
+                        match (previous.highest_modseq, state.highest_modseq) {
+                            (Some(previous_modseq), Some(modseq)) if previous_modseq == modseq => {}
+                            (Some(previous_modseq), Some(_)) => {
+                                let (fetched, vanished) = with_session!(self, session => {
+                                    session.uid_fetch("1:*", false, Some(previous_modseq))?
+                                });
+                                changed.extend(fetched.into_iter().map(|message| message.uid));
+                                deleted.extend(vanished);
+                            }
+                            _ => changed.extend(state.flags.iter().filter_map(|(uid, flags)| {
+                                previous
+                                    .flags
+                                    .get(uid)
+                                    .is_some_and(|previous_flags| previous_flags != flags)
+                                    .then_some(*uid)
+                            })),
+                        }
+                    }
+                }
+
+                changes.changed.extend(
+                    changed
+                        .difference(&deleted)
+                        .map(|uid| self.message_id(&folder.name, state.uid_validity, *uid)),
+                );
+                changes.deleted.extend(
+                    deleted
+                        .into_iter()
+                        .map(|uid| self.message_id(&folder.name, state.uid_validity, uid)),
+                );
+                states.insert(folder.name, state);
+            }
+
+            // folders that no longer exist, or are no longer indexed, take their messages along.
+            for (name, state) in previous {
+                changes.deleted.extend(
+                    parse_uid_set(&state.uids)
+                        .into_iter()
+                        .map(|uid| self.message_id(&name, state.uid_validity, uid)),
+                );
+            }
+
+            changes.checkpoint = serde_json::to_string(&states)?;
+            Ok(changes)
+        })
+    }
+
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError> {
+        utils::blocking(|| {
+            let (uid_validity, uid, folder) = parse_message_id(message_id).ok_or_else(|| {
+                MailError::Invalid(format!("'{message_id}' is not an imap message id"))
+            })?;
+            let folder = folder.to_string();
+
+            let mut flag_changes = Vec::new();
+            for label in add {
+                flag_changes.push(flag_change(label, true)?);
+            }
+            for label in remove {
+                flag_changes.push(flag_change(label, false)?);
+            }
+
+            with_session!(self, session => {
+                let state = session.select(&folder)?;
+                if state.uid_validity != uid_validity {
+                    return Err(MailError::Invalid(format!(
+                        "{folder} was recreated, {message_id} no longer exists"
+                    )));
+                }
+                for flag_change in &flag_changes {
+                    session.uid_store(uid, flag_change)?;
+                }
+            });
+
+            Ok(())
+        })
+    }
+}
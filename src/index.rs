@@ -0,0 +1,366 @@
+use crate::constants;
+use crate::error::MailError;
+use crate::schema;
+use crate::search::{self, ImportReport, Mail, Query};
+use async_trait::async_trait;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use typesense::apis::configuration::Configuration;
+
+/// Where searchable mail ends up. The engine only adds and removes documents, searching is up
+/// to the backend.
+#[async_trait(?Send)]
+pub trait SearchIndex {
+    /// Gets the index ready for documents, e.g. creates or migrates the collection.
+    async fn prepare(&self) -> Result<(), MailError>;
+
+    /// Adds or replaces documents by id.
+    async fn import(&self, mails: &[Mail]) -> Result<ImportReport, MailError>;
+
+    /// Removes documents by id, ids that are not in the index are ignored.
+    async fn delete(&self, message_ids: &[String]) -> Result<(), MailError>;
+
+    /// Ids of the documents matching `query`, best match first.
+    async fn search(&self, query: &Query) -> Result<Vec<String>, MailError>;
+}
+
+/// The mail collection in Typesense, searched through its alias.
+#[derive(Clone)]
+pub struct TypesenseIndex {
+    configuration: Configuration,
+    collection_name: String,
+}
+
+impl TypesenseIndex {
+    pub fn new(configuration: Configuration) -> Self {
+        Self {
+            configuration,
+            collection_name: constants::SEARCHABLE_MAIL_COLLECTION_NAME.to_string(),
+        }
+    }
+
+    /// Connects with the credentials in `TYPESENSE_CREDENTIALS`.
+    pub fn from_credentials() -> Result<Self, MailError> {
+        Ok(Self::new(search::get_typesense_configuration()?))
+    }
+
+    pub fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+}
+
+#[async_trait(?Send)]
+impl SearchIndex for TypesenseIndex {
+    async fn prepare(&self) -> Result<(), MailError> {
+        let migration = schema::migrate_mail_collection(&self.configuration).await?;
+
+        match migration {
+            schema::Migration::Created => println!("created mail collection"),
+            schema::Migration::UpToDate => {}
+            schema::Migration::Patched(diff) => println!("patched mail collection:\n{diff}"),
+            schema::Migration::Rebuilt(diff) => println!("rebuilt mail collection:\n{diff}"),
+        }
+
+        Ok(())
+    }
+
+    async fn import(&self, mails: &[Mail]) -> Result<ImportReport, MailError> {
+        search::import_documents(
+            &self.configuration,
+            &self.collection_name,
+            mails,
+            constants::IMPORT_BATCH_SIZE,
+        )
+        .await
+    }
+
+    async fn delete(&self, message_ids: &[String]) -> Result<(), MailError> {
+        search::delete_documents(&self.configuration, &self.collection_name, message_ids).await
+    }
+
+    async fn search(&self, query: &Query) -> Result<Vec<String>, MailError> {
+        search::search_document_ids(&self.configuration, &self.collection_name, query).await
+    }
+}
+
+// the fields searched and how much a match in each counts, in the order Typesense queries them.
+const MEMORY_QUERY_BY: [(&str, usize); 4] = [
+    ("subject", 8),
+    ("searchable_body", 4),
+    ("from", 2),
+    ("to", 1),
+];
+
+/// Documents kept in memory and searched roughly like Typesense does, so the engine can be
+/// tested without a Typesense server. Clones share the documents.
+///
+/// Every word of the query has to match the start of a word in the subject, body, from or to,
+/// words with a `-` in front must not. Matches in the subject count most, then the body, from
+/// and to, ties go to the newest message. `filter_by` takes clauses joined with `&&`, like
+/// `labels:=inbox`, `labels:[inbox,sent]`, `from:!=me@example.com`, `time:>=1700000000` or
+/// `time:[1700000000..1800000000]`.
+#[derive(Clone, Default)]
+pub struct MemoryIndex {
+    documents: Arc<Mutex<BTreeMap<String, Mail>>>,
+}
+
+impl MemoryIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.lock().unwrap().is_empty()
+    }
+
+    pub fn get(&self, message_id: &str) -> Option<Mail> {
+        self.documents.lock().unwrap().get(message_id).cloned()
+    }
+
+    /// Every document id, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        self.documents.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl SearchIndex for MemoryIndex {
+    async fn prepare(&self) -> Result<(), MailError> {
+        Ok(())
+    }
+
+    async fn import(&self, mails: &[Mail]) -> Result<ImportReport, MailError> {
+        let mut documents = self.documents.lock().unwrap();
+        for mail in mails {
+            documents.insert(mail.id.to_string(), mail.clone());
+        }
+
+        Ok(ImportReport {
+            imported: mails.len(),
+            failures: Vec::new(),
+        })
+    }
+
+    async fn delete(&self, message_ids: &[String]) -> Result<(), MailError> {
+        let mut documents = self.documents.lock().unwrap();
+        for message_id in message_ids {
+            documents.remove(message_id);
+        }
+        Ok(())
+    }
+
+    async fn search(&self, query: &Query) -> Result<Vec<String>, MailError> {
+        let filter = match &query.filter_by {
+            Some(filter_by) => Filter::parse(filter_by)?,
+            None => Filter::default(),
+        };
+
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for word in query.text.split_whitespace().filter(|word| *word != "*") {
+            match word.strip_prefix('-') {
+                Some(word) => excluded.extend(words(word)),
+                None => included.extend(words(word)),
+            }
+        }
+
+        let documents = self.documents.lock().unwrap();
+        let mut hits = Vec::new();
+        for mail in documents.values() {
+            if !filter.matches(mail)? {
+                continue;
+            }
+            if excluded.iter().any(|word| text_score(mail, word) > 0) {
+                continue;
+            }
+
+            let mut score = 0;
+            let mut matched_every_word = true;
+            for word in &included {
+                let word_score = text_score(mail, word);
+                matched_every_word &= word_score > 0;
+                score += word_score;
+            }
+            if matched_every_word {
+                hits.push((Reverse(score), Reverse(mail.time), mail.id.to_string()));
+            }
+        }
+
+        hits.sort();
+        Ok(hits.into_iter().map(|(_, _, id)| id).collect())
+    }
+}
+
+/// Lower cased words, splitting on anything that is not a letter or digit.
+fn words(text: &str) -> Vec<String> {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How well `word` matches the searched fields of `mail`, 0 if it does not.
+fn text_score(mail: &Mail, word: &str) -> usize {
+    MEMORY_QUERY_BY
+        .iter()
+        .filter(|(field, _)| {
+            let text = match *field {
+                "subject" => mail.subject.as_deref().unwrap_or_default(),
+                "searchable_body" => &mail.searchable_body,
+                "from" => &mail.from,
+                _ => mail.to.as_deref().unwrap_or_default(),
+            };
+            words(text)
+                .iter()
+                .any(|field_word| field_word.starts_with(word))
+        })
+        .map(|(_, weight)| weight)
+        .sum()
+}
+
+/// A `filter_by` expression of clauses that all have to match.
+#[derive(Default)]
+struct Filter {
+    clauses: Vec<Clause>,
+}
+
+struct Clause {
+    field: String,
+    operator: String,
+    values: Vec<String>,
+}
+
+impl Filter {
+    fn parse(filter_by: &str) -> Result<Self, MailError> {
+        if filter_by.contains("||") || filter_by.contains('(') {
+            return Err(MailError::Invalid(format!(
+                "the memory index only filters by clauses joined with &&, not '{filter_by}'"
+            )));
+        }
+
+        let mut clauses = Vec::new();
+        for clause in filter_by.split("&&") {
+            let (field, condition) = clause.split_once(':').ok_or_else(|| {
+                MailError::Invalid(format!("could not parse filter clause '{}'", clause.trim()))
+            })?;
+            let condition = condition.trim();
+            let operator = ["!=", ">=", "<=", "=", ">", "<"]
+                .into_iter()
+                .find(|operator| condition.starts_with(operator))
+                .unwrap_or_default();
+            let value = condition[operator.len()..].trim();
+            let values = match value
+                .strip_prefix('[')
+                .and_then(|list| list.strip_suffix(']'))
+            {
+                Some(list) => list.split(',').map(filter_value).collect(),
+                None => vec![filter_value(value)],
+            };
+
+            clauses.push(Clause {
+                field: field.trim().to_string(),
+                operator: operator.to_string(),
+                values,
+            });
+        }
+
+        Ok(Self { clauses })
+    }
+
+    fn matches(&self, mail: &Mail) -> Result<bool, MailError> {
+        for clause in &self.clauses {
+            if !clause.matches(mail)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Values can be quoted with backticks, like in Typesense.
+fn filter_value(value: &str) -> String {
+    value.trim().trim_matches('`').to_string()
+}
+
+impl Clause {
+    fn matches(&self, mail: &Mail) -> Result<bool, MailError> {
+        let texts: Vec<&str> = match self.field.as_str() {
+            "time" => return self.matches_time(mail.time),
+            "id" => vec![&mail.id],
+            "thread_id" => vec![&mail.thread_id],
+            "subject" => mail.subject.as_deref().into_iter().collect(),
+            "from" => vec![&mail.from],
+            "to" => mail.to.as_deref().into_iter().collect(),
+            "source" => vec![&mail.source],
+            "account" => vec![&mail.account],
+            "labels" => mail.labels.iter().map(String::as_str).collect(),
+            "accounts" => mail.accounts.iter().map(String::as_str).collect(),
+            field => {
+                return Err(MailError::Invalid(format!(
+                    "cannot filter by unknown field '{field}'"
+                )));
+            }
+        };
+
+        // `:=` matches the whole value, `:` any of its words.
+        let matches = |value: &String| {
+            texts.iter().any(|text| match self.operator.as_str() {
+                "" => {
+                    text.eq_ignore_ascii_case(value) || words(text).contains(&value.to_lowercase())
+                }
+                _ => text == value,
+            })
+        };
+        match self.operator.as_str() {
+            "" | "=" => Ok(self.values.iter().any(matches)),
+            "!=" => Ok(!self.values.iter().any(matches)),
+            operator => Err(MailError::Invalid(format!(
+                "cannot compare {} with {operator}",
+                self.field
+            ))),
+        }
+    }
+
+    fn matches_time(&self, time: Option<i64>) -> Result<bool, MailError> {
+        let Some(time) = time else {
+            return Ok(self.operator == "!=");
+        };
+        let number = |value: &str| {
+            value.parse::<i64>().map_err(|error| {
+                MailError::Invalid(format!("could not parse '{value}' as time: {error}"))
+            })
+        };
+
+        if self.operator == "!=" {
+            for value in &self.values {
+                if time == number(value)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+
+        let mut matched = false;
+        for value in &self.values {
+            matched |= match value.split_once("..") {
+                Some((from, to)) => (number(from)?..=number(to)?).contains(&time),
+                None => {
+                    let value = number(value)?;
+                    match self.operator.as_str() {
+                        "" | "=" => time == value,
+                        ">" => time > value,
+                        ">=" => time >= value,
+                        "<" => time < value,
+                        _ => time <= value,
+                    }
+                }
+            };
+        }
+        Ok(matched)
+    }
+}
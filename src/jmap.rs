@@ -0,0 +1,507 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::client;
+use crate::constants;
+use crate::error::MailError;
+use crate::search;
+use crate::source::{Changes, FetchFailure, Fetched, FetchedMessage, MailSource};
+use crate::utils;
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+const JMAP_CORE: &str = "urn:ietf:params:jmap:core";
+const JMAP_MAIL: &str = "urn:ietf:params:jmap:mail";
+const MAXIMUM_CHANGES: u32 = 500;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JmapCredentials {
+    /// e.g. `https://api.fastmail.com/jmap/session` or `https://mail.example.com/.well-known/jmap`.
+    pub session_url: String,
+    pub auth: JmapAuth,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JmapAuth {
+    /// An API token, as Fastmail hands out, or an OAuth access token.
+    Bearer(String),
+    Basic {
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub username: String,
+    pub api_url: String,
+    /// A URI template with `{accountId}`, `{blobId}`, `{name}` and `{type}`.
+    pub download_url: String,
+    pub primary_accounts: HashMap<String, String>,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Capabilities {
+    #[serde(rename = "urn:ietf:params:jmap:core")]
+    pub core: CoreCapabilities,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CoreCapabilities {
+    /// How many ids a single `/get` call may ask for.
+    pub max_objects_in_get: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiResponse {
+    method_responses: Vec<(String, Value, String)>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mailbox {
+    pub id: String,
+    pub name: String,
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Email {
+    pub id: String,
+    pub blob_id: String,
+    pub thread_id: String,
+    #[serde(default)]
+    pub mailbox_ids: HashMap<String, bool>,
+    #[serde(default)]
+    pub keywords: HashMap<String, bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetResponse<T> {
+    state: String,
+    list: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryResponse {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangesResponse {
+    new_state: String,
+    has_more_changes: bool,
+    created: Vec<String>,
+    updated: Vec<String>,
+    destroyed: Vec<String>,
+}
+
+/// Mailbox roles (RFC 8621) and the labels they map to.
+fn role_label(role: &str) -> Option<&'static str> {
+    match role {
+        "inbox" => Some(search::LABEL_INBOX),
+        "sent" => Some(search::LABEL_SENT),
+        "junk" => Some(search::LABEL_SPAM),
+        "trash" => Some(search::LABEL_BIN),
+        "flagged" => Some(search::LABEL_STARRED),
+        "important" => Some(search::LABEL_IMPORTANT),
+        _ => None,
+    }
+}
+
+/// Keywords and the labels they map to. `$seen` is left to the caller, as its absence is what
+/// means unread.
+fn keyword_label(keyword: &str) -> Option<&'static str> {
+    match keyword {
+        "$flagged" => Some(search::LABEL_STARRED),
+        "$answered" => Some(search::LABEL_REPLIED),
+        "$important" => Some(search::LABEL_IMPORTANT),
+        "$junk" => Some(search::LABEL_SPAM),
+        _ => None,
+    }
+}
+
+/// A JMAP mail account, e.g. at Fastmail or on a Stalwart server. The sync checkpoint is the
+/// `Email` state string, which `Email/changes` continues from.
+pub struct JmapSource {
+    client: Client,
+    credentials: JmapCredentials,
+    session: Session,
+    account_id: String,
+    mailboxes: Vec<Mailbox>,
+}
+
+impl JmapSource {
+    pub async fn connect() -> Result<Self, MailError> {
+        let credentials: JmapCredentials = utils::read_json(
+            &constants::JMAP_CREDENTIALS.display().to_string(),
+        )
+        .map_err(|error| MailError::Config(format!("could not read jmap credentials: {error}")))?;
+
+        Self::connect_with(credentials).await
+    }
+
+    pub async fn connect_with(credentials: JmapCredentials) -> Result<Self, MailError> {
+        let client = Client::new();
+
+        let session: Session = client::json(
+            client::checked(
+                authorize(client.get(&credentials.session_url), &credentials.auth)
+                    .send()
+                    .await?,
+            )
+            .await?,
+        )
+        .await?;
+        let account_id = session
+            .primary_accounts
+            .get(JMAP_MAIL)
+            .ok_or_else(|| MailError::parse_response("jmap session has no mail account"))?
+            .to_string();
+
+        let mut source = Self {
+            client,
+            credentials,
+            session,
+            account_id,
+            mailboxes: Vec::new(),
+        };
+
+        let mailboxes: GetResponse<Mailbox> = source
+            .call(
+                "Mailbox/get",
+                json!({ "ids": null, "properties": ["id", "name", "role"] }),
+            )
+            .await?;
+        source.mailboxes = mailboxes.list;
+
+        Ok(source)
+    }
+
+    /// Calls a single method on the account and returns its response arguments.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        mut arguments: Value,
+    ) -> Result<T, MailError> {
+        arguments["accountId"] = json!(self.account_id);
+        let request = json!({
+            "using": [JMAP_CORE, JMAP_MAIL],
+            "methodCalls": [[method, arguments, "0"]],
+        });
+
+        let response: ApiResponse = client::json(
+            client::checked(
+                authorize(
+                    self.client.post(&self.session.api_url).json(&request),
+                    &self.credentials.auth,
+                )
+                .send()
+                .await?,
+            )
+            .await?,
+        )
+        .await?;
+        println!("[REQUEST] {} {method}", self.session.api_url);
+
+        let (name, arguments, _) =
+            response
+                .method_responses
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    MailError::parse_response(format!("jmap sent no response to {method}"))
+                })?;
+        // the server keeps a limited number of states to calculate changes from.
+        if name == "error" && arguments["type"] == "cannotCalculateChanges" {
+            return Err(MailError::Checkpoint(format!(
+                "jmap {method} failed: {arguments}"
+            )));
+        }
+        if name == "error" {
+            return Err(MailError::Source(format!(
+                "jmap {method} failed: {arguments}"
+            )));
+        }
+
+        Ok(serde_json::from_value(arguments)?)
+    }
+
+    async fn download(&self, blob_id: &str) -> Result<Vec<u8>, MailError> {
+        let url = self
+            .session
+            .download_url
+            .replace("{accountId}", &self.account_id)
+            .replace("{blobId}", blob_id)
+            .replace("{name}", "message.eml")
+            .replace("{type}", "message/rfc822");
+
+        let content = client::checked(
+            authorize(self.client.get(&url), &self.credentials.auth)
+                .send()
+                .await?,
+        )
+        .await?
+        .bytes()
+        .await?;
+        Ok(content.to_vec())
+    }
+
+    fn message_id(&self, email_id: &str) -> String {
+        format!("jmap:{}:{email_id}", self.account_id)
+    }
+
+    fn email_id<'a>(&self, message_id: &'a str) -> Result<&'a str, MailError> {
+        let email_id = message_id
+            .strip_prefix("jmap:")
+            .and_then(|rest| rest.strip_prefix(self.account_id.as_str()))
+            .and_then(|rest| rest.strip_prefix(':'))
+            .ok_or_else(|| {
+                MailError::Invalid(format!(
+                    "'{message_id}' is not a message of this jmap account"
+                ))
+            })?;
+        Ok(email_id)
+    }
+
+    /// Mailboxes with a role map to their label, others, like Fastmail labels, keep their name.
+    fn labels(&self, email: &Email) -> Vec<String> {
+        let mut labels: Vec<String> = self
+            .mailboxes
+            .iter()
+            .filter(|mailbox| email.mailbox_ids.get(&mailbox.id) == Some(&true))
+            .filter_map(|mailbox| match mailbox.role.as_deref() {
+                Some(role) => role_label(role).map(str::to_string),
+                None => Some(mailbox.name.to_string()),
+            })
+            .collect();
+
+        if email.keywords.get("$seen") != Some(&true) {
+            labels.push(search::LABEL_UNREAD.to_string());
+        }
+        for (keyword, set) in &email.keywords {
+            if !set {
+                continue;
+            }
+            match keyword_label(keyword) {
+                Some(label) => labels.push(label.to_string()),
+                None if !keyword.starts_with('$') => labels.push(keyword.to_string()),
+                None => {}
+            }
+        }
+
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+
+    fn mailbox_for(&self, label: &str) -> Option<&Mailbox> {
+        self.mailboxes.iter().find(|mailbox| match &mailbox.role {
+            Some(role) => role_label(role) == Some(label),
+            None => mailbox.name == label,
+        })
+    }
+}
+
+fn authorize(request: RequestBuilder, auth: &JmapAuth) -> RequestBuilder {
+    match auth {
+        JmapAuth::Bearer(token) => request.bearer_auth(token),
+        JmapAuth::Basic { username, password } => request.basic_auth(username, Some(password)),
+    }
+}
+
+#[async_trait(?Send)]
+impl MailSource for JmapSource {
+    fn name(&self) -> &str {
+        "jmap"
+    }
+
+    fn account(&self) -> &str {
+        &self.session.username
+    }
+
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError> {
+        let query: QueryResponse = self
+            .call(
+                "Email/query",
+                json!({
+                    "sort": [{ "property": "receivedAt", "isAscending": false }],
+                    "limit": limit,
+                }),
+            )
+            .await?;
+
+        Ok(query
+            .ids
+            .iter()
+            .map(|email_id| self.message_id(email_id))
+            .collect())
+    }
+
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError> {
+        let email_ids = message_ids
+            .iter()
+            .map(|message_id| self.email_id(message_id))
+            .collect::<Result<Vec<&str>, MailError>>()?;
+
+        let mut fetched = Fetched::default();
+        // servers refuse a /get for more ids than they allow.
+        let chunk_size = self.session.capabilities.core.max_objects_in_get.max(1);
+        for chunk in email_ids.chunks(chunk_size) {
+            let emails: GetResponse<Email> = self
+                .call(
+                    "Email/get",
+                    json!({
+                        "ids": chunk,
+                        "properties": ["id", "blobId", "threadId", "mailboxIds", "keywords"],
+                    }),
+                )
+                .await?;
+            for email in emails.list {
+                let content = match self.download(&email.blob_id).await {
+                    Ok(content) => content,
+                    Err(error) => {
+                        fetched.failures.push(FetchFailure {
+                            message_id: self.message_id(&email.id),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+
+                let mut label_ids: Vec<String> = email
+                    .mailbox_ids
+                    .iter()
+                    .filter(|(_, set)| **set)
+                    .map(|(mailbox_id, _)| mailbox_id.to_string())
+                    .collect();
+                label_ids.extend(
+                    email
+                        .keywords
+                        .iter()
+                        .filter(|(_, set)| **set)
+                        .map(|(keyword, _)| keyword.to_string()),
+                );
+                label_ids.sort();
+
+                fetched.messages.push(FetchedMessage {
+                    id: self.message_id(&email.id),
+                    metadata: MessageMetadata {
+                        source: self.name().to_string(),
+                        account: self.session.username.to_string(),
+                        history_id: Some(emails.state.to_string()),
+                        thread_id: Some(email.thread_id.to_string()),
+                        labels: self.labels(&email),
+                        label_ids,
+                        time: None,
+                    },
+                    format: MessageFormat::Raw,
+                    content,
+                });
+            }
+        }
+
+        Ok(fetched)
+    }
+
+    async fn checkpoint(&mut self) -> Result<String, MailError> {
+        let emails: GetResponse<Email> = self.call("Email/get", json!({ "ids": [] })).await?;
+        Ok(emails.state)
+    }
+
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError> {
+        let mut changes = Changes::default();
+        let mut state = checkpoint.to_string();
+
+        loop {
+            let response: ChangesResponse = self
+                .call(
+                    "Email/changes",
+                    json!({ "sinceState": state, "maxChanges": MAXIMUM_CHANGES }),
+                )
+                .await?;
+
+            for email_id in response.created.iter().chain(&response.updated) {
+                let message_id = self.message_id(email_id);
+                changes.deleted.retain(|id| id != &message_id);
+                if !changes.changed.contains(&message_id) {
+                    changes.changed.push(message_id);
+                }
+            }
+            for email_id in &response.destroyed {
+                let message_id = self.message_id(email_id);
+                changes.changed.retain(|id| id != &message_id);
+                changes.deleted.push(message_id);
+            }
+
+            state = response.new_state;
+            if !response.has_more_changes {
+                break;
+            }
+        }
+
+        changes.checkpoint = state;
+        Ok(changes)
+    }
+
+    /// Labels that are mailboxes add or remove the message from that mailbox, as JMAP allows a
+    /// message in several, everything else is a keyword.
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError> {
+        let email_id = self.email_id(message_id)?.to_string();
+
+        let mut patch = serde_json::Map::new();
+        let changes = add
+            .iter()
+            .map(|label| (label, true))
+            .chain(remove.iter().map(|label| (label, false)));
+        for (label, add) in changes {
+            let set = if add { json!(true) } else { Value::Null };
+            let path = match label.as_str() {
+                search::LABEL_UNREAD => {
+                    // unread is the absence of $seen.
+                    let seen = if add { Value::Null } else { json!(true) };
+                    patch.insert("keywords/$seen".to_string(), seen);
+                    continue;
+                }
+                search::LABEL_STARRED => "keywords/$flagged".to_string(),
+                search::LABEL_REPLIED => "keywords/$answered".to_string(),
+                label => match self.mailbox_for(label) {
+                    Some(mailbox) => format!("mailboxIds/{}", mailbox.id),
+                    None if label == search::LABEL_IMPORTANT => "keywords/$important".to_string(),
+                    None => format!("keywords/{label}"),
+                },
+            };
+            patch.insert(path, set);
+        }
+
+        let response: Value = self
+            .call(
+                "Email/set",
+                json!({ "update": { email_id.as_str(): patch } }),
+            )
+            .await?;
+        if let Some(error) = response["notUpdated"].get(&email_id) {
+            return Err(MailError::Source(format!(
+                "could not update {message_id}: {error}"
+            )));
+        }
+
+        Ok(())
+    }
+}
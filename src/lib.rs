@@ -0,0 +1,60 @@
+//! Indexes mail from Gmail, IMAP, Maildir, Microsoft Graph, JMAP and local archives into a
+//! search index, Typesense by default.
+//!
+//! A `SyncEngine` ties a `MailSource` to a `SearchIndex`, keeping fetched messages in a
+//! `MessageCache` and checkpoints and what is already indexed in a `StateStore`:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), mail::MailError> {
+//! use mail::{MessageCache, StateStore, SyncEngine, TypesenseIndex};
+//! use mail::maildir::MaildirSource;
+//! use std::path::Path;
+//!
+//! let mut engine = SyncEngine::builder()
+//!     .source(MaildirSource::new(Path::new("/home/me/Maildir"))?)
+//!     .index(TypesenseIndex::from_credentials()?)
+//!     .cache(MessageCache::open(Path::new("/home/me/.cache/mail"))?)
+//!     .state(StateStore::open(Path::new("/home/me/.cache/mail/state.sqlite3"))?)
+//!     .build()?;
+//!
+//! let report = engine.sync().await?;
+//! println!("indexed {} messages", report.indexed);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `MemoryIndex` stands in for Typesense and `mock_gmail::MockGmail` for Gmail, so a sync can
+//! be tested without either.
+
+pub mod cache;
+pub mod client;
+pub mod constants;
+mod dedupe;
+pub mod eml;
+pub mod engine;
+pub mod error;
+pub mod export;
+pub mod gmail;
+pub mod graph;
+pub mod imap;
+pub mod index;
+pub mod jmap;
+pub mod maildir;
+pub mod mbox;
+pub mod mime;
+pub mod mock_gmail;
+pub mod schema;
+pub mod search;
+pub mod source;
+pub mod state;
+pub mod sync;
+mod threading;
+mod utils;
+
+pub use cache::MessageCache;
+pub use engine::{NoHooks, SyncEngine, SyncEngineBuilder, SyncHooks, SyncReport};
+pub use error::MailError;
+pub use index::{MemoryIndex, SearchIndex, TypesenseIndex};
+pub use search::{Mail, Query};
+pub use source::{FetchFailure, Fetched, FetchedMessage, MailSource};
+pub use state::StateStore;
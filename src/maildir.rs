@@ -0,0 +1,510 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::error::MailError;
+use crate::search;
+use crate::source::{Changes, FetchFailure, Fetched, FetchedMessage, MailSource};
+use crate::utils;
+use async_trait::async_trait;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, channel};
+use std::time::{Duration, SystemTime};
+
+/// A message file in a Maildir. The unique part of the file name stays the same when the
+/// message moves from `new/` to `cur/`, to another folder or gets different flags.
+#[derive(Debug, Clone)]
+struct MaildirFile {
+    unique: String,
+    folder: String,
+    flags: String,
+    path: PathBuf,
+}
+
+/// Mail kept in Maildir folders, e.g. by mbsync or offlineimap. Subfolders are found anywhere
+/// below the root, both Maildir++ style (`.Sent`) and as plain directories (`Sent/`).
+pub struct MaildirSource {
+    root: PathBuf,
+    account: String,
+    /// The files as of the last checkpoint, so a sync walks the tree once.
+    files: Option<BTreeMap<String, MaildirFile>>,
+}
+
+impl MaildirSource {
+    pub fn new(root: &Path) -> Result<Self, MailError> {
+        if !root.is_dir() {
+            Err(MailError::Config(format!(
+                "'{}' is not a directory",
+                root.display()
+            )))?
+        }
+
+        let account = root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            account,
+            files: None,
+        })
+    }
+
+    fn message_id(&self, unique: &str) -> String {
+        format!("maildir:{}:{unique}", self.account)
+    }
+
+    fn snapshot(&mut self) -> Result<String, MailError> {
+        let files = self.scan()?;
+        let snapshot: BTreeMap<&String, String> = files
+            .iter()
+            .map(|(message_id, file)| {
+                let path = file.path.strip_prefix(&self.root).unwrap_or(&file.path);
+                (message_id, path.to_string_lossy().into_owned())
+            })
+            .collect();
+        let snapshot = serde_json::to_string(&snapshot)?;
+
+        self.files = Some(files);
+        Ok(snapshot)
+    }
+
+    /// The files from the last scan, scanned again when it does not know every message, e.g.
+    /// when failed messages are retried without a sync.
+    fn files(
+        &mut self,
+        message_ids: &[String],
+    ) -> Result<&BTreeMap<String, MaildirFile>, MailError> {
+        let known = self.files.as_ref().is_some_and(|files| {
+            message_ids
+                .iter()
+                .all(|message_id| files.contains_key(message_id))
+        });
+        if !known {
+            self.files = Some(self.scan()?);
+        }
+        Ok(self.files.get_or_insert_default())
+    }
+
+    /// Every message file below the root, keyed by message id.
+    fn scan(&self) -> Result<BTreeMap<String, MaildirFile>, MailError> {
+        let mut files = BTreeMap::new();
+        let mut directories = vec![self.root.to_path_buf()];
+
+        while let Some(directory) = directories.pop() {
+            let folder = folder_name(&self.root, &directory);
+
+            for subdirectory in ["new", "cur"] {
+                let path = directory.join(subdirectory);
+                if !path.is_dir() {
+                    continue;
+                }
+                let entries = fs::read_dir(&path).map_err(|error| {
+                    MailError::io(format!("could not read '{}'", path.display()), error)
+                })?;
+                for entry in entries {
+                    let path = entry?.path();
+                    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                        continue;
+                    };
+                    if file_name.starts_with('.') {
+                        continue;
+                    }
+                    let (unique, flags) = split_file_name(file_name);
+                    files.insert(
+                        self.message_id(unique),
+                        MaildirFile {
+                            unique: unique.to_string(),
+                            folder: folder.to_string(),
+                            flags: flags.to_string(),
+                            path,
+                        },
+                    );
+                }
+            }
+
+            let entries = fs::read_dir(&directory).map_err(|error| {
+                MailError::io(format!("could not read '{}'", directory.display()), error)
+            })?;
+            for entry in entries {
+                let path = entry?.path();
+                let is_maildir_part = path
+                    .file_name()
+                    .is_some_and(|name| name == "new" || name == "cur" || name == "tmp");
+                if path.is_dir() && !is_maildir_part {
+                    directories.push(path);
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    pub fn watch(&self) -> Result<MaildirWatcher, MailError> {
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+        watcher
+            .watch(&self.root, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+
+        Ok(MaildirWatcher {
+            _watcher: watcher,
+            events: receiver,
+        })
+    }
+}
+
+pub struct MaildirWatcher {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl MaildirWatcher {
+    /// Blocks until a message is added, moved or deleted below the root. Changes that come in
+    /// within a second of each other are reported together, as mbsync moves many files at once.
+    pub fn wait(&self) -> Result<(), MailError> {
+        loop {
+            let event = self
+                .events
+                .recv()
+                .map_err(|_| MailError::Source("maildir watcher stopped".to_string()))?
+                .map_err(watch_error)?;
+            if !event.paths.iter().any(|path| is_message_path(path)) {
+                continue;
+            }
+
+            while let Ok(event) = self.events.recv_timeout(Duration::from_secs(1)) {
+                event.map_err(watch_error)?;
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn watch_error(error: notify::Error) -> MailError {
+    MailError::Source(format!("could not watch maildir: {error}"))
+}
+
+/// Whether `path` is a message in `new/` or `cur/`, changes in `tmp/` are deliveries in progress.
+fn is_message_path(path: &Path) -> bool {
+    path.parent()
+        .and_then(|parent| parent.file_name())
+        .is_some_and(|name| name == "new" || name == "cur")
+}
+
+/// The folder a Maildir directory holds, `INBOX` for the root and Maildir++ dots removed.
+fn folder_name(root: &Path, directory: &Path) -> String {
+    let relative = directory.strip_prefix(root).unwrap_or(directory);
+    let name = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/");
+    let name = name.trim_start_matches('.');
+
+    if name.is_empty() {
+        "INBOX".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Splits `1700000000.M1P2.host:2,FS` into its unique part and flags. Windows tools use `;` or
+/// `!` instead of `:`, which is not allowed in file names there.
+fn split_file_name(file_name: &str) -> (&str, &str) {
+    for separator in [":2,", ";2,", "!2,"] {
+        if let Some((unique, flags)) = file_name.rsplit_once(separator) {
+            return (unique, flags);
+        }
+    }
+    (file_name, "")
+}
+
+fn folder_label(folder: &str) -> Option<&'static str> {
+    let name = folder.rsplit(['/', '.']).next().unwrap_or(folder);
+
+    match name.to_ascii_lowercase().as_str() {
+        "inbox" => Some(search::LABEL_INBOX),
+        "sent" | "sent mail" | "sent items" | "sent messages" => Some(search::LABEL_SENT),
+        "spam" | "junk" | "junk email" => Some(search::LABEL_SPAM),
+        "trash" | "bin" | "deleted items" | "deleted messages" => Some(search::LABEL_BIN),
+        "starred" | "flagged" => Some(search::LABEL_STARRED),
+        "important" => Some(search::LABEL_IMPORTANT),
+        _ => None,
+    }
+}
+
+/// Maildir flags are S (seen), F (flagged), T (trashed), R (replied), P (passed) and D (draft).
+fn flag_labels(flags: &str) -> Vec<&'static str> {
+    let mut labels = Vec::new();
+
+    if !flags.contains('S') {
+        labels.push(search::LABEL_UNREAD);
+    }
+    if flags.contains('F') {
+        labels.push(search::LABEL_STARRED);
+    }
+    if flags.contains('T') {
+        labels.push(search::LABEL_BIN);
+    }
+    if flags.contains('R') {
+        labels.push(search::LABEL_REPLIED);
+    }
+
+    labels
+}
+
+fn label_flag(label: &str) -> Result<(char, bool), MailError> {
+    match label {
+        search::LABEL_STARRED => Ok(('F', true)),
+        search::LABEL_BIN => Ok(('T', true)),
+        search::LABEL_REPLIED => Ok(('R', true)),
+        // unread is the absence of the seen flag.
+        search::LABEL_UNREAD => Ok(('S', false)),
+        _ => Err(MailError::Invalid(format!(
+            "label '{label}' can not be changed in a maildir"
+        ))),
+    }
+}
+
+/// Writes a message into the Maildir at `root` the way a delivery agent does: into `tmp/` first
+/// and then renamed into `cur/`, with the flags of its labels. Returns the path of the message.
+pub fn deliver(
+    root: &Path,
+    unique: &str,
+    raw: &[u8],
+    labels: &[String],
+) -> Result<PathBuf, MailError> {
+    for directory in ["tmp", "new", "cur"] {
+        fs::create_dir_all(root.join(directory)).map_err(|error| {
+            MailError::io(
+                format!("could not create maildir in '{}'", root.display()),
+                error,
+            )
+        })?;
+    }
+
+    let mut flags = Vec::new();
+    for label in [
+        search::LABEL_UNREAD,
+        search::LABEL_STARRED,
+        search::LABEL_BIN,
+        search::LABEL_REPLIED,
+    ] {
+        let (flag, set) = label_flag(label)?;
+        if labels.iter().any(|existing| existing == label) == set {
+            flags.push(flag);
+        }
+    }
+    flags.sort_unstable();
+
+    let temporary_path = root.join("tmp").join(unique);
+    fs::write(&temporary_path, raw).map_err(|error| {
+        MailError::io(
+            format!("could not write '{}'", temporary_path.display()),
+            error,
+        )
+    })?;
+
+    let path = root
+        .join("cur")
+        .join(format!("{unique}:2,{}", flags.iter().collect::<String>()));
+    fs::rename(&temporary_path, &path)?;
+
+    Ok(path)
+}
+
+fn modification_time(path: &Path) -> SystemTime {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+#[async_trait(?Send)]
+impl MailSource for MaildirSource {
+    fn name(&self) -> &str {
+        "maildir"
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError> {
+        utils::blocking(|| {
+            let mut files: Vec<(String, SystemTime)> = self
+                .files(&[])?
+                .iter()
+                .map(|(message_id, file)| (message_id.to_string(), modification_time(&file.path)))
+                .collect();
+
+            files.sort_by(|(_, a), (_, b)| b.cmp(a));
+            files.truncate(limit as usize);
+
+            Ok(files
+                .into_iter()
+                .map(|(message_id, _)| message_id)
+                .collect())
+        })
+    }
+
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError> {
+        utils::blocking(|| {
+            let account = self.account.to_string();
+            let files = self.files(message_ids)?;
+            let mut fetched_messages = Vec::new();
+            let mut failures = Vec::new();
+
+            for message_id in message_ids {
+                let Some(file) = files.get(message_id) else {
+                    failures.push(FetchFailure {
+                        message_id: message_id.to_string(),
+                        error: MailError::Invalid(format!(
+                            "{message_id} is no longer in the maildir"
+                        )),
+                    });
+                    continue;
+                };
+
+                let content = match fs::read(&file.path) {
+                    Ok(content) => content,
+                    // mbsync may have moved it in the meantime, the next sync picks it up.
+                    Err(error) => {
+                        failures.push(FetchFailure {
+                            message_id: message_id.to_string(),
+                            error: MailError::io(
+                                format!("could not read '{}'", file.path.display()),
+                                error,
+                            ),
+                        });
+                        continue;
+                    }
+                };
+
+                let mut labels: Vec<String> = folder_label(&file.folder)
+                    .into_iter()
+                    .chain(flag_labels(&file.flags))
+                    .map(str::to_string)
+                    .collect();
+                // a flagged message in a "Flagged" folder is starred twice otherwise.
+                labels.sort_unstable();
+                labels.dedup();
+
+                fetched_messages.push(FetchedMessage {
+                    id: message_id.to_string(),
+                    metadata: MessageMetadata {
+                        source: "maildir".to_string(),
+                        account: account.to_string(),
+                        history_id: None,
+                        thread_id: None,
+                        label_ids: vec![file.folder.to_string(), format!("flags:{}", file.flags)],
+                        labels,
+                        time: None,
+                    },
+                    format: MessageFormat::Raw,
+                    content,
+                });
+            }
+
+            Ok(Fetched {
+                messages: fetched_messages,
+                failures,
+            })
+        })
+    }
+
+    /// The checkpoint is where every message was, as a JSON map from message id to its path
+    /// relative to the root. A message changed when its path did.
+    async fn checkpoint(&mut self) -> Result<String, MailError> {
+        utils::blocking(|| self.snapshot())
+    }
+
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError> {
+        utils::blocking(|| {
+            let previous: BTreeMap<String, String> =
+                serde_json::from_str(checkpoint).map_err(|error| {
+                    MailError::Checkpoint(format!("could not parse maildir checkpoint: {error}"))
+                })?;
+            let checkpoint = self.snapshot()?;
+            let current: BTreeMap<String, String> = serde_json::from_str(&checkpoint)?;
+
+            let changed = current
+                .iter()
+                .filter(|(message_id, path)| previous.get(*message_id) != Some(*path))
+                .map(|(message_id, _)| message_id.to_string())
+                .collect();
+            let deleted = previous
+                .keys()
+                .filter(|message_id| !current.contains_key(*message_id))
+                .cloned()
+                .collect();
+
+            Ok(Changes {
+                changed,
+                deleted,
+                checkpoint,
+            })
+        })
+    }
+
+    /// Renames the message file with its new flags, which is how Maildir stores them.
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError> {
+        utils::blocking(|| {
+            let files = self.scan()?;
+            let file = files
+                .get(message_id)
+                .ok_or_else(|| MailError::Invalid(format!("{message_id} is not in the maildir")))?;
+
+            let mut flags: Vec<char> = file.flags.chars().collect();
+            let changes = add
+                .iter()
+                .map(|label| (label, true))
+                .chain(remove.iter().map(|label| (label, false)));
+            for (label, add) in changes {
+                let (flag, set) = label_flag(label)?;
+                flags.retain(|existing| *existing != flag);
+                if set == add {
+                    flags.push(flag);
+                }
+            }
+            // flags have to be in ASCII order.
+            flags.sort_unstable();
+            flags.dedup();
+
+            let separator = match file.path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.contains(";2,") => ';',
+                Some(name) if name.contains("!2,") => '!',
+                _ => ':',
+            };
+            let file_name = format!(
+                "{}{separator}2,{}",
+                file.unique,
+                flags.iter().collect::<String>()
+            );
+
+            // a message with flags belongs in cur/, even if it was still in new/.
+            let folder = file.path.parent().and_then(Path::parent).ok_or_else(|| {
+                MailError::Invalid(format!("'{}' is not in a maildir", file.path.display()))
+            })?;
+            let path = folder.join("cur").join(file_name);
+
+            fs::rename(&file.path, &path).map_err(|error| {
+                MailError::Source(format!(
+                    "could not move '{}' to '{}': {error}",
+                    file.path.display(),
+                    path.display()
+                ))
+            })?;
+            // the last scan no longer knows where it is.
+            self.files = None;
+
+            Ok(())
+        })
+    }
+}
@@ -1,61 +1,671 @@
-mod client;
-mod constants;
-mod gmail;
-mod search;
-mod utils;
-use crate::search::Searchable;
-use client::GmailClient;
+use chrono::DateTime;
+use mail::cache::{self, MessageCache};
+use mail::client::GmailClient;
+use mail::search::{self, ImportReport};
+use mail::source::{FetchFailure, FetchedMessage, MailSource};
+use mail::{
+    MailError, Query, SearchIndex, StateStore, SyncEngine, SyncHooks, TypesenseIndex, constants,
+    eml, export, gmail, graph, imap, jmap, maildir, mbox, schema, sync,
+};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use tokio::runtime::Runtime;
+use std::time::Duration;
+use tokio::task;
 
-fn main() {
-    let runtime = Runtime::new().unwrap();
-    let typesense_configuration = search::get_typesense_configuration().unwrap();
-    let mut gmail_client = GmailClient::new();
+#[tokio::main]
+async fn main() {
+    let index = match TypesenseIndex::from_credentials() {
+        Ok(index) => index,
+        Err(error) => fail("could not configure typesense", error),
+    };
+
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    match arguments.first().map(String::as_str) {
+        None => sync(&index, &[]).await,
+        Some("sync") => sync(&index, &arguments[1..]).await,
+        Some("reindex") => reindex(&index, &arguments[1..]).await,
+        Some("label") => label(&arguments[1..]).await,
+        Some("import") => import(&index, &arguments[1..]).await,
+        Some("export") => export(&index, &arguments[1..]).await,
+        Some("failures") => failures(&index, &arguments[1..]).await,
+        Some(command) => {
+            eprintln!(
+                "unknown command '{command}', expected sync, reindex, label, import, export or failures"
+            );
+            exit(1)
+        }
+    }
+}
+
+/// Reports on stderr what did not make it into the index.
+struct CliHooks;
+
+impl SyncHooks for CliHooks {
+    fn imported(&self, report: &ImportReport) {
+        for failure in &report.failures {
+            eprintln!(
+                "could not import message {} into typesense: {}",
+                failure.id, failure.error
+            );
+        }
+    }
+
+    fn import_failed(&self, error: &MailError) {
+        eprintln!("could not import messages into typesense: {error}");
+    }
 
-    let messages_list = match gmail::messages_list(&mut gmail_client, None) {
-        Ok(message_list) => message_list,
+    fn read_failed(&self, failure: &FetchFailure) {
+        eprintln!("could not read {}: {}", failure.message_id, failure.error);
+    }
+}
+
+fn open_cache() -> MessageCache {
+    match MessageCache::open(&constants::CACHE_PATH) {
+        Ok(cache) => cache,
         Err(error) => {
-            eprintln!("could not get messages list: {}", error);
+            eprintln!("could not open message cache: {error}");
             exit(1)
         }
+    }
+}
+
+fn open_state() -> StateStore {
+    match StateStore::open(&constants::STATE_PATH) {
+        Ok(state) => state,
+        Err(error) => {
+            eprintln!("could not open sync state: {error}");
+            exit(1)
+        }
+    }
+}
+
+/// Reports an error and exits with a code scripts can tell apart: 2 when the credentials need
+/// to be renewed, 3 for a broken configuration, 4 when the quota ran out and 1 for anything
+/// else.
+fn fail(context: &str, error: MailError) -> ! {
+    eprintln!("{context}: {error}");
+
+    let code = match error.root() {
+        MailError::Auth(_) => {
+            let credentials = match &error {
+                MailError::Sync { source_name, .. } if source_name == "graph" => {
+                    &constants::GRAPH_CREDENTIALS
+                }
+                MailError::Sync { source_name, .. } if source_name == "imap" => {
+                    &constants::IMAP_CREDENTIALS
+                }
+                MailError::Sync { source_name, .. } if source_name == "jmap" => {
+                    &constants::JMAP_CREDENTIALS
+                }
+                _ => &constants::GMAIL_CREDENTIALS,
+            };
+            eprintln!("authorize again and update '{}'", credentials.display());
+            2
+        }
+        MailError::Config(_) => 3,
+        MailError::Quota { .. } => {
+            eprintln!("try again later, or with fewer messages at once");
+            4
+        }
+        _ => 1,
     };
+    exit(code)
+}
 
-    let messages_ids: Vec<String> = messages_list
-        .messages
-        .iter()
-        .map(|message: &gmail::MessageListMessage| message.id.to_string())
-        .collect();
+async fn gmail_source(format: gmail::Format) -> gmail::GmailSource {
+    let source = match GmailClient::new() {
+        Ok(client) => gmail::GmailSource::new(client, format).await,
+        Err(error) => Err(error),
+    };
+    match source {
+        Ok(source) => source,
+        Err(error) => fail("could not connect to gmail", error),
+    }
+}
 
-    let messages = match gmail::get_messages_batched(&mut gmail_client, &messages_ids) {
-        Ok(messages) => messages,
+fn engine<S: MailSource>(index: &TypesenseIndex, source: S, limit: u32) -> SyncEngine<S> {
+    let engine = SyncEngine::builder()
+        .source(source)
+        .index(index.clone())
+        .cache(open_cache())
+        .state(open_state())
+        .hooks(CliHooks)
+        .limit(limit)
+        .build();
+
+    match engine {
+        Ok(engine) => engine,
+        Err(error) => fail("could not start sync", error),
+    }
+}
+
+async fn run<S: MailSource>(engine: &mut SyncEngine<S>) {
+    if let Err(error) = engine.sync().await {
+        fail("sync failed", error)
+    }
+}
+
+/// Fetches the latest messages into the cache and imports them. With `--raw` messages are
+/// fetched as RFC 822 and parsed by `mime` instead of relying on Gmail's parsed payload.
+/// `sync imap` syncs the account in `imap.json` instead, with `--idle` it keeps running and
+/// syncs again whenever the inbox changes. `sync maildir` indexes a local Maildir and
+/// `sync graph` and `sync jmap` the mailboxes in `graph.json` and `jmap.json`.
+async fn sync(index: &TypesenseIndex, arguments: &[String]) {
+    let format = match arguments {
+        [] => gmail::Format::Full,
+        [flag] if flag == "--raw" => gmail::Format::Raw,
+        [source, rest @ ..] if source == "imap" => {
+            return sync_imap(index, rest).await;
+        }
+        [source, rest @ ..] if source == "maildir" => {
+            return sync_maildir(index, rest).await;
+        }
+        [source] if source == "graph" => {
+            return sync_graph(index).await;
+        }
+        [source] if source == "jmap" => {
+            return sync_jmap(index).await;
+        }
+        _ => {
+            eprintln!(
+                "usage: sync [--raw] | sync imap [--idle] | sync maildir <path> [--watch] | sync graph | sync jmap"
+            );
+            exit(1)
+        }
+    };
+
+    let source = gmail_source(format).await;
+    run(&mut engine(index, source, constants::SYNC_MESSAGE_LIMIT)).await;
+}
+
+async fn sync_imap(index: &TypesenseIndex, arguments: &[String]) {
+    let idle = match arguments {
+        [] => false,
+        [flag] if flag == "--idle" => true,
+        _ => {
+            eprintln!("usage: sync imap [--idle]");
+            exit(1)
+        }
+    };
+
+    let source = match imap::ImapSource::connect() {
+        Ok(source) => source,
         Err(error) => {
-            eprintln!("could not get messages: {}", error);
+            eprintln!("could not connect to imap: {error}");
             exit(1)
         }
     };
 
-    let messages: Vec<search::Mail> = messages
-        .iter()
-        .filter_map(|message| {
-            message
-                .to_searchable_mail()
-                .inspect_err(|error| {
-                    eprintln!("could not convert gmail message to searchable message: {error}")
-                })
-                .ok()
-        })
-        .collect();
-
-    for message in messages {
-        match search::import_document(
-            &runtime,
-            &typesense_configuration,
-            &constants::SEARCHABLE_MAIL_COLLECTION_NAME,
-            &message,
-        ) {
-            Err(error) => eprintln!("could not import message into typesense: {error}"),
-            _ => {}
+    let mut engine = engine(index, source, constants::SYNC_MESSAGE_LIMIT);
+    run(&mut engine).await;
+
+    if idle {
+        loop {
+            let idle = task::block_in_place(|| {
+                engine
+                    .source_mut()
+                    .idle("INBOX", Duration::from_secs(constants::IMAP_IDLE_SECONDS))
+            });
+            match idle {
+                Ok(true) => run(&mut engine).await,
+                Ok(false) => {}
+                Err(error) => {
+                    eprintln!("could not idle on imap inbox: {error}");
+                    exit(1)
+                }
+            }
+        }
+    }
+
+    if let Err(error) = engine.source_mut().logout() {
+        eprintln!("could not log out of imap: {error}");
+    }
+}
+
+async fn sync_graph(index: &TypesenseIndex) {
+    let source = match graph::GraphClient::new() {
+        Ok(client) => graph::GraphSource::new(client).await,
+        Err(error) => Err(error),
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not connect to microsoft graph: {error}");
+            exit(1)
+        }
+    };
+
+    run(&mut engine(index, source, constants::SYNC_MESSAGE_LIMIT)).await;
+}
+
+async fn sync_jmap(index: &TypesenseIndex) {
+    let source = match jmap::JmapSource::connect().await {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not connect to jmap: {error}");
+            exit(1)
+        }
+    };
+
+    run(&mut engine(index, source, constants::SYNC_MESSAGE_LIMIT)).await;
+}
+
+/// Indexes every message of a Maildir, and with `--watch` keeps the index in sync as messages
+/// arrive, move, change flags or are deleted.
+async fn sync_maildir(index: &TypesenseIndex, arguments: &[String]) {
+    let (path, watch) = match arguments {
+        [path] => (Path::new(path), false),
+        [path, flag] if flag == "--watch" => (Path::new(path), true),
+        _ => {
+            eprintln!("usage: sync maildir <path> [--watch]");
+            exit(1)
+        }
+    };
+
+    let source = match maildir::MaildirSource::new(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("could not open maildir: {error}");
+            exit(1)
+        }
+    };
+
+    // local files are cheap to read, so every sync looks at all of them.
+    let mut engine = engine(index, source, u32::MAX);
+    run(&mut engine).await;
+
+    if watch {
+        let watcher = match engine.source().watch() {
+            Ok(watcher) => watcher,
+            Err(error) => {
+                eprintln!("could not watch maildir: {error}");
+                exit(1)
+            }
         };
+
+        loop {
+            if let Err(error) = task::block_in_place(|| watcher.wait()) {
+                eprintln!("could not watch maildir: {error}");
+                exit(1)
+            }
+            run(&mut engine).await;
+        }
+    }
+}
+
+/// Rebuilds the mail collection from the message cache next to the live one and swaps the alias
+/// once it is filled, without going to the mail sources.
+/// `--keep <n>` deletes all but the `n` newest versions afterwards.
+async fn reindex(index: &TypesenseIndex, arguments: &[String]) {
+    let keep = match arguments {
+        [] => None,
+        [flag, keep] if flag == "--keep" => match keep.parse() {
+            Ok(keep) => Some(keep),
+            Err(error) => {
+                eprintln!("could not parse '{keep}' as number of versions to keep: {error}");
+                exit(1)
+            }
+        },
+        _ => {
+            eprintln!("usage: reindex [--keep <versions>]");
+            exit(1)
+        }
+    };
+
+    let mut cache = open_cache();
+    let state = open_state();
+
+    let result = schema::reindex(
+        index.configuration(),
+        search::Mail::collection_schema(),
+        keep,
+        async |collection_name| {
+            let message_ids: Vec<String> = cache.entries().map(|(id, _)| id.clone()).collect();
+            let mails = sync::cached_mails(&mut cache, &state, &message_ids)?;
+            cache.save()?;
+
+            let report = search::import_documents(
+                index.configuration(),
+                collection_name,
+                &mails,
+                constants::IMPORT_BATCH_SIZE,
+            )
+            .await?;
+            state.record_import(&mails, &report)?;
+            CliHooks.imported(&report);
+
+            Ok(())
+        },
+    )
+    .await;
+
+    if let Err(error) = result {
+        fail("could not reindex mail collection", error)
+    }
+}
+
+/// `label <message id> +starred -inbox` adds and removes labels on the message in Gmail. The
+/// index picks the change up on the next sync.
+async fn label(arguments: &[String]) {
+    let Some((message_id, changes)) = arguments.split_first() else {
+        eprintln!("usage: label <message id> [+label] [-label]");
+        exit(1)
+    };
+
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    for change in changes {
+        if let Some(label) = change.strip_prefix('+') {
+            add.push(label.to_string());
+        } else if let Some(label) = change.strip_prefix('-') {
+            remove.push(label.to_string());
+        } else {
+            eprintln!("expected +label or -label, got '{change}'");
+            exit(1)
+        }
+    }
+
+    let mut source = gmail_source(gmail::Format::Full).await;
+    if let Err(error) = source.modify_labels(message_id, &add, &remove).await {
+        eprintln!("could not change labels of {message_id}: {error}");
+        exit(1)
+    }
+}
+
+/// `import mbox <path> [--account <name>]` caches and indexes every message of an mbox file, e.g.
+/// a Google Takeout export. The account defaults to the file name.
+/// `import eml [--account <name>] <paths>...` does the same for `.eml` and `.emlx` files and
+/// directories of them.
+async fn import(index: &TypesenseIndex, arguments: &[String]) {
+    match arguments.first().map(String::as_str) {
+        Some("mbox") => import_mbox(index, &arguments[1..]).await,
+        Some("eml") => import_eml(index, &arguments[1..]).await,
+        _ => {
+            eprintln!(
+                "usage: import mbox <path> [--account <name>] | import eml [--account <name>] <paths>..."
+            );
+            exit(1)
+        }
+    }
+}
+
+async fn import_mbox(index: &TypesenseIndex, arguments: &[String]) {
+    let (path, account) = match arguments {
+        [path] => (Path::new(path), None),
+        [path, flag, account] if flag == "--account" => {
+            (Path::new(path), Some(account.to_string()))
+        }
+        _ => {
+            eprintln!("usage: import mbox <path> [--account <name>]");
+            exit(1)
+        }
+    };
+    let account = account.unwrap_or_else(|| {
+        path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+
+    let reader = match mbox::MboxReader::open(path) {
+        Ok(reader) => reader,
+        Err(error) => fail("could not read mbox", error),
+    };
+
+    let messages = reader.enumerate().map(|(index, message)| {
+        message
+            .map(|raw| mbox::fetched_message(raw, &account))
+            .map_err(|error| FetchFailure {
+                message_id: format!("{} message {index}", path.display()),
+                error,
+            })
+    });
+    let imported = import_messages(index, messages).await;
+
+    println!("imported {imported} messages from '{}'", path.display());
+}
+
+async fn import_eml(index: &TypesenseIndex, arguments: &[String]) {
+    let (account, paths) = match arguments {
+        [flag, account, paths @ ..] if flag == "--account" => (account.to_string(), paths),
+        paths => (String::new(), paths),
+    };
+    if paths.is_empty() {
+        eprintln!("usage: import eml [--account <name>] <paths>...");
+        exit(1)
+    }
+
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let files = match eml::files(&paths) {
+        Ok(files) => files,
+        Err(error) => fail("could not find eml files", error),
+    };
+
+    let messages = files
+        .iter()
+        .map(|path| eml::fetched_message(path, &account));
+    let imported = import_messages(index, messages).await;
+
+    println!("imported {imported} of {} files", files.len());
+}
+
+/// `export <mbox|maildir|eml> <directory> [--filter <filter>] [query]` writes every message
+/// matching the search into `directory`, with a `manifest.json` of ids, hashes and labels.
+/// Messages come from the cache and are fetched from Gmail as raw RFC 822 if only Gmail's
+/// parsed JSON is cached.
+async fn export(index: &TypesenseIndex, arguments: &[String]) {
+    let usage = "usage: export <mbox|maildir|eml> <directory> [--filter <filter>] [query]";
+    let (Some(format), Some(directory)) = (arguments.first(), arguments.get(1)) else {
+        eprintln!("{usage}");
+        exit(1)
+    };
+    let Some(format) = export::ExportFormat::from_name(format) else {
+        eprintln!("{usage}");
+        exit(1)
+    };
+
+    let mut filter_by: Option<String> = None;
+    let mut words = Vec::new();
+    let mut rest = arguments[2..].iter();
+    while let Some(argument) = rest.next() {
+        if argument == "--filter" {
+            let Some(filter) = rest.next() else {
+                eprintln!("{usage}");
+                exit(1)
+            };
+            filter_by = Some(filter.to_string());
+        } else {
+            words.push(argument.to_string());
+        }
+    }
+    let mut query = if words.is_empty() {
+        Query::all()
+    } else {
+        Query::new(&words.join(" "))
+    };
+    if let Some(filter_by) = &filter_by {
+        query = query.filter_by(filter_by);
+    }
+
+    let message_ids = match index.search(&query).await {
+        Ok(message_ids) => message_ids,
+        Err(error) => fail("could not search messages", error),
+    };
+
+    let mut exporter = match export::Exporter::create(Path::new(directory), format, &query) {
+        Ok(exporter) => exporter,
+        Err(error) => fail("could not start export", error),
+    };
+
+    let cache = open_cache();
+    let mut from_gmail = Vec::new();
+
+    for message_id in &message_ids {
+        match cache.entry(message_id) {
+            Some(entry) if entry.format == cache::MessageFormat::Raw => {
+                let result = cache.get(message_id).and_then(|raw| {
+                    exporter.add(message_id, &raw.unwrap_or_default(), &entry.metadata)
+                });
+                if let Err(error) = result {
+                    eprintln!("could not export {message_id}: {error}");
+                    exporter.add_missing(message_id, &error.to_string());
+                }
+            }
+            // only gmail ids have no `source:` prefix.
+            None if message_id.contains(':') => {
+                eprintln!("could not export {message_id}: it is not in the message cache");
+                exporter.add_missing(message_id, "not in the message cache");
+            }
+            _ => from_gmail.push(message_id.to_string()),
+        }
+    }
+
+    if !from_gmail.is_empty() {
+        let mut source = gmail_source(gmail::Format::Raw).await;
+        for batch in from_gmail.chunks(constants::GMAIL_BATCH_SIZE) {
+            let fetched = match source.fetch(batch).await {
+                Ok(fetched) => fetched,
+                Err(error) => {
+                    eprintln!("could not fetch messages from gmail: {error}");
+                    for message_id in batch {
+                        exporter.add_missing(message_id, &format!("could not fetch: {error}"));
+                    }
+                    continue;
+                }
+            };
+            for failure in &fetched.failures {
+                eprintln!("could not export {}: {}", failure.message_id, failure.error);
+                exporter.add_missing(&failure.message_id, &failure.error.to_string());
+            }
+            for message_id in batch {
+                let returned = fetched
+                    .messages
+                    .iter()
+                    .any(|message| &message.id == message_id);
+                let failed = fetched
+                    .failures
+                    .iter()
+                    .any(|failure| &failure.message_id == message_id);
+                if !returned && !failed {
+                    eprintln!("could not export {message_id}: gmail did not return it");
+                    exporter.add_missing(message_id, "not returned by gmail");
+                }
+            }
+            for message in fetched.messages {
+                if let Err(error) = exporter.add(&message.id, &message.content, &message.metadata) {
+                    eprintln!("could not export {}: {error}", message.id);
+                    exporter.add_missing(&message.id, &error.to_string());
+                }
+            }
+        }
+    }
+
+    let missing = exporter.missing().len();
+    match exporter.finish() {
+        Ok(exported) => println!(
+            "exported {exported} of {} messages to '{directory}'",
+            message_ids.len()
+        ),
+        Err(error) => {
+            eprintln!("could not finish export: {error}");
+            exit(1)
+        }
+    }
+    // an incomplete export must not look like a successful one, e.g. for a legal hold.
+    if missing > 0 {
+        eprintln!("{missing} messages are missing from the export, see its manifest");
+        exit(1)
+    }
+}
+
+/// `failures list` shows the messages that could not be converted or indexed, `failures retry`
+/// converts and imports them again from the message cache, e.g. after a parser fix.
+async fn failures(index: &TypesenseIndex, arguments: &[String]) {
+    let state = open_state();
+    let failures = match state.failures() {
+        Ok(failures) => failures,
+        Err(error) => {
+            eprintln!("could not read failures: {error}");
+            exit(1)
+        }
+    };
+
+    match arguments {
+        [command] if command == "list" => {
+            for failure in &failures {
+                let failed_at = DateTime::from_timestamp(failure.failed_at, 0)
+                    .map(|failed_at| failed_at.to_rfc3339())
+                    .unwrap_or_default();
+                println!(
+                    "{failed_at}\t{}\t{}\t{}",
+                    failure.stage, failure.message_id, failure.error
+                );
+            }
+            println!("{} failures", failures.len());
+        }
+        [command] if command == "retry" => {
+            let mut engine = local_engine(index, state, "could not start retry");
+
+            let mut message_ids = Vec::new();
+            for failure in failures {
+                if !engine.cache().contains(&failure.message_id) {
+                    eprintln!(
+                        "could not retry {}: it is not in the message cache",
+                        failure.message_id
+                    );
+                } else if !message_ids.contains(&failure.message_id) {
+                    message_ids.push(failure.message_id);
+                }
+            }
+
+            match engine.retry(&message_ids).await {
+                Ok(report) => println!(
+                    "indexed {} of {} messages",
+                    report.indexed,
+                    message_ids.len()
+                ),
+                Err(error) => fail("could not retry failures", error),
+            }
+
+            match engine.state().failures() {
+                Ok(failures) if !failures.is_empty() => {
+                    println!("{} failures remain", failures.len())
+                }
+                Ok(_) => {}
+                Err(error) => eprintln!("could not read failures: {error}"),
+            }
+        }
+        _ => {
+            eprintln!("usage: failures list | failures retry");
+            exit(1)
+        }
+    }
+}
+
+/// An engine without a source, for importing messages that are not synced from one.
+fn local_engine(index: &TypesenseIndex, state: StateStore, context: &str) -> SyncEngine<()> {
+    let engine = SyncEngine::builder()
+        .index(index.clone())
+        .cache(open_cache())
+        .state(state)
+        .hooks(CliHooks)
+        .build();
+
+    match engine {
+        Ok(engine) => engine,
+        Err(error) => fail(context, error),
+    }
+}
+
+/// Caches and indexes messages from a file based import. Returns how many messages were
+/// indexed.
+async fn import_messages(
+    index: &TypesenseIndex,
+    messages: impl Iterator<Item = Result<FetchedMessage, FetchFailure>>,
+) -> usize {
+    let mut engine = local_engine(index, open_state(), "could not start import");
+
+    match engine.import(messages).await {
+        Ok(report) => report.indexed,
+        Err(error) => fail("could not import messages", error),
     }
 }
@@ -0,0 +1,265 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::error::MailError;
+use crate::mime;
+use crate::search;
+use crate::source::FetchedMessage;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Reads the messages of an mbox file one at a time, so archives larger than memory can be
+/// imported. Messages are separated by `From ` lines; mboxrd escaping (`>From `, `>>From `, ...)
+/// is undone, and a `Content-Length` header (mboxcl2) is trusted when it ends on a separator.
+pub struct MboxReader<R: BufRead> {
+    reader: R,
+    /// A line that was read ahead and belongs to the next message.
+    pending: Option<Vec<u8>>,
+}
+
+impl MboxReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, MailError> {
+        let file = File::open(path).map_err(|error| {
+            MailError::io(format!("could not open '{}'", path.display()), error)
+        })?;
+        Ok(Self::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            pending: None,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<Vec<u8>>, MailError> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    fn next_message(&mut self) -> Result<Option<Vec<u8>>, MailError> {
+        // skip anything before the first separator, usually nothing or blank lines.
+        loop {
+            match self.read_line()? {
+                None => return Ok(None),
+                Some(line) if is_separator(&line) => break,
+                Some(_) => {}
+            }
+        }
+
+        let mut message = Vec::new();
+        let mut content_length = None;
+
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(Some(message));
+            };
+            if is_separator(&line) {
+                self.pending = Some(line);
+                return Ok(Some(trim_separator_newline(message)));
+            }
+
+            let header_end = line == b"\n" || line == b"\r\n";
+            if let Some(length) = header_value(&line, "content-length") {
+                content_length = length.trim().parse::<usize>().ok();
+            }
+            message.extend_from_slice(unescape(&line));
+
+            if header_end {
+                break;
+            }
+        }
+
+        if let Some(length) = content_length {
+            let mut body = vec![0; length];
+            self.reader.read_exact(&mut body)?;
+
+            // only trust the length if the next message or the end of the file follows it.
+            let next = self.read_line()?;
+            let trusted = match &next {
+                None => true,
+                Some(line) => {
+                    is_separator(line) || line.iter().all(|byte| byte.is_ascii_whitespace())
+                }
+            };
+
+            if trusted {
+                message.extend_from_slice(&body);
+                if let Some(line) = next.filter(|line| is_separator(line)) {
+                    self.pending = Some(line);
+                }
+                return Ok(Some(message));
+            }
+
+            // an mboxrd file with a wrong length, read the body line by line after all.
+            for line in body.split_inclusive(|&byte| byte == b'\n') {
+                message.extend_from_slice(unescape(line));
+            }
+            self.pending = next;
+        }
+
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(Some(trim_separator_newline(message)));
+            };
+            if is_separator(&line) {
+                self.pending = Some(line);
+                return Ok(Some(trim_separator_newline(message)));
+            }
+            message.extend_from_slice(unescape(&line));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = Result<Vec<u8>, MailError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+fn is_separator(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+/// Removes the blank line that ends a message, before the next separator or the end of the file.
+fn trim_separator_newline(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+    message
+}
+
+/// Strips one `>` from lines like `>>From `, which mboxrd escapes by adding one.
+fn unescape(line: &[u8]) -> &[u8] {
+    let quotes = line.iter().take_while(|&&byte| byte == b'>').count();
+    if quotes > 0 && line[quotes..].starts_with(b"From ") {
+        &line[1..]
+    } else {
+        line
+    }
+}
+
+/// Appends a message in mboxrd format: a `From ` separator, lines like `From ` or `>From `
+/// escaped with one more `>` and a blank line after the message.
+pub fn write_message(
+    writer: &mut impl Write,
+    raw: &[u8],
+    time: DateTime<Utc>,
+) -> Result<(), MailError> {
+    writeln!(
+        writer,
+        "From MAILER-DAEMON {}",
+        time.format("%a %b %e %H:%M:%S %Y")
+    )?;
+
+    for line in raw.split_inclusive(|&byte| byte == b'\n') {
+        let quotes = line.iter().take_while(|&&byte| byte == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            writer.write_all(b">")?;
+        }
+        writer.write_all(line)?;
+    }
+
+    if !raw.ends_with(b"\n") {
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+fn header_value<'a>(line: &'a [u8], name: &str) -> Option<&'a str> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (key, value) = line.split_once(':')?;
+    key.eq_ignore_ascii_case(name).then_some(value)
+}
+
+/// Translates the labels of a Takeout `X-Gmail-Labels` header, e.g. `Inbox,Important,"a,b"`.
+fn takeout_labels(header: &str) -> Vec<String> {
+    let mut labels = Vec::new();
+    let mut label = String::new();
+    let mut quoted = false;
+
+    for character in header.chars().chain([',']) {
+        match character {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                labels.push(label.trim().to_string());
+                label.clear();
+            }
+            _ => label.push(character),
+        }
+    }
+
+    labels.retain(|label| !label.is_empty());
+    labels
+}
+
+fn convert_takeout_labels(labels: &[String]) -> Vec<String> {
+    labels
+        .iter()
+        .filter_map(|label| match label.to_ascii_lowercase().as_str() {
+            "inbox" => Some(search::LABEL_INBOX),
+            "starred" => Some(search::LABEL_STARRED),
+            "important" => Some(search::LABEL_IMPORTANT),
+            "sent" => Some(search::LABEL_SENT),
+            "scheduled" => Some(search::LABEL_SCHEDULED),
+            "spam" => Some(search::LABEL_SPAM),
+            "trash" | "bin" => Some(search::LABEL_BIN),
+            "unread" => Some(search::LABEL_UNREAD),
+            _ => None,
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Turns a message from an mbox file into what the message cache stores. The id is the hash of
+/// the message, so importing the same archive twice does not duplicate anything. Takeout's
+/// `X-GM-THRID` is the decimal form of the Gmail thread id, which the API returns as hex. Only
+/// the headers are read here, a body that does not parse fails when the message is converted.
+pub fn fetched_message(raw: Vec<u8>, account: &str) -> FetchedMessage {
+    let hash = format!("{:x}", Sha256::digest(&raw));
+
+    let headers = mime::headers(&raw);
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    };
+    let label_ids = header("X-Gmail-Labels")
+        .map(takeout_labels)
+        .unwrap_or_default();
+    let thread_id = header("X-GM-THRID")
+        .and_then(|thread_id| thread_id.trim().parse::<u64>().ok())
+        .map(|thread_id| format!("{thread_id:x}"));
+
+    FetchedMessage {
+        id: format!("mbox:{}", &hash[..32]),
+        metadata: MessageMetadata {
+            source: "mbox".to_string(),
+            account: account.to_string(),
+            history_id: None,
+            thread_id,
+            labels: convert_takeout_labels(&label_ids),
+            label_ids,
+            time: None,
+        },
+        format: MessageFormat::Raw,
+        content: raw,
+    }
+}
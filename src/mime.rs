@@ -0,0 +1,770 @@
+use crate::error::MailError;
+use crate::search;
+use crate::search::Searchable;
+use base64::engine::general_purpose;
+use base64::{DecodeError, Engine};
+use chrono::DateTime;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+use encoding_rs::Encoding;
+
+/// A parsed message together with what its source knows about it, so every source that can
+/// hand over RFC 822 bytes shares one conversion into `search::Mail`.
+#[derive(Debug, Clone)]
+pub struct ParsedMail {
+    pub id: String,
+    pub source: String,
+    pub account: String,
+    pub thread_id: String,
+    pub labels: Vec<String>,
+    /// When the source received the message, takes precedence over the Date header.
+    pub time: Option<i64>,
+    pub message: Part,
+}
+
+/// A parsed RFC 5322 message or one of its MIME parts. Bodies are stored with their
+/// Content-Transfer-Encoding undone, but still in their own charset.
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub headers: Vec<Header>,
+    pub content_type: ContentType,
+    pub body: Vec<u8>,
+    pub parts: Vec<Part>,
+    /// Why the body could not be decoded, it is left empty then.
+    pub decode_error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentType {
+    pub mime_type: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        Self {
+            mime_type: "text/plain".to_string(),
+            parameters: vec![("charset".to_string(), "us-ascii".to_string())],
+        }
+    }
+}
+
+impl Part {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.content_type.mime_type.starts_with("multipart/")
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        let disposition = self.header("Content-Disposition").unwrap_or_default();
+        disposition
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("attachment")
+    }
+
+    /// The body decoded from its charset, unknown charsets are read as UTF-8.
+    pub fn text(&self) -> String {
+        let charset = self.content_type.parameter("charset").unwrap_or("utf-8");
+        decode_charset(charset, &self.body)
+    }
+
+    /// Depth-first search for the first inline part of `mime_type`.
+    pub fn find(&self, mime_type: &str) -> Option<&Part> {
+        if self.is_attachment() {
+            return None;
+        }
+        if self.content_type.mime_type == mime_type {
+            return Some(self);
+        }
+        self.parts.iter().find_map(|part| part.find(mime_type))
+    }
+
+    /// Every part whose body could not be decoded, depth-first.
+    fn decode_errors(&self, warnings: &mut Vec<String>) {
+        if let Some(error) = &self.decode_error {
+            warnings.push(format!(
+                "could not decode {} part: {error}",
+                self.content_type.mime_type
+            ));
+        }
+        for part in &self.parts {
+            part.decode_errors(warnings);
+        }
+    }
+}
+
+impl Searchable for ParsedMail {
+    fn to_searchable_mail(&self) -> Result<search::Conversion, MailError> {
+        let missing = |field: &str| MailError::parse(&self.id, format!("missing {field}"));
+        let mut warnings = Vec::new();
+
+        let from = self.message.header("From").ok_or_else(|| missing("from"))?;
+        let subject = self.message.header("Subject").map(str::to_string);
+        if subject.is_none() {
+            warnings.push("missing subject".to_string());
+        }
+        let to = self.message.header("To").map(str::to_string);
+        if to.is_none() {
+            warnings.push("missing to".to_string());
+        }
+        let time = message_time(self.time, self.message.header("Date"), &mut warnings);
+        self.message.decode_errors(&mut warnings);
+
+        // either body stands in for the other one when it is missing.
+        let html = self.message.find("text/html").map(Part::text);
+        let plain = self.message.find("text/plain").map(Part::text);
+        let (raw_body, searchable_body) = match (html, plain) {
+            (Some(html), Some(plain)) => (html, plain),
+            (Some(html), None) => {
+                let text = html_to_text(&html);
+                (html, text)
+            }
+            (None, Some(plain)) => (plain.clone(), plain),
+            (None, None) => return Err(missing("body")),
+        };
+
+        let mail = search::Mail {
+            id: self.id.to_string(),
+            thread_id: self.thread_id.to_string(),
+            subject,
+            time,
+            labels: self.labels.clone(),
+            raw_body,
+            searchable_body,
+            from: from.to_string(),
+            to,
+            source: self.source.to_string(),
+            account: self.account.to_string(),
+            accounts: Vec::new(),
+        };
+
+        Ok(search::Conversion { mail, warnings })
+    }
+}
+
+// elements whose content is not text.
+const HIDDEN_ELEMENTS: [&str; 4] = ["head", "script", "style", "title"];
+
+// elements that start a new line.
+const BLOCK_ELEMENTS: [&str; 16] = [
+    "br", "p", "div", "li", "tr", "td", "th", "table", "ul", "ol", "h1", "h2", "h3", "h4", "h5",
+    "h6",
+];
+
+/// The text of an HTML body, for mail without a text/plain alternative: tags are dropped,
+/// block elements become line breaks and entities are decoded.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    let mut hidden: Option<String> = None;
+
+    while let Some(start) = rest.find('<') {
+        if hidden.is_none() {
+            text.push_str(&decode_entities(&rest[..start]));
+        }
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|character: char| character.is_whitespace() || character == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match &hidden {
+            Some(element) if closing && *element == name => hidden = None,
+            Some(_) => {}
+            None if !closing && HIDDEN_ELEMENTS.contains(&name.as_str()) => hidden = Some(name),
+            None if BLOCK_ELEMENTS.contains(&name.as_str()) => text.push('\n'),
+            None => {}
+        }
+    }
+    if hidden.is_none() {
+        text.push_str(&decode_entities(rest));
+    }
+
+    // collapse the whitespace HTML ignores, keeping at most one blank line.
+    let mut output = String::new();
+    let mut blank = true;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<&str>>().join(" ");
+        if line.is_empty() {
+            if !blank {
+                output.push('\n');
+            }
+            blank = true;
+            continue;
+        }
+        output.push_str(&line);
+        output.push('\n');
+        blank = false;
+    }
+
+    output.trim_end().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| {
+                let entity = &rest[1..end + 1];
+                let character = match entity {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some(' '),
+                    _ => entity
+                        .strip_prefix("#x")
+                        .or_else(|| entity.strip_prefix("#X"))
+                        .map(|hex| u32::from_str_radix(hex, 16))
+                        .or_else(|| entity.strip_prefix('#').map(str::parse))
+                        .and_then(Result::ok)
+                        .and_then(char::from_u32),
+                };
+                character.map(|character| (character, end + 2))
+            });
+        match decoded {
+            Some((character, length)) => {
+                output.push(character);
+                rest = &rest[length..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// When a message was received: what its source says, e.g. Gmail's internalDate, and
+/// otherwise its Date header. What is wrong with the Date header is added to `warnings`.
+pub fn message_time(
+    received: Option<i64>,
+    date: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Option<i64> {
+    if received.is_some() {
+        return received;
+    }
+
+    match date {
+        Some(date) => {
+            let time = parse_date(date).map(|time| time.timestamp());
+            if time.is_none() {
+                warnings.push(format!("could not parse date '{date}'"));
+            }
+            time
+        }
+        None => {
+            warnings.push("missing date".to_string());
+            None
+        }
+    }
+}
+
+// zones that show up by name instead of as an offset.
+const ZONE_OFFSETS: [(&str, &str); 14] = [
+    ("UT", "+0000"),
+    ("UTC", "+0000"),
+    ("GMT", "+0000"),
+    ("Z", "+0000"),
+    ("BST", "+0100"),
+    ("CET", "+0100"),
+    ("CEST", "+0200"),
+    ("EST", "-0500"),
+    ("EDT", "-0400"),
+    ("CST", "-0600"),
+    ("CDT", "-0500"),
+    ("MST", "-0700"),
+    ("PST", "-0800"),
+    ("PDT", "-0700"),
+];
+
+const DATE_FORMATS: [&str; 6] = [
+    "%d %b %Y %H:%M:%S %z",
+    "%d %B %Y %H:%M:%S %z",
+    "%d %b %Y %H:%M %z",
+    "%d %b %y %H:%M:%S %z",
+    "%Y-%m-%d %H:%M:%S %z",
+    "%a %b %e %H:%M:%S %Y %z",
+];
+
+// times without a zone are taken as UTC.
+const NAIVE_DATE_FORMATS: [&str; 5] = [
+    "%d %b %Y %H:%M:%S",
+    "%d %b %Y %H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%a %b %e %H:%M:%S %Y",
+];
+
+/// Parses a Date header. Besides RFC 2822 this takes what mail clients send in practice:
+/// comments like `(UTC)`, zones by name or missing, a missing or malformed day of the week,
+/// missing seconds, ISO 8601 and asctime.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let mut without_comments = String::new();
+    let mut depth = 0;
+    for character in value.chars() {
+        match character {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => without_comments.push(character),
+            _ => {}
+        }
+    }
+    let value = without_comments
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Ok(time) = DateTime::parse_from_rfc2822(&value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(&value) {
+        return Some(time.with_timezone(&Utc));
+    }
+
+    let mut words: Vec<&str> = value.split(' ').collect();
+    // the day of the week says nothing the date does not.
+    if let Some(first) = words.first()
+        && first.trim_end_matches(',').chars().all(char::is_alphabetic)
+        && words
+            .get(1)
+            .is_some_and(|second| second.starts_with(|c: char| c.is_ascii_digit()))
+    {
+        words.remove(0);
+    }
+    // an offset followed by the name of the zone, e.g. `-0800 PST`.
+    if words.len() > 1
+        && words[words.len() - 1].chars().all(char::is_alphabetic)
+        && words[words.len() - 2].starts_with(['+', '-'])
+    {
+        words.pop();
+    }
+    if let Some(last) = words.last_mut()
+        && let Some((_, offset)) = ZONE_OFFSETS
+            .iter()
+            .find(|(zone, _)| zone.eq_ignore_ascii_case(last))
+    {
+        *last = offset;
+    }
+    let value = words.join(" ");
+
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| DateTime::parse_from_str(&value, format).ok())
+        .map(|time| time.with_timezone(&Utc))
+        .or_else(|| {
+            NAIVE_DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(&value, format).ok())
+                .map(|time| time.and_utc())
+        })
+}
+
+pub fn parse(raw: &[u8]) -> Result<Part, MailError> {
+    let (raw_headers, raw_body) = split_headers(raw);
+
+    let headers = parse_headers(raw_headers);
+    if headers.is_empty() && !raw_headers.is_empty() {
+        Err(MailError::parse_response(
+            "could not parse any message headers",
+        ))?
+    }
+
+    let content_type = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Type"))
+        .map(|header| parse_content_type(&header.value))
+        .unwrap_or_default();
+
+    let transfer_encoding = headers
+        .iter()
+        .find(|header| {
+            header
+                .name
+                .eq_ignore_ascii_case("Content-Transfer-Encoding")
+        })
+        .map(|header| header.value.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let mut part = Part {
+        headers,
+        content_type,
+        body: Vec::new(),
+        parts: Vec::new(),
+        decode_error: None,
+    };
+
+    if part.is_multipart() {
+        let boundary = part
+            .content_type
+            .parameter("boundary")
+            .ok_or_else(|| {
+                MailError::parse_response(format!(
+                    "missing boundary for {} part",
+                    part.content_type.mime_type
+                ))
+            })?
+            .to_string();
+
+        for raw_part in split_multipart(raw_body, &boundary) {
+            part.parts.push(parse(raw_part)?);
+        }
+    } else {
+        // a broken attachment should not keep the rest of the message out of the index.
+        match decode_transfer_encoding(&transfer_encoding, raw_body) {
+            Ok(body) => part.body = body,
+            Err(error) => part.decode_error = Some(error.to_string()),
+        }
+    }
+
+    Ok(part)
+}
+
+/// Only the headers of a message, for when its body is not needed.
+pub fn headers(raw: &[u8]) -> Vec<Header> {
+    parse_headers(split_headers(raw).0)
+}
+
+fn split_headers(raw: &[u8]) -> (&[u8], &[u8]) {
+    // a part without headers starts with its blank line.
+    if let Some(body) = raw.strip_prefix(b"\r\n") {
+        return (&[], body);
+    }
+    if let Some(body) = raw.strip_prefix(b"\n") {
+        return (&[], body);
+    }
+
+    for index in 0..raw.len() {
+        if raw[index..].starts_with(b"\r\n\r\n") {
+            return (&raw[..index], &raw[index + 4..]);
+        }
+        if raw[index..].starts_with(b"\n\n") {
+            return (&raw[..index], &raw[index + 2..]);
+        }
+    }
+
+    (raw, &[])
+}
+
+fn parse_headers(raw_headers: &[u8]) -> Vec<Header> {
+    let raw_headers = String::from_utf8_lossy(raw_headers);
+    let mut headers: Vec<Header> = Vec::new();
+
+    for line in raw_headers.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // folded continuation of the previous header.
+            if let Some(header) = headers.last_mut() {
+                header.value.push(' ');
+                header.value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push(Header {
+                name: name.trim().to_string(),
+                value: value.trim().to_string(),
+            });
+        }
+    }
+
+    for header in &mut headers {
+        header.value = decode_encoded_words(&header.value);
+    }
+
+    headers
+}
+
+pub fn parse_content_type(value: &str) -> ContentType {
+    let mut sections = split_parameters(value).into_iter();
+    let mime_type = sections
+        .next()
+        .map(|mime_type| mime_type.trim().to_ascii_lowercase())
+        .filter(|mime_type| !mime_type.is_empty())
+        .unwrap_or_else(|| "text/plain".to_string());
+
+    let mut parameters: Vec<(String, String)> = Vec::new();
+    for section in sections {
+        let Some((key, value)) = section.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().trim_matches('"').to_string();
+
+        // RFC 2231 extended values look like `filename*=utf-8''na%C3%AFve.txt`.
+        if let Some(key) = key.strip_suffix('*') {
+            let value = match value.splitn(3, '\'').collect::<Vec<&str>>().as_slice() {
+                [charset, _language, encoded] => decode_charset(charset, &percent_decode(encoded)),
+                _ => value,
+            };
+            parameters.push((key.to_string(), value));
+        } else {
+            parameters.push((key, value));
+        }
+    }
+
+    ContentType {
+        mime_type,
+        parameters,
+    }
+}
+
+fn split_parameters(value: &str) -> Vec<String> {
+    let mut sections = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for character in value.chars() {
+        match character {
+            '"' => {
+                quoted = !quoted;
+                current.push(character);
+            }
+            ';' if !quoted => sections.push(std::mem::take(&mut current)),
+            _ => current.push(character),
+        }
+    }
+    sections.push(current);
+
+    sections
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut line_start = 0;
+
+    while line_start < body.len() {
+        let line_end = body[line_start..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map(|position| line_start + position + 1)
+            .unwrap_or(body.len());
+        let line = trim_line_ending(&body[line_start..line_end]);
+
+        if line.starts_with(delimiter.as_bytes()) {
+            let rest = &line[delimiter.len()..];
+            if let Some(start) = start {
+                // the line break before a delimiter belongs to the delimiter.
+                let end = strip_trailing_line_ending(&body[start..line_start]);
+                parts.push(end);
+            }
+            if rest.starts_with(b"--") {
+                return parts;
+            }
+            start = Some(line_end);
+        }
+
+        line_start = line_end;
+    }
+
+    // a missing closing delimiter still ends the last part.
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+
+    parts
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn strip_trailing_line_ending(part: &[u8]) -> &[u8] {
+    if let Some(part) = part.strip_suffix(b"\r\n") {
+        return part;
+    }
+    part.strip_suffix(b"\n").unwrap_or(part)
+}
+
+fn decode_transfer_encoding(encoding: &str, body: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    match encoding {
+        "base64" => {
+            let cleaned: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|byte| !byte.is_ascii_whitespace())
+                .collect();
+            let unpadded: &[u8] = cleaned
+                .strip_suffix(b"==")
+                .or_else(|| cleaned.strip_suffix(b"="))
+                .unwrap_or(&cleaned);
+            general_purpose::STANDARD
+                .decode(&cleaned)
+                .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(unpadded))
+        }
+        "quoted-printable" => Ok(decode_quoted_printable(body, false)),
+        _ => Ok(body.to_vec()),
+    }
+}
+
+/// In encoded words (`header` set) an underscore stands for a space.
+fn decode_quoted_printable(input: &[u8], header: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut index = 0;
+
+    while index < input.len() {
+        match input[index] {
+            b'=' if input[index + 1..].starts_with(b"\r\n") => index += 3,
+            b'=' if input[index + 1..].starts_with(b"\n") => index += 2,
+            b'=' if index + 2 < input.len() => {
+                match std::str::from_utf8(&input[index + 1..index + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        output.push(byte);
+                        index += 3;
+                    }
+                    None => {
+                        output.push(b'=');
+                        index += 1;
+                    }
+                }
+            }
+            b'_' if header => {
+                output.push(b' ');
+                index += 1;
+            }
+            byte => {
+                output.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    output
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && index + 2 < bytes.len()
+            && let Some(byte) = std::str::from_utf8(&bytes[index + 1..index + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            output.push(byte);
+            index += 3;
+            continue;
+        }
+        output.push(bytes[index]);
+        index += 1;
+    }
+
+    output
+}
+
+pub fn decode_charset(charset: &str, bytes: &[u8]) -> String {
+    let charset = charset.trim().trim_matches('"');
+
+    // encoding_rs follows the WHATWG labels, which map us-ascii to windows-1252 as well.
+    match Encoding::for_label(charset.as_bytes()) {
+        Some(encoding) => encoding.decode_without_bom_handling(bytes).0.into_owned(),
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Decodes RFC 2047 encoded words like `=?utf-8?B?SGFsbG8=?=`. Whitespace between two encoded
+/// words is dropped, as the standard requires.
+pub fn decode_encoded_words(value: &str) -> String {
+    let mut output = String::new();
+    let mut rest = value;
+    let mut previous_was_encoded = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+
+        match decode_encoded_word(candidate) {
+            Some((decoded, length)) => {
+                if !(previous_was_encoded && before.trim().is_empty()) {
+                    output.push_str(before);
+                }
+                output.push_str(&decoded);
+                rest = &candidate[length..];
+                previous_was_encoded = true;
+            }
+            None => {
+                output.push_str(before);
+                output.push_str("=?");
+                rest = &candidate[2..];
+                previous_was_encoded = false;
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+fn decode_encoded_word(candidate: &str) -> Option<(String, usize)> {
+    let inner = candidate.strip_prefix("=?")?;
+    let (charset, inner) = inner.split_once('?')?;
+    let (encoding, inner) = inner.split_once('?')?;
+    let end = inner.find("?=")?;
+    let text = &inner[..end];
+
+    // `charset*language` is allowed by RFC 2231.
+    let charset = charset.split('*').next().unwrap_or(charset);
+
+    let bytes = match encoding {
+        "B" | "b" => general_purpose::STANDARD
+            .decode(text)
+            .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(text.trim_end_matches('=')))
+            .ok()?,
+        "Q" | "q" => decode_quoted_printable(text.as_bytes(), true),
+        _ => return None,
+    };
+
+    let length = candidate.len() - inner[end + 2..].len();
+
+    Some((decode_charset(charset, &bytes), length))
+}
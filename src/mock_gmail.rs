@@ -0,0 +1,884 @@
+use crate::client::{Credentials, CredentialsOAuth, CredentialsToken};
+use crate::mime;
+use base64::Engine;
+use base64::engine::general_purpose;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const ACCESS_TOKEN_PREFIX: &str = "mock-access-token";
+const DEFAULT_MAX_RESULTS: usize = 100;
+const MAXIMUM_MAX_RESULTS: usize = 500;
+
+/// What the mock answers instead of the real response, see `MockGmail::inject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// 401, as for an expired access token.
+    Unauthorized,
+    /// 429 with a `Retry-After` of one second.
+    RateLimited,
+    /// 500.
+    ServerError,
+    /// The first part of the next batch response is cut off halfway through its JSON.
+    MalformedPart,
+    /// The first part of the next batch response is a 429, the batch itself succeeds.
+    RateLimitedPart,
+}
+
+impl Fault {
+    /// `401`, `429`, `500`, `malformed` or `part-429`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "401" => Some(Fault::Unauthorized),
+            "429" => Some(Fault::RateLimited),
+            "500" => Some(Fault::ServerError),
+            "malformed" => Some(Fault::MalformedPart),
+            "part-429" => Some(Fault::RateLimitedPart),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockMessage {
+    pub id: String,
+    pub thread_id: String,
+    pub label_ids: Vec<String>,
+    /// Milliseconds since the epoch, like Gmail's internalDate.
+    pub internal_date: i64,
+    pub raw: Vec<u8>,
+}
+
+impl MockMessage {
+    /// A message in its own thread in the inbox, received when its Date header says.
+    pub fn new(id: &str, raw: &[u8]) -> Self {
+        let internal_date = mime::headers(raw)
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("Date"))
+            .and_then(|header| mime::parse_date(&header.value))
+            .map(|time| time.timestamp_millis())
+            .unwrap_or_default();
+
+        Self {
+            id: id.to_string(),
+            thread_id: id.to_string(),
+            label_ids: vec!["INBOX".to_string()],
+            internal_date,
+            raw: raw.to_vec(),
+        }
+    }
+}
+
+/// `<id>.json` next to a fixture message, every field is optional.
+#[derive(Debug, Default, Deserialize)]
+struct MessageFixture {
+    #[serde(rename = "threadId")]
+    thread_id: Option<String>,
+    #[serde(rename = "labelIds")]
+    label_ids: Option<Vec<String>>,
+    #[serde(rename = "internalDate")]
+    internal_date: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MailboxFixture {
+    #[serde(rename = "emailAddress")]
+    email_address: Option<String>,
+}
+
+/// One change to the mailbox, as `history.list` returns it.
+#[derive(Debug, Clone)]
+struct HistoryRecord {
+    id: u64,
+    // messagesAdded, messagesDeleted, labelsAdded or labelsRemoved.
+    kind: &'static str,
+    message_id: String,
+    thread_id: String,
+    label_ids: Vec<String>,
+}
+
+/// The messages the mock serves and their history. Every change gets the next history id, like
+/// in Gmail.
+#[derive(Debug)]
+pub struct Mailbox {
+    email_address: String,
+    history_id: u64,
+    messages: Vec<MockMessage>,
+    history: Vec<HistoryRecord>,
+    /// History before this id is gone, starting from an earlier one answers 404.
+    oldest_history_id: u64,
+}
+
+impl Mailbox {
+    pub fn new(email_address: &str) -> Self {
+        Self {
+            email_address: email_address.to_string(),
+            history_id: 1,
+            messages: Vec::new(),
+            history: Vec::new(),
+            oldest_history_id: 0,
+        }
+    }
+
+    /// Loads every `<id>.eml` in `directory`. A `<id>.json` next to it can set the `threadId`,
+    /// `labelIds` and `internalDate` of the message, `mailbox.json` the `emailAddress`.
+    pub fn load(directory: &Path) -> Result<Self, Box<dyn Error>> {
+        let mailbox_path = directory.join("mailbox.json");
+        let fixture: MailboxFixture = if mailbox_path.exists() {
+            serde_json::from_slice(&fs::read(&mailbox_path)?)
+                .map_err(|error| format!("could not parse '{}': {error}", mailbox_path.display()))?
+        } else {
+            MailboxFixture::default()
+        };
+        let mut mailbox = Self::new(fixture.email_address.as_deref().unwrap_or("me@example.com"));
+
+        let mut messages = Vec::new();
+        for file in fs::read_dir(directory)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "eml") {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| format!("'{}' is not a valid message id", path.display()))?;
+
+            let mut message = MockMessage::new(id, &fs::read(&path)?);
+            let fixture_path = path.with_extension("json");
+            if fixture_path.exists() {
+                let fixture: MessageFixture = serde_json::from_slice(&fs::read(&fixture_path)?)
+                    .map_err(|error| {
+                        format!("could not parse '{}': {error}", fixture_path.display())
+                    })?;
+                if let Some(thread_id) = fixture.thread_id {
+                    message.thread_id = thread_id;
+                }
+                if let Some(label_ids) = fixture.label_ids {
+                    message.label_ids = label_ids;
+                }
+                if let Some(internal_date) = fixture.internal_date {
+                    message.internal_date = internal_date;
+                }
+            }
+            messages.push(message);
+        }
+
+        // oldest first, so history ids go up with time.
+        messages.sort_by(|a, b| (a.internal_date, &a.id).cmp(&(b.internal_date, &b.id)));
+        for message in messages {
+            mailbox.add(message);
+        }
+
+        Ok(mailbox)
+    }
+
+    pub fn email_address(&self) -> &str {
+        &self.email_address
+    }
+
+    pub fn history_id(&self) -> u64 {
+        self.history_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Adds or replaces a message as if it just arrived.
+    pub fn add(&mut self, message: MockMessage) {
+        self.messages.retain(|existing| existing.id != message.id);
+        self.record("messagesAdded", &message, message.label_ids.clone());
+        self.messages.push(message);
+    }
+
+    /// Returns whether the message was there.
+    pub fn delete(&mut self, message_id: &str) -> bool {
+        let Some(position) = self
+            .messages
+            .iter()
+            .position(|message| message.id == message_id)
+        else {
+            return false;
+        };
+
+        let message = self.messages.remove(position);
+        self.record("messagesDeleted", &message, Vec::new());
+        true
+    }
+
+    /// Forgets the history so far, like Gmail does after about a week.
+    pub fn expire_history(&mut self) {
+        self.history.clear();
+        self.oldest_history_id = self.history_id;
+    }
+
+    /// Adds and removes labels like `messages.modify`, returns the message afterwards.
+    pub fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Option<MockMessage> {
+        let message = self
+            .messages
+            .iter_mut()
+            .find(|message| message.id == message_id)?;
+
+        let added: Vec<String> = add
+            .iter()
+            .filter(|label_id| !message.label_ids.contains(label_id))
+            .cloned()
+            .collect();
+        let removed: Vec<String> = remove
+            .iter()
+            .filter(|label_id| message.label_ids.contains(label_id))
+            .cloned()
+            .collect();
+        message.label_ids.extend(added.iter().cloned());
+        message
+            .label_ids
+            .retain(|label_id| !removed.contains(label_id));
+
+        let message = message.clone();
+        if !added.is_empty() {
+            self.record("labelsAdded", &message, added);
+        }
+        if !removed.is_empty() {
+            self.record("labelsRemoved", &message, removed);
+        }
+        Some(message)
+    }
+
+    fn record(&mut self, kind: &'static str, message: &MockMessage, label_ids: Vec<String>) {
+        self.history_id += 1;
+        self.history.push(HistoryRecord {
+            id: self.history_id,
+            kind,
+            message_id: message.id.to_string(),
+            thread_id: message.thread_id.to_string(),
+            label_ids,
+        });
+    }
+
+    fn message(&self, message_id: &str) -> Option<&MockMessage> {
+        self.messages
+            .iter()
+            .find(|message| message.id == message_id)
+    }
+
+    /// Newest first, like Gmail lists them.
+    fn newest_first(&self) -> Vec<&MockMessage> {
+        let mut messages: Vec<&MockMessage> = self.messages.iter().collect();
+        messages.sort_by(|a, b| (b.internal_date, &b.id).cmp(&(a.internal_date, &a.id)));
+        messages
+    }
+}
+
+struct State {
+    mailbox: Mailbox,
+    faults: VecDeque<Fault>,
+    tokens_issued: u32,
+    requests: Vec<String>,
+}
+
+/// A Gmail API on localhost serving a `Mailbox`: `messages.list` with paging, `messages.get`,
+/// `messages.modify`, `history.list`, the profile, `/batch` and the OAuth token endpoint. Any
+/// bearer token is accepted, failures come from `inject`.
+///
+/// Point a `GmailClient` at it with `credentials()`. The server stops when this is dropped.
+pub struct MockGmail {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+impl MockGmail {
+    /// Serves on a free port of 127.0.0.1.
+    pub async fn start(mailbox: Mailbox) -> Result<Self, Box<dyn Error>> {
+        Self::bind("127.0.0.1:0", mailbox).await
+    }
+
+    pub async fn bind(address: &str, mailbox: Mailbox) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|error| format!("could not listen on {address}: {error}"))?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            mailbox,
+            faults: VecDeque::new(),
+            tokens_issued: 0,
+            requests: Vec::new(),
+        }));
+
+        let server_state = Arc::clone(&state);
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = Arc::clone(&server_state);
+                tokio::spawn(async move {
+                    if let Err(error) = serve(stream, &state).await {
+                        eprintln!("mock gmail could not answer a request: {error}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            address,
+            state,
+            server,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub fn token_url(&self) -> String {
+        format!("{}/token", self.url())
+    }
+
+    /// Credentials that send a `GmailClient` to this server.
+    pub fn credentials(&self) -> Credentials {
+        Credentials {
+            oauth: CredentialsOAuth {
+                client_id: "mock-client-id".to_string(),
+                project_id: "mock-project".to_string(),
+                auth_uri: format!("{}/auth", self.url()),
+                token_uri: self.token_url(),
+                auth_provider_x509_cert_url: String::new(),
+                client_secret: "mock-client-secret".to_string(),
+                redirect_uris: Vec::new(),
+            },
+            token: CredentialsToken {
+                refresh_token: Some("mock-refresh-token".to_string()),
+                access_token: format!("{ACCESS_TOKEN_PREFIX}-0"),
+                expires_in: None,
+                scope: "https://mail.google.com/".to_string(),
+                token_type: "Bearer".to_string(),
+                refresh_token_expires_in: None,
+            },
+            api_url: Some(self.url()),
+        }
+    }
+
+    /// Answers the next `times` requests with `fault` instead, after the faults injected
+    /// before. The token endpoint never fails, and only batch requests take the faults of a
+    /// part.
+    pub fn inject(&self, fault: Fault, times: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.faults.extend(std::iter::repeat_n(fault, times));
+    }
+
+    /// Changes the mailbox while the server is running, e.g. to have a sync pick up new mail.
+    pub fn update<T>(&self, update: impl FnOnce(&mut Mailbox) -> T) -> T {
+        update(&mut self.state.lock().unwrap().mailbox)
+    }
+
+    /// Every request so far as `METHOD path`, requests inside a batch included.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many access tokens the token endpoint handed out.
+    pub fn tokens_issued(&self) -> u32 {
+        self.state.lock().unwrap().tokens_issued
+    }
+}
+
+impl Drop for MockGmail {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json; charset=UTF-8".to_string(),
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, reason: &str, message: &str) -> Self {
+        Self::json(
+            status,
+            json!({ "error": { "code": status, "message": message, "status": reason } }),
+        )
+    }
+
+    fn not_found() -> Self {
+        Self::error(404, "NOT_FOUND", "Requested entity was not found.")
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+/// Answers a single request and closes the connection, which keeps the HTTP side trivial.
+async fn serve(stream: TcpStream, state: &Mutex<State>) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut words = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        return Ok(());
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    let request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body,
+    };
+    let response = handle(&mut state.lock().unwrap(), &request);
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    let stream = reader.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn handle(state: &mut State, request: &Request) -> Response {
+    state
+        .requests
+        .push(format!("{} {}", request.method, request.path()));
+
+    if request.method == "POST" && request.path() == "/token" {
+        return token(state, request);
+    }
+
+    let authorized = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| !token.trim().is_empty());
+    if !authorized {
+        return Response::error(401, "UNAUTHENTICATED", "Missing access token.");
+    }
+
+    let is_batch = request.method == "POST" && request.path() == "/batch/gmail/v1";
+    let fault = match state.faults.front() {
+        Some(Fault::MalformedPart | Fault::RateLimitedPart) if !is_batch => None,
+        Some(_) => state.faults.pop_front(),
+        None => None,
+    };
+    match fault {
+        Some(Fault::Unauthorized) => Response::error(
+            401,
+            "UNAUTHENTICATED",
+            "Request had invalid authentication credentials.",
+        ),
+        Some(Fault::RateLimited) => {
+            let mut response = Response::error(
+                429,
+                "RESOURCE_EXHAUSTED",
+                "Too many concurrent requests for user.",
+            );
+            response
+                .headers
+                .push(("Retry-After".to_string(), "1".to_string()));
+            response
+        }
+        Some(Fault::ServerError) => Response::error(500, "INTERNAL", "Backend Error"),
+        Some(fault @ (Fault::MalformedPart | Fault::RateLimitedPart)) => {
+            batch(state, request, Some(fault))
+        }
+        None if is_batch => batch(state, request, None),
+        None => route(&mut state.mailbox, request),
+    }
+}
+
+/// The OAuth token endpoint, only for refreshing.
+fn token(state: &mut State, request: &Request) -> Response {
+    let body = String::from_utf8_lossy(&request.body);
+    let form: BTreeMap<&str, &str> = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+
+    if form.get("grant_type") != Some(&"refresh_token")
+        || form
+            .get("refresh_token")
+            .is_none_or(|token| token.is_empty())
+    {
+        return Response::json(
+            400,
+            json!({ "error": "invalid_grant", "error_description": "Bad Request" }),
+        );
+    }
+
+    state.tokens_issued += 1;
+    Response::json(
+        200,
+        json!({
+            "access_token": format!("{ACCESS_TOKEN_PREFIX}-{}", state.tokens_issued),
+            "expires_in": 3599,
+            "scope": "https://mail.google.com/",
+            "token_type": "Bearer",
+        }),
+    )
+}
+
+/// Answers every request in a multipart/mixed batch in a multipart/mixed response, `fault`
+/// goes to its first part. Like Gmail, the `Content-ID` of a part is that of its request with
+/// `response-` in front.
+fn batch(state: &mut State, request: &Request, fault: Option<Fault>) -> Response {
+    let Some(boundary) = request
+        .header("Content-Type")
+        .and_then(|content_type| content_type.split_once("boundary="))
+        .map(|(_, boundary)| boundary.trim_matches('"').to_string())
+    else {
+        return Response::error(400, "INVALID_ARGUMENT", "Missing batch boundary.");
+    };
+
+    let body = String::from_utf8_lossy(&request.body);
+    let response_boundary = "batch_mock_response";
+    let mut response_body = String::new();
+
+    let delimiter = format!("--{boundary}");
+    let inner_requests = body
+        .split(&delimiter)
+        .filter_map(|part| {
+            let request_line = part
+                .lines()
+                .find(|line| line.starts_with("GET ") || line.starts_with("POST "))?;
+            let content_id = part.lines().find_map(|line| {
+                line.strip_prefix("Content-ID:")
+                    .map(|content_id| content_id.trim().trim_matches(['<', '>']))
+            });
+            Some((request_line, content_id))
+        })
+        .enumerate();
+    for (index, (request_line, content_id)) in inner_requests {
+        let mut words = request_line.split_whitespace();
+        let inner = Request {
+            method: words.next().unwrap_or_default().to_string(),
+            target: words.next().unwrap_or_default().to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        state
+            .requests
+            .push(format!("{} {}", inner.method, inner.path()));
+
+        let mut response = route(&mut state.mailbox, &inner);
+        if index == 0 && fault == Some(Fault::RateLimitedPart) {
+            response = Response::error(429, "RESOURCE_EXHAUSTED", "Rate Limit Exceeded");
+        }
+        let mut json = String::from_utf8_lossy(&response.body).to_string();
+        if index == 0 && fault == Some(Fault::MalformedPart) {
+            json.truncate(json.len() / 2);
+        }
+
+        let content_id = match content_id {
+            Some(content_id) => format!("<response-{content_id}>"),
+            None => format!("response-{}", index + 1),
+        };
+        response_body.push_str(&format!(
+            "--{response_boundary}\r\nContent-Type: application/http\r\nContent-ID: {content_id}\r\n\r\nHTTP/1.1 {} {}\r\nContent-Type: {}\r\n\r\n{json}\r\n",
+            response.status,
+            reason_phrase(response.status),
+            response.content_type,
+        ));
+    }
+    response_body.push_str(&format!("--{response_boundary}--\r\n"));
+
+    Response {
+        status: 200,
+        content_type: format!("multipart/mixed; boundary={response_boundary}"),
+        headers: Vec::new(),
+        body: response_body.into_bytes(),
+    }
+}
+
+fn route(mailbox: &mut Mailbox, request: &Request) -> Response {
+    let Some(path) = request.path().strip_prefix("/gmail/v1/users/me/") else {
+        return Response::not_found();
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["profile"]) => Response::json(
+            200,
+            json!({
+                "emailAddress": mailbox.email_address,
+                "messagesTotal": mailbox.messages.len(),
+                "threadsTotal": mailbox.messages.len(),
+                "historyId": mailbox.history_id.to_string(),
+            }),
+        ),
+        ("GET", ["messages"]) => messages_list(mailbox, request),
+        ("GET", ["messages", message_id]) => match mailbox.message(message_id) {
+            Some(message) => Response::json(
+                200,
+                message_resource(mailbox, message, request.query("format").unwrap_or("full")),
+            ),
+            None => Response::not_found(),
+        },
+        ("POST", ["messages", message_id, "modify"]) => {
+            #[derive(Deserialize)]
+            struct Modify {
+                #[serde(rename = "addLabelIds", default)]
+                add_label_ids: Vec<String>,
+                #[serde(rename = "removeLabelIds", default)]
+                remove_label_ids: Vec<String>,
+            }
+            let Ok(modify) = serde_json::from_slice::<Modify>(&request.body) else {
+                return Response::error(400, "INVALID_ARGUMENT", "Invalid JSON payload.");
+            };
+            match mailbox.modify_labels(message_id, &modify.add_label_ids, &modify.remove_label_ids)
+            {
+                Some(message) => {
+                    Response::json(200, message_resource(mailbox, &message, "minimal"))
+                }
+                None => Response::not_found(),
+            }
+        }
+        ("GET", ["history"]) => history_list(mailbox, request),
+        _ => Response::not_found(),
+    }
+}
+
+/// The page `pageToken` and `maxResults` ask for, the token is the offset of the page.
+fn page<T>(items: &[T], request: &Request) -> (usize, usize) {
+    let start = request
+        .query("pageToken")
+        .and_then(|token| token.parse().ok())
+        .unwrap_or(0)
+        .min(items.len());
+    let max_results = request
+        .query("maxResults")
+        .and_then(|max_results| max_results.parse().ok())
+        .unwrap_or(DEFAULT_MAX_RESULTS)
+        .clamp(1, MAXIMUM_MAX_RESULTS);
+    (start, (start + max_results).min(items.len()))
+}
+
+fn messages_list(mailbox: &Mailbox, request: &Request) -> Response {
+    let messages = mailbox.newest_first();
+    let (start, end) = page(&messages, request);
+
+    let mut response = json!({ "resultSizeEstimate": messages.len() });
+    if start < end {
+        response["messages"] = messages[start..end]
+            .iter()
+            .map(|message| json!({ "id": message.id, "threadId": message.thread_id }))
+            .collect();
+    }
+    if end < messages.len() {
+        response["nextPageToken"] = json!(end.to_string());
+    }
+    Response::json(200, response)
+}
+
+fn history_list(mailbox: &Mailbox, request: &Request) -> Response {
+    let Some(start_history_id) = request
+        .query("startHistoryId")
+        .and_then(|history_id| history_id.parse::<u64>().ok())
+    else {
+        return Response::error(400, "INVALID_ARGUMENT", "Invalid startHistoryId.");
+    };
+    if start_history_id < mailbox.oldest_history_id {
+        return Response::not_found();
+    }
+
+    let records: Vec<&HistoryRecord> = mailbox
+        .history
+        .iter()
+        .filter(|record| record.id > start_history_id)
+        .collect();
+    let (start, end) = page(&records, request);
+
+    let mut response = json!({ "historyId": mailbox.history_id.to_string() });
+    if start < end {
+        response["history"] = records[start..end]
+            .iter()
+            .map(|record| {
+                let message = json!({ "id": record.message_id, "threadId": record.thread_id });
+                let mut change = json!({ "message": message });
+                if record.kind.starts_with("labels") {
+                    change["labelIds"] = json!(record.label_ids);
+                }
+                let mut history = json!({ "id": record.id.to_string(), "messages": [message] });
+                history[record.kind] = json!([change]);
+                history
+            })
+            .collect();
+    }
+    if end < records.len() {
+        response["nextPageToken"] = json!(end.to_string());
+    }
+    Response::json(200, response)
+}
+
+/// A message as `messages.get` returns it in `format`: `minimal`, `metadata`, `raw` or
+/// `full`.
+fn message_resource(mailbox: &Mailbox, message: &MockMessage, format: &str) -> Value {
+    let history_id = mailbox
+        .history
+        .iter()
+        .rev()
+        .find(|record| record.message_id == message.id)
+        .map(|record| record.id)
+        .unwrap_or(mailbox.history_id);
+
+    let mut resource = json!({
+        "id": message.id,
+        "threadId": message.thread_id,
+        "labelIds": message.label_ids,
+        "snippet": "",
+        "historyId": history_id.to_string(),
+        "internalDate": message.internal_date.to_string(),
+        "sizeEstimate": message.raw.len(),
+    });
+
+    match format {
+        "raw" => resource["raw"] = json!(general_purpose::URL_SAFE.encode(&message.raw)),
+        "full" | "metadata" => {
+            let Ok(parsed) = mime::parse(&message.raw) else {
+                return resource;
+            };
+            resource["payload"] = payload_part(&parsed, "", format == "full");
+        }
+        _ => {}
+    }
+
+    resource
+}
+
+fn headers(part: &mime::Part) -> Vec<Value> {
+    part.headers
+        .iter()
+        .map(|header| json!({ "name": header.name, "value": header.value }))
+        .collect()
+}
+
+/// Gmail nests parts like the message does and numbers them by their path, e.g. `0.1`.
+/// Without `with_bodies` only the top level is described, like for `format=metadata`.
+fn payload_part(part: &mime::Part, part_id: &str, with_bodies: bool) -> Value {
+    let mut payload = json!({
+        "partId": part_id,
+        "mimeType": part.content_type.mime_type,
+        "filename": part.content_type.parameter("name").unwrap_or_default(),
+        "headers": headers(part),
+        "body": { "size": 0 },
+    });
+    if !with_bodies {
+        return payload;
+    }
+
+    if part.is_multipart() {
+        let parts: Vec<Value> = part
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(index, child)| {
+                let child_id = match part_id {
+                    "" => index.to_string(),
+                    _ => format!("{part_id}.{index}"),
+                };
+                payload_part(child, &child_id, true)
+            })
+            .collect();
+        payload["parts"] = json!(parts);
+    } else {
+        payload["body"] = json!({
+            "size": part.body.len(),
+            "data": general_purpose::URL_SAFE.encode(&part.body),
+        });
+    }
+    payload
+}
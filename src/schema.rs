@@ -0,0 +1,559 @@
+use crate::constants;
+use crate::error::MailError;
+use crate::search::Mail;
+use serde::Deserialize;
+use serde::Serialize;
+use typesense::apis::Error as ApiError;
+use typesense::apis::collections_api;
+use typesense::apis::configuration::Configuration;
+use typesense::apis::documents_api;
+use typesense::collection_schema::CollectionSchema;
+use typesense::field::Field;
+use typesense::models::CollectionAliasSchema;
+use typesense::models::CollectionUpdateSchema;
+use typesense::models::ImportDocumentsImportDocumentsParametersParameter;
+use typesense::models::import_documents_import_documents_parameters_parameter::DirtyValues;
+
+#[derive(Debug, Default)]
+pub struct SchemaDiff {
+    pub added: Vec<Field>,
+    pub removed: Vec<String>,
+    pub changed: Vec<FieldChange>,
+}
+
+#[derive(Debug)]
+pub struct FieldChange {
+    pub name: String,
+    pub live: Field,
+    pub wanted: Field,
+}
+
+#[derive(Debug)]
+pub enum Migration {
+    Created,
+    UpToDate,
+    Patched(SchemaDiff),
+    Rebuilt(SchemaDiff),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SchemaVersion {
+    id: String,
+    version: i32,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Dropped fields and added optional ones can be applied in place. A changed field means
+    /// every stored document has to be reindexed under the new definition, and so does a new
+    /// required field, as Typesense rejects it while existing documents lack it.
+    pub fn is_compatible(&self) -> bool {
+        self.changed.is_empty() && self.added.iter().all(|field| field.optional == Some(true))
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for field in &self.added {
+            writeln!(f, "+ {} ({})", field.name, field.r#type)?;
+        }
+        for name in &self.removed {
+            writeln!(f, "- {}", name)?;
+        }
+        for change in &self.changed {
+            writeln!(
+                f,
+                "~ {} ({:?} -> {:?})",
+                change.name,
+                normalize(&change.live),
+                normalize(&change.wanted)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Typesense fills in defaults for every property of a field it returns, so both sides are
+/// compared with the defaults applied.
+fn normalize(field: &Field) -> Field {
+    let sortable_by_default = matches!(field.r#type.as_str(), "int32" | "int64" | "float");
+
+    Field {
+        name: field.name.to_string(),
+        r#type: field.r#type.to_string(),
+        optional: Some(field.optional.unwrap_or(false)),
+        facet: Some(field.facet.unwrap_or(false)),
+        index: Some(field.index.unwrap_or(true)),
+        locale: Some(field.locale.clone().unwrap_or_default()),
+        sort: Some(field.sort.unwrap_or(sortable_by_default)),
+        infix: Some(field.infix.unwrap_or(false)),
+        ..Default::default()
+    }
+}
+
+pub fn diff(live: &[Field], wanted: &[Field]) -> SchemaDiff {
+    let mut diff = SchemaDiff::default();
+
+    // the id field is implicit in typesense and never returned with the collection.
+    let live: Vec<&Field> = live.iter().filter(|field| field.name != "id").collect();
+    let wanted: Vec<&Field> = wanted.iter().filter(|field| field.name != "id").collect();
+
+    for wanted_field in &wanted {
+        match live.iter().find(|field| field.name == wanted_field.name) {
+            None => diff.added.push((*wanted_field).clone()),
+            Some(live_field) => {
+                if normalize(live_field) != normalize(wanted_field) {
+                    diff.changed.push(FieldChange {
+                        name: wanted_field.name.to_string(),
+                        live: (*live_field).clone(),
+                        wanted: (*wanted_field).clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    diff.removed.extend(
+        live.iter()
+            .filter(|live_field| !wanted.iter().any(|field| field.name == live_field.name))
+            .map(|field| field.name.to_string()),
+    );
+
+    diff
+}
+
+fn is_not_found<T>(error: &ApiError<T>) -> bool {
+    matches!(error, ApiError::ResponseError(response) if response.status.as_u16() == 404)
+}
+
+/// Brings the collection behind the mail alias in line with `Mail::collection_schema`, creating
+/// the first version when nothing exists yet.
+pub async fn migrate_mail_collection(
+    configuration: &Configuration,
+) -> Result<Migration, MailError> {
+    migrate_collection(configuration, Mail::collection_schema()).await
+}
+
+/// `schema.name` is the alias searches go through, the documents live in a versioned collection
+/// behind it.
+pub async fn migrate_collection(
+    configuration: &Configuration,
+    schema: CollectionSchema,
+) -> Result<Migration, MailError> {
+    let alias_name = schema.name.to_string();
+
+    let collection_name = match resolve_alias(configuration, &alias_name).await? {
+        Some(collection_name) => collection_name,
+        None => {
+            if collection_exists(configuration, &alias_name).await? {
+                // a collection from before aliases were used, move it behind the alias.
+                reindex(configuration, schema, None, async |collection_name| {
+                    copy_documents(configuration, &alias_name, collection_name).await
+                })
+                .await?;
+                delete_collection(configuration, &alias_name).await?;
+                return Ok(Migration::Rebuilt(SchemaDiff::default()));
+            }
+
+            reindex(configuration, schema, None, async |_| Ok(())).await?;
+            return Ok(Migration::Created);
+        }
+    };
+
+    // a newer build may have added fields that patching back to this schema would drop.
+    let version = schema_version(configuration, &collection_name).await?;
+    if let Some(version) = version
+        && version > constants::SEARCHABLE_MAIL_SCHEMA_VERSION
+    {
+        Err(MailError::Config(format!(
+            "{collection_name} has schema version {version}, this build only knows up to {}",
+            constants::SEARCHABLE_MAIL_SCHEMA_VERSION
+        )))?
+    }
+
+    let live = collections_api::get_collection(configuration, &collection_name)
+        .await
+        .map_err(|error| {
+            MailError::typesense(&format!("get {collection_name} collection"), error)
+        })?;
+
+    let diff = diff(&live.fields, &schema.fields);
+
+    let migration = if diff.is_empty() {
+        if version != Some(constants::SEARCHABLE_MAIL_SCHEMA_VERSION) {
+            record_schema_version(configuration, &collection_name).await?;
+        }
+        Migration::UpToDate
+    } else if diff.is_compatible() {
+        let mut fields: Vec<Field> = diff.added.to_vec();
+        fields.extend(diff.removed.iter().map(|name| Field {
+            name: name.to_string(),
+            drop: Some(true),
+            ..Default::default()
+        }));
+
+        collections_api::update_collection(
+            configuration,
+            &collection_name,
+            CollectionUpdateSchema::new(fields),
+        )
+        .await
+        .map_err(|error| {
+            MailError::typesense(&format!("patch {collection_name} collection"), error)
+        })?;
+
+        record_schema_version(configuration, &collection_name).await?;
+
+        Migration::Patched(diff)
+    } else {
+        reindex(configuration, schema, None, async |new_collection_name| {
+            copy_documents(configuration, &collection_name, new_collection_name).await
+        })
+        .await?;
+        Migration::Rebuilt(diff)
+    };
+
+    Ok(migration)
+}
+
+/// Builds the next version of the collection behind `schema.name`, lets `fill` import documents
+/// into it and then points the alias at it. Searches keep hitting the old version until the
+/// alias is swapped. With `keep` set, only that many of the newest versions are kept.
+pub async fn reindex<F>(
+    configuration: &Configuration,
+    schema: CollectionSchema,
+    keep: Option<usize>,
+    fill: F,
+) -> Result<String, MailError>
+where
+    F: AsyncFnOnce(&str) -> Result<(), MailError>,
+{
+    let alias_name = schema.name.to_string();
+
+    let next_version = collection_versions(configuration, &alias_name)
+        .await?
+        .iter()
+        .map(|(version, _)| version + 1)
+        .max()
+        .unwrap_or(1);
+    let collection_name = versioned_collection_name(&alias_name, next_version);
+
+    let versioned_schema = CollectionSchema {
+        name: collection_name.to_string(),
+        ..schema
+    };
+
+    collections_api::create_collection(configuration, versioned_schema)
+        .await
+        .map_err(|error| {
+            MailError::typesense(&format!("create {collection_name} collection"), error)
+        })?;
+
+    let swapped = async {
+        fill(&collection_name).await?;
+        record_schema_version(configuration, &collection_name).await?;
+
+        collections_api::upsert_alias(
+            configuration,
+            &alias_name,
+            Some(CollectionAliasSchema::new(collection_name.to_string())),
+        )
+        .await
+        .map_err(|error| {
+            MailError::typesense(
+                &format!("point {alias_name} alias to {collection_name}"),
+                error,
+            )
+        })
+    }
+    .await;
+    if let Err(error) = swapped {
+        // leave the current version in place, the half built one is of no use.
+        delete_collection(configuration, &collection_name).await?;
+        return Err(error);
+    }
+
+    println!("{alias_name} now points to {collection_name}");
+
+    if let Some(keep) = keep {
+        collect_garbage(configuration, &alias_name, keep).await?;
+    }
+
+    Ok(collection_name)
+}
+
+/// Deletes old versions of the collection behind `alias_name`, keeping the `keep` newest ones and
+/// always the one the alias points to.
+pub async fn collect_garbage(
+    configuration: &Configuration,
+    alias_name: &str,
+    keep: usize,
+) -> Result<Vec<String>, MailError> {
+    let current = resolve_alias(configuration, alias_name).await?;
+
+    let mut versions = collection_versions(configuration, alias_name).await?;
+    versions.sort_by(|(left, _), (right, _)| right.cmp(left));
+
+    let mut deleted = Vec::new();
+    for (_, collection_name) in versions.into_iter().skip(keep) {
+        if current.as_deref() == Some(collection_name.as_str()) {
+            continue;
+        }
+        delete_collection(configuration, &collection_name).await?;
+        println!("deleted {collection_name}");
+        deleted.push(collection_name);
+    }
+
+    Ok(deleted)
+}
+
+pub fn versioned_collection_name(alias_name: &str, version: u32) -> String {
+    format!("{alias_name}_v{version}")
+}
+
+async fn collection_versions(
+    configuration: &Configuration,
+    alias_name: &str,
+) -> Result<Vec<(u32, String)>, MailError> {
+    let prefix = format!("{alias_name}_v");
+
+    let collections = collections_api::get_collections(configuration)
+        .await
+        .map_err(|error| MailError::typesense("list collections", error))?;
+
+    Ok(collections
+        .into_iter()
+        .filter_map(|collection| {
+            let version = collection.name.strip_prefix(&prefix)?.parse().ok()?;
+            Some((version, collection.name))
+        })
+        .collect())
+}
+
+pub async fn resolve_alias(
+    configuration: &Configuration,
+    alias_name: &str,
+) -> Result<Option<String>, MailError> {
+    match collections_api::get_alias(configuration, alias_name).await {
+        Ok(alias) => Ok(Some(alias.collection_name)),
+        Err(error) if is_not_found(&error) => Ok(None),
+        Err(error) => Err(MailError::typesense(
+            &format!("get {alias_name} alias"),
+            error,
+        )),
+    }
+}
+
+async fn collection_exists(
+    configuration: &Configuration,
+    collection_name: &str,
+) -> Result<bool, MailError> {
+    match collections_api::get_collection(configuration, collection_name).await {
+        Ok(_) => Ok(true),
+        Err(error) if is_not_found(&error) => Ok(false),
+        Err(error) => Err(MailError::typesense(
+            &format!("get {collection_name} collection"),
+            error,
+        )),
+    }
+}
+
+async fn delete_collection(
+    configuration: &Configuration,
+    collection_name: &str,
+) -> Result<(), MailError> {
+    collections_api::delete_collection(configuration, collection_name)
+        .await
+        .map_err(|error| {
+            MailError::typesense(&format!("delete {collection_name} collection"), error)
+        })?;
+
+    Ok(())
+}
+
+/// Copies every document of `from` into `to`, coercing or dropping values that no longer fit the
+/// schema of `to`. The export is streamed and imported in batches, so the collection does not
+/// have to fit into memory. Fails if any document could not be copied, so a rebuild never
+/// replaces a collection with one that lost documents.
+pub async fn copy_documents(
+    configuration: &Configuration,
+    from: &str,
+    to: &str,
+) -> Result<(), MailError> {
+    let url = format!(
+        "{}/collections/{}/documents/export",
+        configuration.base_path, from
+    );
+
+    let mut request = configuration.client.get(&url);
+    if let Some(api_key) = &configuration.api_key {
+        request = request.header("X-TYPESENSE-API-KEY", &api_key.key);
+    }
+
+    let mut response = request.send().await.map_err(|error| {
+        MailError::Index(format!(
+            "could not reach typesense to export {from}: {error}"
+        ))
+    })?;
+    let status = response.status().as_u16();
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        if matches!(status, 401 | 403) {
+            return Err(MailError::Auth(format!(
+                "typesense refused to export {from}: {body}"
+            )));
+        }
+        return Err(MailError::Http { url, status, body });
+    }
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut batch: Vec<String> = Vec::new();
+    let mut copied = 0;
+
+    loop {
+        let chunk = response.chunk().await.map_err(|error| {
+            MailError::Index(format!("could not export {from} collection: {error}"))
+        })?;
+        let finished = chunk.is_none();
+        match chunk {
+            Some(chunk) => pending.extend_from_slice(&chunk),
+            // the last document may not end with a newline.
+            None => pending.push(b'\n'),
+        }
+
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let document = String::from_utf8(line).map_err(|error| {
+                MailError::parse_response(format!("export of {from} collection: {error}"))
+            })?;
+            if !document.trim().is_empty() {
+                batch.push(document.trim().to_string());
+            }
+        }
+
+        if batch.len() >= constants::IMPORT_BATCH_SIZE || (finished && !batch.is_empty()) {
+            copied += import_copies(configuration, from, to, &batch).await?;
+            batch.clear();
+        }
+        if finished {
+            break;
+        }
+    }
+
+    println!("copied {copied} documents from {from} into {to}");
+    Ok(())
+}
+
+async fn import_copies(
+    configuration: &Configuration,
+    from: &str,
+    to: &str,
+    documents: &[String],
+) -> Result<usize, MailError> {
+    let parameters = ImportDocumentsImportDocumentsParametersParameter {
+        action: Some("upsert".to_string()),
+        dirty_values: Some(DirtyValues::CoerceOrDrop),
+        ..Default::default()
+    };
+
+    let result =
+        documents_api::import_documents(configuration, to, documents.join("\n"), Some(parameters))
+            .await
+            .map_err(|error| {
+                MailError::typesense(&format!("copy documents from {from} into {to}"), error)
+            })?;
+
+    let failures: Vec<&str> = result
+        .lines()
+        .filter(|line| !line.contains("\"success\":true"))
+        .collect();
+    if let Some(failure) = failures.first() {
+        Err(MailError::Index(format!(
+            "{} documents could not be copied from {from} into {to}, e.g. {failure}",
+            failures.len()
+        )))?
+    }
+
+    Ok(documents.len())
+}
+
+/// The schema version recorded for `collection_name`, `None` for collections from before
+/// versions were recorded.
+async fn schema_version(
+    configuration: &Configuration,
+    collection_name: &str,
+) -> Result<Option<i32>, MailError> {
+    match documents_api::get_document(
+        configuration,
+        constants::SCHEMA_VERSIONS_COLLECTION_NAME,
+        collection_name,
+    )
+    .await
+    {
+        Ok(document) => Ok(Some(
+            serde_json::from_value::<SchemaVersion>(document)?.version,
+        )),
+        Err(error) if is_not_found(&error) => Ok(None),
+        Err(error) => Err(MailError::typesense(
+            &format!("get schema version of {collection_name}"),
+            error,
+        )),
+    }
+}
+
+async fn record_schema_version(
+    configuration: &Configuration,
+    collection_name: &str,
+) -> Result<(), MailError> {
+    let versions_schema = CollectionSchema {
+        name: constants::SCHEMA_VERSIONS_COLLECTION_NAME.to_string(),
+        fields: vec![Field {
+            name: "version".to_string(),
+            r#type: "int32".to_string(),
+            ..Default::default()
+        }],
+        default_sorting_field: None,
+        token_separators: None,
+        enable_nested_fields: None,
+        symbols_to_index: None,
+    };
+
+    if let Err(error) =
+        collections_api::get_collection(configuration, constants::SCHEMA_VERSIONS_COLLECTION_NAME)
+            .await
+    {
+        if !is_not_found(&error) {
+            return Err(MailError::typesense(
+                "get schema versions collection",
+                error,
+            ));
+        }
+        collections_api::create_collection(configuration, versions_schema)
+            .await
+            .map_err(|error| MailError::typesense("create schema versions collection", error))?;
+    }
+
+    let version = SchemaVersion {
+        id: collection_name.to_string(),
+        version: constants::SEARCHABLE_MAIL_SCHEMA_VERSION,
+    };
+
+    documents_api::index_document(
+        configuration,
+        constants::SCHEMA_VERSIONS_COLLECTION_NAME,
+        serde_json::to_value(&version)?,
+        Some("upsert"),
+    )
+    .await
+    .map_err(|error| {
+        MailError::typesense(
+            &format!("record schema version of {collection_name}"),
+            error,
+        )
+    })?;
+
+    Ok(())
+}
@@ -1,15 +1,17 @@
 use crate::constants;
+use crate::error::MailError;
 use crate::utils;
 use serde::Deserialize;
 use serde::Serialize;
-use std::error::Error;
-use tokio::runtime::Runtime;
-use typesense::apis::collections_api;
 use typesense::apis::configuration::ApiKey;
 use typesense::apis::configuration::Configuration;
-use typesense::apis::documents_api::import_documents;
+use typesense::apis::documents_api::delete_document;
+use typesense::apis::documents_api::import_documents as import_documents_api;
+use typesense::apis::documents_api::search_collection;
 use typesense::collection_schema::CollectionSchema;
 use typesense::field::Field;
+use typesense::models::ImportDocumentsImportDocumentsParametersParameter;
+use typesense::models::SearchParameters;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,20 +86,31 @@ impl FieldType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Mail {
     pub id: String,
     pub thread_id: String,
 
-    pub subject: String,
-    pub time: i64, // unix timestamp
+    // missing on some notifications and drafts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    // unix timestamp, missing when neither the source nor the Date header tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>,
     pub labels: Vec<String>,
 
     pub raw_body: String,        // html
     pub searchable_body: String, // cleaned text
 
     pub from: String,
-    pub to: String,
+    // missing when the message was only sent to Bcc recipients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+
+    pub source: String,  // e.g. gmail
+    pub account: String, // e.g. the email address
+    #[serde(default)]
+    pub accounts: Vec<String>, // every source/account a duplicate of this message is in
 }
 
 #[allow(dead_code)]
@@ -145,9 +158,11 @@ pub const LABEL_SENT: &str = "sent";
 pub const LABEL_SCHEDULED: &str = "scheduled";
 pub const LABEL_SPAM: &str = "spam";
 pub const LABEL_BIN: &str = "bin";
+pub const LABEL_UNREAD: &str = "unread";
+pub const LABEL_REPLIED: &str = "replied";
 
 #[allow(dead_code)]
-pub const LABELS: [&'static str; 7] = [
+pub const LABELS: [&str; 9] = [
     LABEL_INBOX,
     LABEL_STARRED,
     LABEL_IMPORTANT,
@@ -155,10 +170,20 @@ pub const LABELS: [&'static str; 7] = [
     LABEL_SCHEDULED,
     LABEL_SPAM,
     LABEL_BIN,
+    LABEL_UNREAD,
+    LABEL_REPLIED,
 ];
 
+/// A converted message and what was off about it, e.g. a Date header that could not be
+/// parsed, without being reason enough to leave it out of the index.
+#[derive(Debug)]
+pub struct Conversion {
+    pub mail: Mail,
+    pub warnings: Vec<String>,
+}
+
 pub trait Searchable {
-    fn to_searchable_mail(&self) -> Result<Mail, Box<dyn Error>>;
+    fn to_searchable_mail(&self) -> Result<Conversion, MailError>;
 }
 
 #[allow(dead_code)]
@@ -187,6 +212,7 @@ impl Mail {
                 Field {
                     name: "time".to_string(),
                     r#type: FieldType::Int64.as_str().to_string(),
+                    optional: Some(true),
                     ..Default::default()
                 },
                 Field {
@@ -218,10 +244,32 @@ impl Mail {
                 Field {
                     name: "to".to_string(),
                     r#type: FieldType::String.as_str().to_string(),
+                    optional: Some(true),
                     infix: Some(true),
                     facet: Some(true),
                     ..Default::default()
                 },
+                Field {
+                    name: "source".to_string(),
+                    r#type: FieldType::String.as_str().to_string(),
+                    optional: Some(true),
+                    facet: Some(true),
+                    ..Default::default()
+                },
+                Field {
+                    name: "account".to_string(),
+                    r#type: FieldType::String.as_str().to_string(),
+                    optional: Some(true),
+                    facet: Some(true),
+                    ..Default::default()
+                },
+                Field {
+                    name: "accounts".to_string(),
+                    r#type: FieldType::StringArray.as_str().to_string(),
+                    optional: Some(true),
+                    facet: Some(true),
+                    ..Default::default()
+                },
             ],
             default_sorting_field: None,
             token_separators: None,
@@ -231,25 +279,8 @@ impl Mail {
     }
 }
 
-#[allow(dead_code)]
-pub fn update_collection(
-    runtime: Runtime,
-    configuration: &Configuration,
-) -> Result<(), Box<dyn Error>> {
-    let collection = runtime
-        .block_on(collections_api::create_collection(
-            configuration,
-            Mail::collection_schema(),
-        ))
-        .map_err(|error| format!("could not update mail collection schema: {error}"))?;
-
-    println!("{:?}", collection);
-
-    Ok(())
-}
-
 // TODO shoul probaly be embedded in the runtime clinet
-pub fn get_typesense_configuration() -> Result<Configuration, Box<dyn Error>> {
+pub fn get_typesense_configuration() -> Result<Configuration, MailError> {
     #[derive(Serialize, Deserialize, Debug)]
     struct Credentials {
         url: String,
@@ -257,9 +288,10 @@ pub fn get_typesense_configuration() -> Result<Configuration, Box<dyn Error>> {
         api_key: String,
     }
 
-    let credentials: Credentials =
-        utils::read_json(&constants::TYPESENSE_CREDENTIALS.display().to_string())
-            .map_err(|error| format!("could not read typesense credentials: {error}"))?;
+    let credentials: Credentials = utils::read_json(
+        &constants::TYPESENSE_CREDENTIALS.display().to_string(),
+    )
+    .map_err(|error| MailError::Config(format!("could not read typesense credentials: {error}")))?;
 
     let configuration = Configuration {
         base_path: credentials.url,
@@ -274,23 +306,241 @@ pub fn get_typesense_configuration() -> Result<Configuration, Box<dyn Error>> {
     Ok(configuration)
 }
 
-pub fn import_document<T>(
-    runtime: &Runtime,
+pub trait Document: Serialize {
+    fn id(&self) -> &str;
+}
+
+impl Document for Mail {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ImportResult {
+    Success { id: String },
+    Error { id: String, error: String },
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub failures: Vec<ImportFailure>,
+}
+
+#[derive(Debug)]
+pub struct ImportFailure {
+    pub id: String,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+struct ImportResultLine {
+    success: bool,
+    error: Option<String>,
+}
+
+/// Imports documents as JSONL with `action=upsert`, `batch_size` documents per request, so
+/// re-importing an existing id replaces it instead of failing.
+pub async fn import_documents<T>(
     configuration: &Configuration,
     collection_name: &str,
-    document: &T,
-) -> Result<(), Box<dyn Error>>
+    documents: &[T],
+    batch_size: usize,
+) -> Result<ImportReport, MailError>
+where
+    T: Document,
+{
+    if batch_size == 0 {
+        Err(MailError::Invalid(
+            "import batch size must be greater than 0".to_string(),
+        ))?
+    }
+
+    let mut report = ImportReport::default();
+
+    for batch in documents.chunks(batch_size) {
+        let mut body = String::new();
+        for document in batch {
+            let line = serde_json::to_string(document).map_err(|error| {
+                MailError::Index(format!(
+                    "could not serialize document '{}' for {collection_name}: {error}",
+                    document.id()
+                ))
+            })?;
+            body.push_str(&line);
+            body.push('\n');
+        }
+
+        let parameters = ImportDocumentsImportDocumentsParametersParameter {
+            action: Some("upsert".to_string()),
+            batch_size: i32::try_from(batch.len()).ok(),
+            ..Default::default()
+        };
+
+        let result = import_documents_api(configuration, collection_name, body, Some(parameters))
+            .await
+            .map_err(|error| {
+                MailError::typesense(&format!("import documents into {collection_name}"), error)
+            })?;
+
+        for import_result in parse_import_results(batch, &result)? {
+            match import_result {
+                ImportResult::Success { .. } => report.imported += 1,
+                ImportResult::Error { id, error } => {
+                    report.failures.push(ImportFailure { id, error })
+                }
+            }
+        }
+    }
+
+    println!(
+        "imported {} documents into {} ({} failed)",
+        report.imported,
+        collection_name,
+        report.failures.len()
+    );
+
+    Ok(report)
+}
+
+/// Typesense answers an import with one JSON line per document, in the order they were sent.
+fn parse_import_results<T>(batch: &[T], raw_results: &str) -> Result<Vec<ImportResult>, MailError>
 where
-    T: Serialize,
+    T: Document,
 {
-    let body = serde_json::to_string(document)
-        .map_err(|error| format!("could not serialize object for {collection_name}: {error}"))?;
+    let lines: Vec<&str> = raw_results
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+
+    if lines.len() != batch.len() {
+        Err(MailError::Index(format!(
+            "expected {} import results, got {}",
+            batch.len(),
+            lines.len()
+        )))?
+    }
 
-    let result = runtime
-        .block_on(import_documents(configuration, collection_name, body, None))
-        .map_err(|error| format!("could not import document into {collection_name}: {error}"))?;
+    batch
+        .iter()
+        .zip(lines)
+        .map(|(document, line)| {
+            let id = document.id().to_string();
+            let parsed: ImportResultLine = serde_json::from_str(line).map_err(|error| {
+                MailError::parse_response(format!("import result '{line}': {error}"))
+            })?;
 
-    println!("imported document into {}: {}", collection_name, result);
+            if parsed.success {
+                Ok(ImportResult::Success { id })
+            } else {
+                Ok(ImportResult::Error {
+                    id,
+                    error: parsed.error.unwrap_or_else(|| "unknown error".to_string()),
+                })
+            }
+        })
+        .collect()
+}
+
+/// Deletes documents by id, ids that are not in the collection are ignored.
+pub async fn delete_documents(
+    configuration: &Configuration,
+    collection_name: &str,
+    ids: &[String],
+) -> Result<(), MailError> {
+    for id in ids {
+        match delete_document(configuration, collection_name, id).await {
+            Ok(_) => {}
+            Err(typesense::apis::Error::ResponseError(response))
+                if response.status.as_u16() == 404 => {}
+            Err(error) => Err(MailError::typesense(
+                &format!("delete document {id} from {collection_name}"),
+                error,
+            ))?,
+        }
+    }
 
     Ok(())
 }
+
+/// What to search for: `text` in Typesense's query syntax, `*` for everything, narrowed down by
+/// an optional `filter_by` expression like `labels:=INBOX && time:>1700000000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub text: String,
+    pub filter_by: Option<String>,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            filter_by: None,
+        }
+    }
+
+    pub fn all() -> Self {
+        Self::new("*")
+    }
+
+    pub fn filter_by(mut self, filter_by: &str) -> Self {
+        self.filter_by = Some(filter_by.to_string());
+        self
+    }
+}
+
+#[derive(Deserialize)]
+struct MatchedDocument {
+    id: String,
+}
+
+/// The ids of every document matching `query`, in the order Typesense ranks them, paging
+/// through all results.
+pub async fn search_document_ids(
+    configuration: &Configuration,
+    collection_name: &str,
+    query: &Query,
+) -> Result<Vec<String>, MailError> {
+    const PER_PAGE: i32 = 250;
+
+    let mut ids = Vec::new();
+
+    for page in 1.. {
+        let parameters = SearchParameters {
+            filter_by: query.filter_by.clone(),
+            include_fields: Some("id".to_string()),
+            page: Some(page),
+            per_page: Some(PER_PAGE),
+            ..SearchParameters::new(
+                query.text.to_string(),
+                "subject,searchable_body,from,to".to_string(),
+            )
+        };
+
+        let result =
+            search_collection::<MatchedDocument>(configuration, collection_name, parameters)
+                .await
+                .map_err(|error| {
+                    MailError::typesense(
+                        &format!("search {collection_name} for '{}'", query.text),
+                        error,
+                    )
+                })?;
+
+        let hits = result.hits.unwrap_or_default();
+        let last_page = hits.len() < PER_PAGE as usize;
+        ids.extend(
+            hits.into_iter()
+                .filter_map(|hit| hit.document)
+                .map(|document| document.id),
+        );
+
+        if last_page {
+            break;
+        }
+    }
+
+    Ok(ids)
+}
@@ -0,0 +1,71 @@
+use crate::cache::{MessageFormat, MessageMetadata};
+use crate::error::MailError;
+use async_trait::async_trait;
+
+/// A message as a source returned it. `content` is what ends up in the message cache, its
+/// format decides how it is parsed.
+#[derive(Debug)]
+pub struct FetchedMessage {
+    pub id: String,
+    pub metadata: MessageMetadata,
+    pub format: MessageFormat,
+    pub content: Vec<u8>,
+}
+
+/// A message `MailSource::fetch` could not get. It is recorded in the state store, the next
+/// sync that comes across it tries again.
+#[derive(Debug)]
+pub struct FetchFailure {
+    pub message_id: String,
+    pub error: MailError,
+}
+
+/// What `MailSource::fetch` got. Messages that no longer exist are in neither list.
+#[derive(Debug, Default)]
+pub struct Fetched {
+    pub messages: Vec<FetchedMessage>,
+    pub failures: Vec<FetchFailure>,
+}
+
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub changed: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Where to continue from on the next call to `MailSource::changes`.
+    pub checkpoint: String,
+}
+
+/// A mailbox that can feed the index. Message ids have to be unique across sources, as they
+/// are used as document ids in the mail collection.
+///
+/// Sources built on blocking I/O run it in `tokio::task::block_in_place` on a multi-thread
+/// runtime, so they do not stall the other tasks of the pipeline. On a current-thread runtime
+/// they block it instead. Syncs run on the task that started them, so the futures do not need
+/// to be `Send`.
+#[async_trait(?Send)]
+pub trait MailSource {
+    /// Short name of the provider stored with every mail, e.g. `gmail`.
+    fn name(&self) -> &str;
+
+    /// The account within the provider, usually the email address.
+    fn account(&self) -> &str;
+
+    /// Ids of the most recent `limit` messages.
+    async fn list(&mut self, limit: u32) -> Result<Vec<String>, MailError>;
+
+    async fn fetch(&mut self, message_ids: &[String]) -> Result<Fetched, MailError>;
+
+    /// The current position in the mailbox, to ask for changes from later on.
+    async fn checkpoint(&mut self) -> Result<String, MailError>;
+
+    /// Messages that were added, changed or deleted since `checkpoint`.
+    async fn changes(&mut self, checkpoint: &str) -> Result<Changes, MailError>;
+
+    /// Adds and removes labels, named like `search::LABELS`, on a message at the source.
+    async fn modify_labels(
+        &mut self,
+        message_id: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<(), MailError>;
+}
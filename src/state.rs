@@ -0,0 +1,263 @@
+use crate::error::MailError;
+use crate::search::{ImportReport, Mail};
+use crate::utils;
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS checkpoints (
+    source TEXT NOT NULL,
+    account TEXT NOT NULL,
+    checkpoint TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (source, account)
+);
+CREATE TABLE IF NOT EXISTS indexed (
+    message_id TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    indexed_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS failures (
+    message_id TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    error TEXT NOT NULL,
+    failed_at INTEGER NOT NULL,
+    PRIMARY KEY (message_id, stage)
+);
+";
+
+/// The source could not return the message.
+pub const STAGE_FETCH: &str = "fetch";
+/// The message could not be converted into a search document.
+pub const STAGE_CONVERT: &str = "convert";
+/// The index rejected the document, or the import of its batch failed.
+pub const STAGE_INDEX: &str = "index";
+
+/// A message that did not make it into the index, and at which stage, `STAGE_FETCH`,
+/// `STAGE_CONVERT` or `STAGE_INDEX`.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub message_id: String,
+    pub stage: String,
+    pub error: String,
+    /// Unix timestamp of the last time it failed.
+    pub failed_at: i64,
+}
+
+/// What a sync remembers between runs, in SQLite:
+/// - `checkpoints`: how far every source and account has been synced, e.g. Gmail's historyId
+/// - `indexed`: every message in the index with the hash of the document it was indexed as, so
+///   unchanged messages are not imported again
+/// - `failures`: messages that could not be fetched, converted or indexed, until a retry
+///   succeeds
+///
+/// Every method takes `&self`, so the sync and the import running next to it can share it.
+pub struct StateStore {
+    connection: Connection,
+}
+
+impl StateStore {
+    /// Opens or creates the store. Checkpoints the message cache kept in `checkpoints.json`
+    /// next to it are moved into the store.
+    pub fn open(path: &Path) -> Result<Self, MailError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path).map_err(|error| {
+            MailError::Storage(format!(
+                "could not open state in '{}': {error}",
+                path.display()
+            ))
+        })?;
+        let store = Self::with_connection(connection)?;
+
+        if let Some(parent) = path.parent() {
+            store.migrate_checkpoints(&parent.join("checkpoints.json"))?;
+        }
+
+        Ok(store)
+    }
+
+    /// A store that is gone when dropped, for tests and one-off imports.
+    pub fn open_in_memory() -> Result<Self, MailError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, MailError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    fn migrate_checkpoints(&self, path: &Path) -> Result<(), MailError> {
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let checkpoints: BTreeMap<String, String> =
+            utils::read_json(&path.display().to_string())
+                .map_err(|error| MailError::Storage(error.to_string()))?;
+        for (key, checkpoint) in checkpoints {
+            // keys are `source/account`, and only Gmail had no source in front.
+            let (source, account) = key.split_once('/').unwrap_or(("gmail", key.as_str()));
+            if self.checkpoint(source, account)?.is_none() {
+                self.set_checkpoint(source, account, &checkpoint)?;
+            }
+        }
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    pub fn checkpoint(&self, source: &str, account: &str) -> Result<Option<String>, MailError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT checkpoint FROM checkpoints WHERE source = ?1 AND account = ?2",
+                params![source, account],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn set_checkpoint(
+        &self,
+        source: &str,
+        account: &str,
+        checkpoint: &str,
+    ) -> Result<(), MailError> {
+        self.connection.execute(
+            "INSERT INTO checkpoints (source, account, checkpoint, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (source, account)
+             DO UPDATE SET checkpoint = excluded.checkpoint, updated_at = excluded.updated_at",
+            params![source, account, checkpoint, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The hash of the document a message was last indexed as, see `document_hash`.
+    pub fn indexed_hash(&self, message_id: &str) -> Result<Option<String>, MailError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT hash FROM indexed WHERE message_id = ?1",
+                params![message_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Whether the index already holds exactly this document.
+    pub fn is_indexed(&self, mail: &Mail) -> Result<bool, MailError> {
+        Ok(self.indexed_hash(&mail.id)?.as_deref() == Some(document_hash(mail).as_str()))
+    }
+
+    /// Records which documents of an import the index accepted and which it rejected.
+    pub fn record_import(&self, mails: &[Mail], report: &ImportReport) -> Result<(), MailError> {
+        let now = Utc::now().timestamp();
+        let transaction = self.connection.unchecked_transaction()?;
+
+        for mail in mails {
+            match report.failures.iter().find(|failure| failure.id == mail.id) {
+                Some(failure) => {
+                    insert_failure(&transaction, &mail.id, STAGE_INDEX, &failure.error)?
+                }
+                None => {
+                    transaction.execute(
+                        "INSERT INTO indexed (message_id, hash, indexed_at) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (message_id)
+                         DO UPDATE SET hash = excluded.hash, indexed_at = excluded.indexed_at",
+                        params![mail.id, document_hash(mail), now],
+                    )?;
+                    delete_failure(&transaction, &mail.id, STAGE_INDEX)?;
+                }
+            }
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Forgets messages that were removed from the index, and their failures.
+    pub fn remove_indexed(&self, message_ids: &[String]) -> Result<(), MailError> {
+        let transaction = self.connection.unchecked_transaction()?;
+        for message_id in message_ids {
+            transaction.execute(
+                "DELETE FROM indexed WHERE message_id = ?1",
+                params![message_id],
+            )?;
+            transaction.execute(
+                "DELETE FROM failures WHERE message_id = ?1",
+                params![message_id],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Records that a message failed at `stage`, replacing an earlier failure at that stage.
+    pub fn record_failure(
+        &self,
+        message_id: &str,
+        stage: &str,
+        error: &str,
+    ) -> Result<(), MailError> {
+        insert_failure(&self.connection, message_id, stage, error)
+    }
+
+    /// Forgets a failure once the message made it past `stage`.
+    pub fn clear_failure(&self, message_id: &str, stage: &str) -> Result<(), MailError> {
+        delete_failure(&self.connection, message_id, stage)
+    }
+
+    /// Every recorded failure, oldest first.
+    pub fn failures(&self) -> Result<Vec<Failure>, MailError> {
+        let mut statement = self.connection.prepare(
+            "SELECT message_id, stage, error, failed_at FROM failures ORDER BY failed_at, message_id",
+        )?;
+        let failures = statement
+            .query_map([], |row| {
+                Ok(Failure {
+                    message_id: row.get(0)?,
+                    stage: row.get(1)?,
+                    error: row.get(2)?,
+                    failed_at: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(failures)
+    }
+}
+
+fn insert_failure(
+    connection: &Connection,
+    message_id: &str,
+    stage: &str,
+    error: &str,
+) -> Result<(), MailError> {
+    connection.execute(
+        "INSERT INTO failures (message_id, stage, error, failed_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (message_id, stage)
+         DO UPDATE SET error = excluded.error, failed_at = excluded.failed_at",
+        params![message_id, stage, error, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+fn delete_failure(connection: &Connection, message_id: &str, stage: &str) -> Result<(), MailError> {
+    connection.execute(
+        "DELETE FROM failures WHERE message_id = ?1 AND stage = ?2",
+        params![message_id, stage],
+    )?;
+    Ok(())
+}
+
+/// The SHA-256 of a document as it is sent to the index.
+pub fn document_hash(mail: &Mail) -> String {
+    let document = serde_json::to_vec(mail).unwrap_or_default();
+    format!("{:x}", Sha256::digest(document))
+}
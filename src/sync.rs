@@ -0,0 +1,263 @@
+use crate::cache::{MessageCache, MessageFormat};
+use crate::constants;
+use crate::dedupe;
+use crate::error::MailError;
+use crate::gmail;
+use crate::mime;
+use crate::search;
+use crate::search::Searchable;
+use crate::source::MailSource;
+use crate::state::{self, StateStore};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Default)]
+pub struct SyncResult {
+    /// Messages that are cached and should be (re)indexed, but were not sent as documents yet.
+    pub message_ids: Vec<String>,
+    /// Messages that were deleted at the source.
+    pub deleted: Vec<String>,
+}
+
+/// Caches the `limit` most recent messages of `source` and everything that changed since the
+/// last sync, only fetching what is not cached yet or changed. Fetched messages are converted
+/// and sent to `documents` batch by batch, so they can be imported while the next batch is
+/// fetched.
+pub async fn sync_source(
+    source: &mut dyn MailSource,
+    cache: &mut MessageCache,
+    state: &StateStore,
+    limit: u32,
+    documents: &mpsc::Sender<Vec<search::Mail>>,
+) -> Result<SyncResult, MailError> {
+    let source_name = source.name().to_string();
+    let account = source.account().to_string();
+
+    let mut changed = Vec::new();
+    let mut deleted = Vec::new();
+    // copies of deleted messages, one of them may be the new canonical copy.
+    let mut duplicates = Vec::new();
+    // everything cached from the account, when the checkpoint was no longer valid.
+    let mut rescanned = Vec::new();
+
+    let checkpoint = match state.checkpoint(&source_name, &account)? {
+        Some(checkpoint) => match source.changes(&checkpoint).await {
+            Ok(changes) => {
+                for message_id in &changes.changed {
+                    cache.invalidate(message_id);
+                }
+                for message_id in &changes.deleted {
+                    duplicates.extend(cache.remove(message_id));
+                }
+                changed = changes.changed;
+                deleted = changes.deleted;
+                changes.checkpoint
+            }
+            // anything cached from the account may have changed since, so it is all fetched
+            // again.
+            Err(MailError::Checkpoint(reason)) => {
+                eprintln!("syncing {source_name}/{account} from scratch: {reason}");
+                rescanned = cache
+                    .entries()
+                    .filter(|(_, entry)| {
+                        entry.metadata.source == source_name && entry.metadata.account == account
+                    })
+                    .map(|(message_id, _)| message_id.to_string())
+                    .collect();
+                for message_id in &rescanned {
+                    cache.invalidate(message_id);
+                }
+                changed = rescanned.clone();
+                source.checkpoint().await?
+            }
+            Err(error) => return Err(error),
+        },
+        // taken before listing, so nothing that arrives in between is missed next time.
+        None => source.checkpoint().await?,
+    };
+
+    let mut message_ids = source.list(limit).await?;
+    message_ids.retain(|message_id| !deleted.contains(message_id));
+    changed.retain(|message_id| !message_ids.contains(message_id));
+    message_ids.extend(changed);
+
+    let missing: Vec<String> = message_ids
+        .iter()
+        .filter(|message_id| !cache.contains(message_id))
+        .cloned()
+        .collect();
+
+    let mut regrouped = Vec::new();
+    let mut failed = BTreeSet::new();
+    for batch in missing.chunks(constants::SYNC_FETCH_BATCH_SIZE) {
+        let result = source.fetch(batch).await?;
+        for failure in &result.failures {
+            eprintln!(
+                "could not fetch {source_name} message {}: {}",
+                failure.message_id, failure.error
+            );
+            state.record_failure(
+                &failure.message_id,
+                state::STAGE_FETCH,
+                &failure.error.to_string(),
+            )?;
+            failed.insert(failure.message_id.to_string());
+        }
+
+        let mut fetched = Vec::new();
+        for message in result.messages {
+            regrouped.extend(cache.put(
+                &message.id,
+                message.metadata,
+                message.format,
+                &message.content,
+            )?);
+            state.clear_failure(&message.id, state::STAGE_FETCH)?;
+            fetched.push(message.id);
+        }
+
+        let mails = cached_mails(cache, state, &fetched)?;
+        if !mails.is_empty() {
+            documents
+                .send(mails)
+                .await
+                .map_err(|_| MailError::Index("search import stopped".to_string()))?;
+        }
+    }
+    // what the source no longer returns was deleted while the checkpoint was not valid.
+    for message_id in rescanned {
+        if !cache.contains(&message_id) && !failed.contains(&message_id) {
+            duplicates.extend(cache.remove(&message_id));
+            deleted.push(message_id);
+        }
+    }
+    let sent: BTreeSet<&String> = missing.iter().collect();
+    message_ids.retain(|message_id| !sent.contains(message_id));
+
+    for message_id in regrouped.into_iter().chain(duplicates) {
+        if !message_ids.contains(&message_id) && !deleted.contains(&message_id) {
+            message_ids.push(message_id);
+        }
+    }
+
+    cache.save()?;
+    // only once everything up to it is cached.
+    state.set_checkpoint(&source_name, &account, &checkpoint)?;
+    cache.prune()?;
+
+    Ok(SyncResult {
+        message_ids,
+        deleted,
+    })
+}
+
+/// Converts cached messages into search documents. Messages that cannot be converted are
+/// reported, recorded as failures in `state` and skipped. Copies of a message from other
+/// sources or accounts are collapsed into one document under the id of the canonical copy, see
+/// `dedupe::Duplicates`.
+pub fn cached_mails<'a>(
+    cache: &mut MessageCache,
+    state: &StateStore,
+    message_ids: impl IntoIterator<Item = &'a String>,
+) -> Result<Vec<search::Mail>, MailError> {
+    let mut converted = BTreeMap::new();
+    let mut canonical_ids = Vec::new();
+
+    for message_id in message_ids {
+        let Some((mail, key)) = cached_mail(cache, state, message_id)? else {
+            continue;
+        };
+        cache.add_duplicate(message_id, &key);
+        converted.insert(message_id.to_string(), mail);
+
+        let canonical_id = cache.duplicates(message_id).swap_remove(0);
+        if !canonical_ids.contains(&canonical_id) {
+            canonical_ids.push(canonical_id);
+        }
+    }
+
+    let mut mails = Vec::new();
+    for canonical_id in canonical_ids {
+        let mut copies = Vec::new();
+        for message_id in cache.duplicates(&canonical_id) {
+            match converted.remove(&message_id) {
+                Some(mail) => copies.push(mail),
+                None => {
+                    if let Some((mail, _)) = cached_mail(cache, state, &message_id)? {
+                        copies.push(mail);
+                    }
+                }
+            }
+        }
+        mails.extend(dedupe::merge(copies));
+    }
+
+    Ok(mails)
+}
+
+/// Converts a cached message and returns it with its dedupe key.
+fn cached_mail(
+    cache: &MessageCache,
+    state: &StateStore,
+    message_id: &str,
+) -> Result<Option<(search::Mail, String)>, MailError> {
+    let (Some(entry), Some(content)) = (cache.entry(message_id), cache.get(message_id)?) else {
+        return Ok(None);
+    };
+
+    let mail = match entry.format {
+        MessageFormat::Full => serde_json::from_slice::<gmail::Message>(&content)
+            .map_err(|error| MailError::parse(message_id, format!("cached message: {error}")))
+            .and_then(|message| {
+                let message_id_header = message.header("Message-ID").map(str::to_string);
+                Ok((message.to_searchable_mail()?, message_id_header))
+            }),
+        MessageFormat::Raw => mime::parse(&content)
+            .map_err(|error| error.with_message_id(message_id))
+            .and_then(|message| {
+                let message_id_header = message.header("Message-ID").map(str::to_string);
+                let mail = mime::ParsedMail {
+                    id: message_id.to_string(),
+                    source: entry.metadata.source.to_string(),
+                    account: entry.metadata.account.to_string(),
+                    thread_id: entry
+                        .metadata
+                        .thread_id
+                        .clone()
+                        .or_else(|| cache.thread_id(message_id))
+                        .unwrap_or_else(|| message_id.to_string()),
+                    labels: entry.metadata.labels.clone(),
+                    time: entry.metadata.time,
+                    message,
+                }
+                .to_searchable_mail()?;
+                Ok((mail, message_id_header))
+            }),
+    };
+
+    match mail {
+        Ok((search::Conversion { mut mail, warnings }, message_id_header)) => {
+            if !warnings.is_empty() {
+                eprintln!(
+                    "converted {} message {message_id} despite: {}",
+                    entry.metadata.source,
+                    warnings.join(", ")
+                );
+            }
+            mail.source = entry.metadata.source.to_string();
+            mail.account = entry.metadata.account.to_string();
+            mail.accounts = vec![format!("{}/{}", mail.source, mail.account)];
+            let key = dedupe::key(message_id_header.as_deref(), &mail);
+            state.clear_failure(message_id, state::STAGE_CONVERT)?;
+            Ok(Some((mail, key)))
+        }
+        Err(error) => {
+            eprintln!(
+                "could not convert {} message {message_id} to searchable message: {error}",
+                entry.metadata.source
+            );
+            state.record_failure(message_id, state::STAGE_CONVERT, &error.to_string())?;
+            Ok(None)
+        }
+    }
+}
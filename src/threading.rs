@@ -0,0 +1,285 @@
+use crate::mime::Header;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A Message-ID in the thread forest. Messages that are referenced but not imported (yet) are
+/// kept as empty containers, so their replies already share a root.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Container {
+    parent: Option<String>,
+    #[serde(default)]
+    children: Vec<String>,
+    /// Cached messages with this Message-ID, usually one.
+    #[serde(default)]
+    documents: Vec<String>,
+}
+
+/// Threads for sources without thread ids of their own, following JWZ threading
+/// (https://www.jwz.org/doc/threading.html): messages are linked by References and In-Reply-To,
+/// and replies that lost their references are grouped with the message that started their
+/// subject.
+///
+/// The forest is kept between imports, so a thread id is the hash of the Message-ID at the root
+/// of its tree and stays the same until an earlier message of the thread shows up and the tree
+/// gets a new root.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Threads {
+    containers: BTreeMap<String, Container>,
+    /// Cache message id to Message-ID.
+    documents: BTreeMap<String, String>,
+    /// Normalized subject to the Message-ID of the message that started it.
+    subjects: BTreeMap<String, String>,
+    /// Normalized subject to replies without references that wait for the message that
+    /// started it.
+    orphans: BTreeMap<String, Vec<String>>,
+}
+
+impl Threads {
+    /// Adds a message to the forest and returns the other cached messages whose thread id
+    /// changed, because this message joined their trees.
+    pub fn add(&mut self, document_id: &str, headers: &[Header]) -> Vec<String> {
+        self.remove(document_id);
+
+        let message_id = header(headers, "Message-ID")
+            .and_then(|value| message_ids(value).into_iter().next())
+            // without a Message-ID it can still be the start of a thread by subject.
+            .unwrap_or_else(|| format!("{document_id}@local"));
+
+        let mut references: Vec<String> = header(headers, "References")
+            .map(message_ids)
+            .unwrap_or_default();
+        if let Some(in_reply_to) =
+            header(headers, "In-Reply-To").and_then(|value| message_ids(value).into_iter().next())
+            && references.last() != Some(&in_reply_to)
+        {
+            references.push(in_reply_to);
+        }
+        references.retain(|reference| reference != &message_id);
+
+        let mut regrouped = BTreeSet::new();
+
+        self.containers
+            .entry(message_id.to_string())
+            .or_default()
+            .documents
+            .push(document_id.to_string());
+        self.documents
+            .insert(document_id.to_string(), message_id.to_string());
+
+        // every reference is the parent of the next, unless another message said otherwise.
+        for pair in references.windows(2) {
+            self.containers.entry(pair[0].to_string()).or_default();
+            self.containers.entry(pair[1].to_string()).or_default();
+            let has_parent = self
+                .containers
+                .get(&pair[1])
+                .is_some_and(|container| container.parent.is_some());
+            if !has_parent {
+                self.link(&pair[1], &pair[0], &mut regrouped);
+            }
+        }
+        if let Some(parent) = references.last() {
+            self.containers.entry(parent.to_string()).or_default();
+            self.link(&message_id, parent, &mut regrouped);
+        }
+
+        let (subject, reply) = normalize_subject(header(headers, "Subject").unwrap_or_default());
+        if !subject.is_empty() {
+            self.group_by_subject(&message_id, subject, reply, &mut regrouped);
+        }
+
+        regrouped.remove(document_id);
+        regrouped.into_iter().collect()
+    }
+
+    /// Forgets a cached message, its Message-ID stays in the forest for the replies to it.
+    pub fn remove(&mut self, document_id: &str) {
+        if let Some(message_id) = self.documents.remove(document_id)
+            && let Some(container) = self.containers.get_mut(&message_id)
+        {
+            container
+                .documents
+                .retain(|document| document != document_id);
+        }
+    }
+
+    pub fn thread_id(&self, document_id: &str) -> Option<String> {
+        let message_id = self.documents.get(document_id)?;
+        let root = self.root(message_id);
+        Some(format!(
+            "thread:{}",
+            &format!("{:x}", Sha256::digest(root))[..32]
+        ))
+    }
+
+    fn group_by_subject(
+        &mut self,
+        message_id: &str,
+        subject: String,
+        reply: bool,
+        regrouped: &mut BTreeSet<String>,
+    ) {
+        let is_root = self
+            .containers
+            .get(message_id)
+            .is_some_and(|container| container.parent.is_none());
+
+        if !reply {
+            if self.subjects.contains_key(&subject) {
+                return;
+            }
+            self.subjects
+                .insert(subject.to_string(), message_id.to_string());
+
+            // replies that came first now know where they belong.
+            for orphan in self.orphans.remove(&subject).unwrap_or_default() {
+                let still_root = self
+                    .containers
+                    .get(&orphan)
+                    .is_some_and(|container| container.parent.is_none());
+                if still_root && self.root(message_id) != orphan {
+                    self.link(&orphan, message_id, regrouped);
+                }
+            }
+        } else if is_root {
+            match self.subjects.get(&subject).cloned() {
+                Some(start) if self.root(&start) != message_id => {
+                    self.link(message_id, &start, regrouped)
+                }
+                Some(_) => {}
+                None => {
+                    let orphans = self.orphans.entry(subject).or_default();
+                    if !orphans.iter().any(|orphan| orphan == message_id) {
+                        orphans.push(message_id.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes `parent` the parent of `child`, unless that would make a loop. Documents below
+    /// `child` whose root changes are added to `regrouped`.
+    fn link(&mut self, child: &str, parent: &str, regrouped: &mut BTreeSet<String>) {
+        if child == parent || self.is_ancestor(child, parent) {
+            return;
+        }
+
+        let old_root = self.root(child).to_string();
+
+        let old_parent = self
+            .containers
+            .get_mut(child)
+            .and_then(|container| container.parent.replace(parent.to_string()));
+        if let Some(old_parent) = old_parent
+            && let Some(container) = self.containers.get_mut(&old_parent)
+        {
+            container.children.retain(|existing| existing != child);
+        }
+        if let Some(container) = self.containers.get_mut(parent)
+            && !container.children.iter().any(|existing| existing == child)
+        {
+            container.children.push(child.to_string());
+        }
+
+        if self.root(child) != old_root {
+            self.collect_documents(child, regrouped);
+        }
+    }
+
+    /// Whether `ancestor` is `message_id` or above it.
+    fn is_ancestor(&self, ancestor: &str, message_id: &str) -> bool {
+        let mut current = Some(message_id);
+        let mut depth = 0;
+
+        while let Some(message_id) = current {
+            if message_id == ancestor {
+                return true;
+            }
+            depth += 1;
+            if depth > self.containers.len() {
+                return false;
+            }
+            current = self
+                .containers
+                .get(message_id)
+                .and_then(|container| container.parent.as_deref());
+        }
+
+        false
+    }
+
+    fn root<'a>(&'a self, message_id: &'a str) -> &'a str {
+        let mut root = message_id;
+        // the depth guard only matters for forests written by an older, broken version.
+        for _ in 0..=self.containers.len() {
+            match self
+                .containers
+                .get(root)
+                .and_then(|container| container.parent.as_deref())
+            {
+                Some(parent) => root = parent,
+                None => break,
+            }
+        }
+        root
+    }
+
+    fn collect_documents(&self, message_id: &str, documents: &mut BTreeSet<String>) {
+        let mut pending = vec![message_id];
+        let mut visited = BTreeSet::new();
+
+        while let Some(message_id) = pending.pop() {
+            if !visited.insert(message_id) {
+                continue;
+            }
+            let Some(container) = self.containers.get(message_id) else {
+                continue;
+            };
+            documents.extend(container.documents.iter().cloned());
+            pending.extend(container.children.iter().map(String::as_str));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+/// The ids in a header like References, without their angle brackets.
+pub fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .map(|(message_id, _)| message_id.trim().to_string())
+        .filter(|message_id| !message_id.is_empty())
+        .collect()
+}
+
+/// Strips reply and forward prefixes like `Re:`, `Fwd:` or `AW:` and collapses whitespace.
+/// Returns the subject and whether it had such a prefix.
+fn normalize_subject(subject: &str) -> (String, bool) {
+    const PREFIXES: [&str; 6] = ["re", "fwd", "fw", "aw", "sv", "wg"];
+
+    let mut subject = subject.trim();
+    let mut reply = false;
+
+    while let Some((prefix, rest)) = subject.split_once(':') {
+        // `Re[2]:` counts replies in some clients.
+        let prefix = prefix.trim().split('[').next().unwrap_or_default();
+        if !PREFIXES
+            .iter()
+            .any(|known| prefix.eq_ignore_ascii_case(known))
+        {
+            break;
+        }
+        subject = rest.trim_start();
+        reply = true;
+    }
+
+    let subject = subject.split_whitespace().collect::<Vec<&str>>().join(" ");
+    (subject.to_lowercase(), reply)
+}
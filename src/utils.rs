@@ -1,11 +1,12 @@
 use anyhow::Result;
 use std::{
-    error::Error,
     fs::File,
     io::{BufReader, BufWriter},
 };
 
 use serde::{Serialize, de::DeserializeOwned};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task;
 
 pub fn write_struct_to_file<T: Serialize>(value: &T, path: &str) -> Result<()> {
     let file = File::create(path)?;
@@ -16,7 +17,7 @@ pub fn write_struct_to_file<T: Serialize>(value: &T, path: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn read_json<T>(path: &str) -> Result<T, Box<dyn Error>>
+pub fn read_json<T>(path: &str) -> Result<T, String>
 where
     T: DeserializeOwned,
 {
@@ -26,3 +27,15 @@ where
         .map_err(|error| format!("could not parse '{path}' to struct: {error}"))?;
     Ok(credentials)
 }
+
+/// Runs blocking I/O without stalling the other tasks of a multi-thread runtime. A
+/// current-thread runtime, e.g. the one of `#[tokio::test]`, has no other worker to move them
+/// to, so there it just blocks.
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
@@ -0,0 +1,34 @@
+use mail::MessageCache;
+use mail::cache::{MessageFormat, MessageMetadata};
+use std::fs;
+
+mod common;
+
+use common::TestDirectory;
+
+#[test]
+fn saves_without_leaving_temporary_files() {
+    let directory = TestDirectory::new("cache-save");
+    let path = directory.0.join("cache");
+
+    let mut cache = MessageCache::open(&path).unwrap();
+    let raw = b"From: alice@example.com\r\nMessage-ID: <1@example.com>\r\n\r\nhello\r\n";
+    cache
+        .put("1", MessageMetadata::default(), MessageFormat::Raw, raw)
+        .unwrap();
+    cache.save().unwrap();
+
+    let mut names: Vec<String> = fs::read_dir(&path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["duplicates.json", "index.json", "objects", "threads.json"]
+    );
+
+    let reopened = MessageCache::open(&path).unwrap();
+    assert_eq!(reopened.get("1").unwrap().as_deref(), Some(&raw[..]));
+    assert!(reopened.thread_id("1").is_some());
+}
@@ -0,0 +1,20 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory of its own for every test, removed again when dropped.
+pub struct TestDirectory(pub PathBuf);
+
+impl TestDirectory {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mail-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
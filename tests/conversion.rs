@@ -0,0 +1,46 @@
+use mail::MailError;
+use mail::gmail::Message;
+use mail::mime::{self, ParsedMail};
+use mail::search::{Conversion, Searchable};
+use serde_json::{Value, json};
+use std::fs;
+
+/// What a fixture converts into, or why it does not.
+fn convert(content: &str) -> Value {
+    let message: Message = serde_json::from_str(content).unwrap();
+    snapshot(message.to_searchable_mail())
+}
+
+fn snapshot(conversion: Result<Conversion, MailError>) -> Value {
+    match conversion {
+        Ok(conversion) => json!({ "mail": conversion.mail, "warnings": conversion.warnings }),
+        Err(error) => json!({ "error": error.to_string() }),
+    }
+}
+
+// Gmail API messages as fetched with `format=full`, each with a snapshot of its conversion.
+// Review changed snapshots with `cargo insta review`.
+#[test]
+fn converts_gmail_messages() {
+    insta::glob!("fixtures/messages/*.json", |path| {
+        insta::assert_json_snapshot!(convert(&fs::read_to_string(path).unwrap()));
+    });
+}
+
+// RFC 822 messages as a file import or IMAP hands them over.
+#[test]
+fn converts_raw_messages() {
+    insta::glob!("fixtures/raw/*.eml", |path| {
+        let raw = fs::read(path).unwrap();
+        let parsed = ParsedMail {
+            id: "raw".to_string(),
+            source: "eml".to_string(),
+            account: String::new(),
+            thread_id: "raw".to_string(),
+            labels: Vec::new(),
+            time: None,
+            message: mime::parse(&raw).unwrap(),
+        };
+        insta::assert_json_snapshot!(snapshot(parsed.to_searchable_mail()));
+    });
+}
@@ -0,0 +1,105 @@
+use mail::Query;
+use mail::cache::MessageMetadata;
+use mail::export::{ExportFormat, Exporter};
+use mail::mbox::MboxReader;
+use serde_json::Value;
+use std::fs;
+
+mod common;
+
+use common::TestDirectory;
+
+#[test]
+fn records_missing_messages_in_the_manifest() {
+    let directory = TestDirectory::new("export-missing");
+    let export_directory = directory.0.join("export");
+
+    let mut exporter =
+        Exporter::create(&export_directory, ExportFormat::Eml, &Query::all()).unwrap();
+    let raw = b"From: alice@example.com\r\nSubject: Hello\r\n\r\nhello\r\n";
+    exporter
+        .add("1001", raw, &MessageMetadata::default())
+        .unwrap();
+    exporter.add_missing("1002", "not returned by gmail");
+    assert_eq!(exporter.missing().len(), 1);
+    assert_eq!(exporter.finish().unwrap(), 1);
+
+    let manifest: Value =
+        serde_json::from_slice(&fs::read(export_directory.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["messages"][0]["id"], "1001");
+    assert_eq!(manifest["missing"][0]["id"], "1002");
+    assert_eq!(manifest["missing"][0]["error"], "not returned by gmail");
+}
+
+const LUNCH: &[u8] =
+    b"From: alice@example.com\r\nSubject: Lunch\r\nDate: Mon, 1 Jan 2024 10:00:00 +0000\r\n\r\nFrom the kitchen: noon\r\n";
+const INVOICE: &[u8] =
+    b"From: bob@example.com\r\nSubject: Invoice\r\nDate: Tue, 2 Jan 2024 10:00:00 +0000\r\n\r\ndue friday\r\n";
+
+/// Exports both messages, the invoice starred and read, and returns the manifest.
+fn export(directory: &TestDirectory, format: ExportFormat, ids: [&str; 2]) -> Value {
+    let export_directory = directory.0.join("export");
+    let mut exporter = Exporter::create(&export_directory, format, &Query::all()).unwrap();
+    let unread = MessageMetadata {
+        labels: vec!["inbox".to_string(), "unread".to_string()],
+        ..MessageMetadata::default()
+    };
+    let starred = MessageMetadata {
+        labels: vec!["starred".to_string()],
+        ..MessageMetadata::default()
+    };
+    exporter.add(ids[0], LUNCH, &unread).unwrap();
+    exporter.add(ids[1], INVOICE, &starred).unwrap();
+    assert_eq!(exporter.finish().unwrap(), 2);
+
+    serde_json::from_slice(&fs::read(export_directory.join("manifest.json")).unwrap()).unwrap()
+}
+
+fn exported_file(directory: &TestDirectory, manifest: &Value, index: usize) -> Vec<u8> {
+    let file = manifest["messages"][index]["file"].as_str().unwrap();
+    fs::read(directory.0.join("export").join(file)).unwrap()
+}
+
+#[test]
+fn exports_eml_files_apart_when_their_ids_only_differ_in_unsafe_characters() {
+    let directory = TestDirectory::new("export-eml");
+    let manifest = export(
+        &directory,
+        ExportFormat::Eml,
+        ["imap:me:1:7:Work/Sub", "imap:me:1:7:Work:Sub"],
+    );
+
+    let lunch = manifest["messages"][0]["file"].as_str().unwrap();
+    let invoice = manifest["messages"][1]["file"].as_str().unwrap();
+    assert!(lunch.starts_with("imap_me_1_7_Work_Sub-") && lunch.ends_with(".eml"));
+    assert_ne!(lunch, invoice);
+    assert_eq!(exported_file(&directory, &manifest, 0), LUNCH);
+    assert_eq!(exported_file(&directory, &manifest, 1), INVOICE);
+}
+
+#[test]
+fn exports_an_mbox() {
+    let directory = TestDirectory::new("export-mbox");
+    let manifest = export(&directory, ExportFormat::Mbox, ["1001", "1002"]);
+    assert_eq!(manifest["messages"][1]["file"], "messages.mbox");
+
+    let messages: Vec<Vec<u8>> =
+        MboxReader::open(&directory.0.join("export").join("messages.mbox"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+    assert_eq!(messages, [LUNCH, INVOICE]);
+}
+
+#[test]
+fn exports_a_maildir_flagged_by_labels() {
+    let directory = TestDirectory::new("export-maildir");
+    let manifest = export(&directory, ExportFormat::Maildir, ["1001", "1002"]);
+
+    let lunch = manifest["messages"][0]["file"].as_str().unwrap();
+    let invoice = manifest["messages"][1]["file"].as_str().unwrap();
+    assert!(lunch.starts_with("cur/1704103200.M0.") && lunch.ends_with(":2,"));
+    assert!(invoice.starts_with("cur/1704189600.M1.") && invoice.ends_with(":2,FS"));
+    assert_eq!(exported_file(&directory, &manifest, 0), LUNCH);
+    assert_eq!(exported_file(&directory, &manifest, 1), INVOICE);
+}
@@ -0,0 +1,193 @@
+use mail::cache::MessageFormat;
+use mail::client::GmailClient;
+use mail::gmail::{self, Format, GmailSource};
+use mail::mock_gmail::{Fault, Mailbox, MockGmail, MockMessage};
+use mail::search::Searchable;
+use mail::{MailError, MailSource};
+use std::path::Path;
+
+async fn mock() -> MockGmail {
+    let mailbox = Mailbox::load(Path::new("tests/fixtures/gmail")).unwrap();
+    MockGmail::start(mailbox).await.unwrap()
+}
+
+async fn source(server: &MockGmail, format: Format) -> GmailSource {
+    let client = GmailClient::with_credentials(server.credentials());
+    GmailSource::new(client, format).await.unwrap()
+}
+
+#[tokio::test]
+async fn lists_newest_first_in_pages() {
+    let server = mock().await;
+    let client = GmailClient::with_credentials(server.credentials());
+
+    let first_page = gmail::messages_list(&client, Some(2)).await.unwrap();
+    let ids: Vec<&str> = first_page.messages.iter().map(|m| m.id.as_str()).collect();
+    assert_eq!(ids, ["1003", "1002"]);
+    assert_eq!(first_page.next_page_token.as_deref(), Some("2"));
+
+    let everything = gmail::messages_list(&client, Some(10)).await.unwrap();
+    assert_eq!(everything.messages.len(), 3);
+    assert_eq!(everything.next_page_token, None);
+}
+
+#[tokio::test]
+async fn fetches_raw_messages_in_a_batch() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    assert_eq!(source.account(), "me@example.com");
+
+    let ids = source.list(10).await.unwrap();
+    let messages = source.fetch(&ids).await.unwrap().messages;
+
+    assert_eq!(messages.len(), 3);
+    // the access token is still valid, so the batch does not refresh it.
+    assert_eq!(server.tokens_issued(), 0);
+    let reply = messages
+        .iter()
+        .find(|message| message.id == "1002")
+        .unwrap();
+    assert_eq!(reply.format, MessageFormat::Raw);
+    assert_eq!(reply.metadata.thread_id.as_deref(), Some("1001"));
+    assert_eq!(reply.metadata.label_ids, ["INBOX", "STARRED", "UNREAD"]);
+    // the Date header of the fixture, as Gmail would have received it.
+    assert_eq!(reply.metadata.time, Some(1704187800));
+    assert!(
+        server
+            .requests()
+            .contains(&"GET /gmail/v1/users/me/messages/1002".to_string())
+    );
+}
+
+#[tokio::test]
+async fn fetches_full_messages_that_convert() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Full).await;
+
+    let ids = source.list(10).await.unwrap();
+    for fetched in source.fetch(&ids).await.unwrap().messages {
+        let message: gmail::Message = serde_json::from_slice(&fetched.content).unwrap();
+        let conversion = message.to_searchable_mail().unwrap();
+        assert_eq!(conversion.mail.id, fetched.id);
+        assert_eq!(conversion.mail.time, fetched.metadata.time);
+    }
+}
+
+#[tokio::test]
+async fn follows_the_history() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    let checkpoint = source.checkpoint().await.unwrap();
+
+    server.update(|mailbox| {
+        let raw = b"From: dave@example.com\r\nSubject: New\r\n\r\nhello\r\n";
+        mailbox.add(MockMessage::new("1004", raw));
+        mailbox.delete("1001");
+        mailbox.modify_labels("1003", &["STARRED".to_string()], &[]);
+    });
+    source
+        .modify_labels("1002", &[], &["starred".to_string()])
+        .await
+        .unwrap();
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert_eq!(changes.changed, ["1004", "1003", "1002"]);
+    assert_eq!(changes.deleted, ["1001"]);
+    assert_ne!(changes.checkpoint, checkpoint);
+
+    let unchanged = source.changes(&changes.checkpoint).await.unwrap();
+    assert!(unchanged.changed.is_empty() && unchanged.deleted.is_empty());
+}
+
+#[tokio::test]
+async fn refreshes_the_access_token_after_401() {
+    let server = mock().await;
+    server.inject(Fault::Unauthorized, 1);
+
+    let mut source = source(&server, Format::Raw).await;
+    assert_eq!(server.tokens_issued(), 1);
+    assert_eq!(source.list(10).await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn retries_rate_limits() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    server.inject(Fault::RateLimited, 1);
+
+    assert_eq!(source.list(10).await.unwrap().len(), 3);
+    let lists = server
+        .requests()
+        .iter()
+        .filter(|request| *request == "GET /gmail/v1/users/me/messages")
+        .count();
+    assert_eq!(lists, 2);
+}
+
+#[tokio::test]
+async fn retries_server_errors() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    server.inject(Fault::ServerError, 1);
+
+    assert_eq!(source.checkpoint().await.unwrap(), "4");
+}
+
+#[tokio::test]
+async fn reports_malformed_batch_parts() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    server.inject(Fault::MalformedPart, 1);
+
+    let ids = source.list(10).await.unwrap();
+    let fetched = source.fetch(&ids).await.unwrap();
+    let messages: Vec<&str> = fetched
+        .messages
+        .iter()
+        .map(|message| message.id.as_str())
+        .collect();
+    assert_eq!(messages, ["1002", "1001"]);
+    assert_eq!(fetched.failures.len(), 1);
+    assert_eq!(fetched.failures[0].message_id, "1003");
+    assert!(
+        matches!(fetched.failures[0].error, MailError::Parse { .. }),
+        "{:?}",
+        fetched.failures[0].error
+    );
+}
+
+#[tokio::test]
+async fn retries_rate_limited_batch_parts() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+    server.inject(Fault::RateLimitedPart, 1);
+
+    let ids = source.list(10).await.unwrap();
+    let fetched = source.fetch(&ids).await.unwrap();
+    let mut fetched_ids: Vec<&str> = fetched
+        .messages
+        .iter()
+        .map(|message| message.id.as_str())
+        .collect();
+    fetched_ids.sort();
+    assert_eq!(fetched_ids, ["1001", "1002", "1003"]);
+    assert!(fetched.failures.is_empty());
+    // only the rate limited message is fetched again.
+    let gets = |id: &str| {
+        let request = format!("GET /gmail/v1/users/me/messages/{id}");
+        server.requests().iter().filter(|r| **r == request).count()
+    };
+    assert_eq!((gets("1003"), gets("1002")), (2, 1));
+}
+
+#[tokio::test]
+async fn leaves_out_deleted_messages_of_a_batch() {
+    let server = mock().await;
+    let mut source = source(&server, Format::Raw).await;
+
+    let ids = vec!["1001".to_string(), "gone".to_string()];
+    let fetched = source.fetch(&ids).await.unwrap();
+    assert_eq!(fetched.messages.len(), 1);
+    assert_eq!(fetched.messages[0].id, "1001");
+    assert!(fetched.failures.is_empty());
+}
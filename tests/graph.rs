@@ -0,0 +1,376 @@
+use mail::graph::{GraphClient, GraphSource};
+use mail::{MailError, MailSource};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::TestDirectory;
+
+const MAILBOX: &str = "me@example.com";
+
+/// Folder id to parent folder id and the ids of its messages, with every delta link handed out
+/// so far as the folder and its messages at the time.
+#[derive(Default)]
+struct Mailbox {
+    folders: BTreeMap<String, (Option<String>, Vec<String>)>,
+    delta_links: Vec<(String, Vec<String>)>,
+    /// Requests still to be answered with 429.
+    throttled: usize,
+    /// Messages whose content is answered with 500 or 404, as if it failed or the message was
+    /// deleted after its metadata was read.
+    broken: Vec<String>,
+    vanished: Vec<String>,
+    requests: Vec<String>,
+}
+
+impl Mailbox {
+    fn folder(&mut self, id: &str, parent: Option<&str>, messages: &[&str]) {
+        self.folders.insert(
+            id.to_string(),
+            (
+                parent.map(str::to_string),
+                messages.iter().map(|id| id.to_string()).collect(),
+            ),
+        );
+    }
+
+    fn messages(&mut self, folder: &str) -> &mut Vec<String> {
+        &mut self.folders.get_mut(folder).unwrap().1
+    }
+
+    fn folder_of(&self, message_id: &str) -> Option<&String> {
+        self.folders
+            .iter()
+            .find(|(_, (_, messages))| messages.iter().any(|id| id == message_id))
+            .map(|(folder, _)| folder)
+    }
+
+    fn child_folders(&self, parent: Option<&str>) -> Value {
+        let folders: Vec<Value> = self
+            .folders
+            .iter()
+            .filter(|(_, (folder_parent, _))| folder_parent.as_deref() == parent)
+            .map(|(id, _)| {
+                let children = self
+                    .folders
+                    .values()
+                    .filter(|(folder_parent, _)| folder_parent.as_deref() == Some(id))
+                    .count();
+                json!({
+                    "id": id,
+                    "displayName": format!("{id} folder"),
+                    "childFolderCount": children,
+                })
+            })
+            .collect();
+        json!({ "value": folders })
+    }
+
+    /// The messages of `folder` that are new or gone since `before`, with a new delta link.
+    fn delta(&mut self, base_url: &str, folder: &str, before: &[String]) -> Value {
+        let now = self.folders[folder].1.clone();
+        let mut value: Vec<Value> = now
+            .iter()
+            .filter(|id| !before.contains(id))
+            .map(|id| json!({ "id": id }))
+            .collect();
+        value.extend(
+            before
+                .iter()
+                .filter(|id| !now.contains(id))
+                .map(|id| json!({ "id": id, "@removed": { "reason": "deleted" } })),
+        );
+
+        self.delta_links.push((folder.to_string(), now));
+        let delta_link = format!("{base_url}/delta/{}", self.delta_links.len() - 1);
+        json!({ "value": value, "@odata.deltaLink": delta_link })
+    }
+
+    fn route(&mut self, base_url: &str, path: &str) -> (u16, String) {
+        self.requests.push(path.to_string());
+        if self.throttled > 0 {
+            self.throttled -= 1;
+            return (
+                429,
+                json!({ "error": { "code": "TooManyRequests" } }).to_string(),
+            );
+        }
+
+        let (path, _query) = path.split_once('?').unwrap_or((path, ""));
+        if let Some(index) = path.strip_prefix("/delta/") {
+            let Some((folder, before)) = self.delta_links.get(index.parse::<usize>().unwrap())
+            else {
+                return (
+                    410,
+                    json!({ "error": { "code": "SyncStateNotFound" } }).to_string(),
+                );
+            };
+            let (folder, before) = (folder.clone(), before.clone());
+            return (200, self.delta(base_url, &folder, &before).to_string());
+        }
+
+        let path = path
+            .replace("%40", "@")
+            .strip_prefix(&format!("/users/{MAILBOX}/"))
+            .map(str::to_string)
+            .unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').collect();
+        let not_found = (
+            404,
+            json!({ "error": { "code": "ErrorItemNotFound" } }).to_string(),
+        );
+
+        match segments.as_slice() {
+            ["mailFolders"] => (200, self.child_folders(None).to_string()),
+            ["mailFolders", folder, "childFolders"] => {
+                (200, self.child_folders(Some(folder)).to_string())
+            }
+            ["mailFolders", folder, "messages", "delta"] => {
+                (200, self.delta(base_url, folder, &[]).to_string())
+            }
+            ["mailFolders", folder] if self.folders.contains_key(*folder) => {
+                (200, json!({ "id": folder }).to_string())
+            }
+            ["messages", id] => match self.folder_of(id) {
+                Some(folder) => (
+                    200,
+                    json!({
+                        "id": id,
+                        "conversationId": format!("conversation-{id}"),
+                        "changeKey": "1",
+                        "parentFolderId": folder,
+                        "categories": ["Blue"],
+                        "isRead": false,
+                        "importance": "normal",
+                    })
+                    .to_string(),
+                ),
+                None => not_found,
+            },
+            ["messages", id, "$value"] if self.broken.iter().any(|broken| broken == id) => (
+                500,
+                json!({ "error": { "code": "ErrorInternalServerError" } }).to_string(),
+            ),
+            ["messages", id, "$value"] if self.vanished.iter().any(|vanished| vanished == id) => {
+                not_found
+            }
+            ["messages", id, "$value"] if self.folder_of(id).is_some() => (
+                200,
+                format!(
+                    "From: alice@example.com\r\nSubject: Message {id}\r\nMessage-ID: <{id}@example.com>\r\n\r\nhello\r\n"
+                ),
+            ),
+            _ => not_found,
+        }
+    }
+}
+
+/// Serves `mailbox` like the Graph API on a local port, one request per connection.
+async fn serve(mailbox: Arc<Mutex<Mailbox>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let url = base_url.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+            let (status, body) = mailbox.lock().unwrap().route(&url, path);
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nRetry-After: 0\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    base_url
+}
+
+async fn source(mailbox: &Arc<Mutex<Mailbox>>, directory: &TestDirectory) -> GraphSource {
+    let base_url = serve(mailbox.clone()).await;
+    let credentials = json!({
+        "oauth": { "client_id": "client", "tenant": "common" },
+        "token": {
+            "access_token": "token",
+            "refresh_token": "refresh",
+            "scope": "Mail.ReadWrite",
+            "token_type": "Bearer",
+        },
+        "mailbox": MAILBOX,
+        "base_url": base_url,
+    });
+    let path = directory.0.join("graph.json");
+    fs::write(&path, credentials.to_string()).unwrap();
+
+    GraphSource::new(GraphClient::from_file(&path).unwrap())
+        .await
+        .unwrap()
+}
+
+fn mailbox() -> Arc<Mutex<Mailbox>> {
+    let mut mailbox = Mailbox::default();
+    mailbox.folder("inbox", None, &["m1"]);
+    mailbox.folder("projects", Some("inbox"), &["m2"]);
+    mailbox.folder("archive", Some("projects"), &[]);
+    mailbox.folder("sentitems", None, &["m3"]);
+    mailbox.folder("junkemail", None, &[]);
+    mailbox.folder("deleteditems", None, &[]);
+    Arc::new(Mutex::new(mailbox))
+}
+
+#[tokio::test]
+async fn follows_delta_links_of_nested_folders() {
+    let directory = TestDirectory::new("graph-delta");
+    let mailbox = mailbox();
+    let mut source = source(&mailbox, &directory).await;
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    let delta_links: BTreeMap<String, String> = serde_json::from_str(&checkpoint).unwrap();
+    assert_eq!(
+        delta_links.keys().collect::<Vec<_>>(),
+        [
+            "archive",
+            "deleteditems",
+            "inbox",
+            "junkemail",
+            "projects",
+            "sentitems"
+        ]
+    );
+
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        mailbox.messages("archive").push("m4".to_string());
+        mailbox.messages("inbox").retain(|id| id != "m1");
+        // moved, with an immutable id it is the same message in another folder.
+        mailbox.messages("sentitems").clear();
+        mailbox.messages("inbox").push("m3".to_string());
+    }
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    let mut changed = changes.changed.clone();
+    changed.sort();
+    assert_eq!(changed, ["graph:m3", "graph:m4"]);
+    assert_eq!(changes.deleted, ["graph:m1"]);
+
+    let unchanged = source.changes(&changes.checkpoint).await.unwrap();
+    assert!(unchanged.changed.is_empty() && unchanged.deleted.is_empty());
+
+    let ids = vec!["graph:m3".to_string(), "graph:m1".to_string()];
+    let fetched = source.fetch(&ids).await.unwrap();
+    assert!(fetched.failures.is_empty());
+    assert_eq!(fetched.messages.len(), 1);
+    let message = &fetched.messages[0];
+    assert_eq!(message.id, "graph:m3");
+    assert_eq!(message.metadata.labels, ["inbox", "unread", "Blue"]);
+    assert!(message.content.starts_with(b"From: alice@example.com"));
+}
+
+#[tokio::test]
+async fn waits_when_throttled() {
+    let directory = TestDirectory::new("graph-throttled");
+    let mailbox = mailbox();
+    mailbox.lock().unwrap().throttled = 2;
+
+    source(&mailbox, &directory).await;
+    let requests = mailbox.lock().unwrap().requests.clone();
+    assert!(requests[0].contains("/mailFolders/inbox"), "{requests:?}");
+    // answered 429 twice, so the first request went out three times.
+    assert!(requests[..3].iter().all(|request| *request == requests[0]));
+    assert_ne!(requests[3], requests[0]);
+}
+
+#[tokio::test]
+async fn reports_an_expired_delta_link() {
+    let directory = TestDirectory::new("graph-expired");
+    let mailbox = mailbox();
+    let mut source = source(&mailbox, &directory).await;
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    let mut delta_links: BTreeMap<String, String> = serde_json::from_str(&checkpoint).unwrap();
+    let expired = delta_links["inbox"].replace("/delta/", "/delta/99");
+    delta_links.insert("inbox".to_string(), expired);
+
+    let error = source
+        .changes(&serde_json::to_string(&delta_links).unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(error, MailError::Checkpoint(_)), "{error:?}");
+}
+
+#[tokio::test]
+async fn reports_messages_whose_content_could_not_be_downloaded() {
+    let directory = TestDirectory::new("graph-content");
+    let mailbox = mailbox();
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        mailbox.broken.push("m1".to_string());
+        mailbox.vanished.push("m3".to_string());
+    }
+    let mut source = source(&mailbox, &directory).await;
+
+    let ids = vec![
+        "graph:m1".to_string(),
+        "graph:m2".to_string(),
+        "graph:m3".to_string(),
+        "graph:gone".to_string(),
+    ];
+    let fetched = source.fetch(&ids).await.unwrap();
+
+    // m3 and gone were deleted before they could be read, m1 is worth another try.
+    assert_eq!(fetched.messages.len(), 1);
+    assert_eq!(fetched.messages[0].id, "graph:m2");
+    assert_eq!(fetched.failures.len(), 1);
+    assert_eq!(fetched.failures[0].message_id, "graph:m1");
+    assert!(
+        matches!(
+            fetched.failures[0].error,
+            MailError::Http { status: 500, .. }
+        ),
+        "{:?}",
+        fetched.failures[0].error
+    );
+}
+
+#[tokio::test]
+async fn labels_messages_with_their_custom_folder() {
+    let directory = TestDirectory::new("graph-folders");
+    let mailbox = mailbox();
+    let mut source = source(&mailbox, &directory).await;
+
+    let fetched = source
+        .fetch(&["graph:m2".to_string(), "graph:m3".to_string()])
+        .await
+        .unwrap();
+
+    let labels: Vec<&Vec<String>> = fetched
+        .messages
+        .iter()
+        .map(|message| &message.metadata.labels)
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            &vec!["projects folder", "unread", "Blue"],
+            &vec!["sent", "unread", "Blue"]
+        ]
+    );
+}
@@ -0,0 +1,422 @@
+use mail::MailSource;
+use mail::imap::{
+    ImapAuth, ImapCredentials, ImapSource, Value, format_uid_set, literal_length, parse_message_id,
+    parse_uid_set, parse_values,
+};
+use mail::search::{LABEL_INBOX, LABEL_REPLIED, LABEL_STARRED, LABEL_UNREAD};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const ACCOUNT: &str = "me@example.com";
+
+struct FakeFolder {
+    attributes: String,
+    uid_validity: u64,
+    uid_next: u64,
+    /// Uid to flags and the modseq of the last change.
+    messages: BTreeMap<u64, (Vec<String>, u64)>,
+}
+
+/// A mailbox served over a small subset of IMAP, with CONDSTORE when `condstore` is set.
+#[derive(Default)]
+struct FakeMailbox {
+    condstore: bool,
+    folders: BTreeMap<String, FakeFolder>,
+    modseq: u64,
+    commands: Vec<String>,
+}
+
+impl FakeMailbox {
+    fn folder(&mut self, name: &str, attributes: &str, messages: usize) {
+        self.folders.insert(
+            name.to_string(),
+            FakeFolder {
+                attributes: attributes.to_string(),
+                uid_validity: 1,
+                uid_next: 1,
+                messages: BTreeMap::new(),
+            },
+        );
+        for _ in 0..messages {
+            self.add(name);
+        }
+    }
+
+    fn add(&mut self, folder: &str) -> u64 {
+        self.modseq += 1;
+        let folder = self.folders.get_mut(folder).unwrap();
+        let uid = folder.uid_next;
+        folder.uid_next += 1;
+        folder.messages.insert(uid, (Vec::new(), self.modseq));
+        uid
+    }
+
+    fn flag(&mut self, folder: &str, uid: u64, flag: &str) {
+        self.modseq += 1;
+        let message = self
+            .folders
+            .get_mut(folder)
+            .unwrap()
+            .messages
+            .get_mut(&uid)
+            .unwrap();
+        message.0.push(flag.to_string());
+        message.1 = self.modseq;
+    }
+
+    fn expunge(&mut self, folder: &str, uid: u64) {
+        self.modseq += 1;
+        self.folders.get_mut(folder).unwrap().messages.remove(&uid);
+    }
+
+    /// Answers one tagged command, `selected` is the folder of the session.
+    fn answer(&mut self, line: &str, selected: &mut Option<String>) -> String {
+        self.commands.push(line.to_string());
+        let (tag, command) = line.split_once(' ').unwrap_or((line, ""));
+        let upper = command.to_ascii_uppercase();
+        let ok = format!("{tag} OK done\r\n");
+
+        if upper == "CAPABILITY" {
+            let condstore = if self.condstore { " CONDSTORE" } else { "" };
+            return format!("* CAPABILITY IMAP4rev1{condstore}\r\n{ok}");
+        }
+        if upper.starts_with("LOGIN") || upper.starts_with("ENABLE") {
+            return ok;
+        }
+        if upper == "LOGOUT" {
+            return format!("* BYE\r\n{ok}");
+        }
+        if upper.starts_with("LIST") {
+            let folders: String = self
+                .folders
+                .iter()
+                .map(|(name, folder)| {
+                    format!("* LIST ({}) \"/\" \"{name}\"\r\n", folder.attributes)
+                })
+                .collect();
+            return format!("{folders}{ok}");
+        }
+        if upper.starts_with("SELECT ") {
+            let name = command.split('"').nth(1).unwrap();
+            let Some(folder) = self.folders.get(name) else {
+                return format!("{tag} NO no such folder\r\n");
+            };
+            *selected = Some(name.to_string());
+            let modseq = if self.condstore {
+                format!("* OK [HIGHESTMODSEQ {}]\r\n", self.modseq)
+            } else {
+                String::new()
+            };
+            return format!(
+                "* {} EXISTS\r\n* OK [UIDVALIDITY {}]\r\n* OK [UIDNEXT {}]\r\n{modseq}{ok}",
+                folder.messages.len(),
+                folder.uid_validity,
+                folder.uid_next,
+            );
+        }
+
+        let folder = &self.folders[selected.as_ref().unwrap()];
+        if upper == "UID SEARCH ALL" {
+            let uids: Vec<String> = folder.messages.keys().map(u64::to_string).collect();
+            return format!("* SEARCH {}\r\n{ok}", uids.join(" "));
+        }
+        if let Some(rest) = upper.strip_prefix("UID FETCH ") {
+            let uid_set = rest.split(' ').next().unwrap();
+            let changed_since: u64 = rest
+                .split_once("CHANGEDSINCE ")
+                .map(|(_, modseq)| modseq.trim_end_matches(')').parse().unwrap())
+                .unwrap_or(0);
+            let uids = if uid_set == "1:*" {
+                folder.messages.keys().copied().collect()
+            } else {
+                parse_uid_set(uid_set)
+            };
+
+            let mut responses = String::new();
+            for (number, (uid, (flags, modseq))) in folder.messages.iter().enumerate() {
+                if !uids.contains(uid) || *modseq <= changed_since {
+                    continue;
+                }
+                let mut items = format!("UID {uid} FLAGS ({})", flags.join(" "));
+                if upper.contains("BODY.PEEK[]") {
+                    let body = format!(
+                        "From: alice@example.com\r\nSubject: Message {uid}\r\n\r\nhello\r\n"
+                    );
+                    items.push_str(&format!(" BODY[] {{{}}}\r\n{body}", body.len()));
+                }
+                responses.push_str(&format!("* {} FETCH ({items})\r\n", number + 1));
+            }
+            return format!("{responses}{ok}");
+        }
+
+        format!("{tag} BAD unknown command\r\n")
+    }
+}
+
+fn handle(stream: TcpStream, mailbox: Arc<Mutex<FakeMailbox>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"* OK fake imap ready\r\n").unwrap();
+
+    let mut selected = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+        let response = mailbox
+            .lock()
+            .unwrap()
+            .answer(line.trim_end(), &mut selected);
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+        line.clear();
+    }
+}
+
+/// Serves `mailbox` on a local port, a thread per connection.
+fn serve(mailbox: Arc<Mutex<FakeMailbox>>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                return;
+            };
+            let mailbox = mailbox.clone();
+            thread::spawn(move || handle(stream, mailbox));
+        }
+    });
+
+    port
+}
+
+fn mailbox(condstore: bool) -> Arc<Mutex<FakeMailbox>> {
+    let mut mailbox = FakeMailbox {
+        condstore,
+        ..FakeMailbox::default()
+    };
+    mailbox.folder("INBOX", "\\HasNoChildren", 3);
+    mailbox.folder("Sent", "\\Sent", 2);
+    mailbox.folder("Lists", "\\Noselect", 0);
+    Arc::new(Mutex::new(mailbox))
+}
+
+fn source(mailbox: &Arc<Mutex<FakeMailbox>>) -> ImapSource {
+    ImapSource::connect_with(&ImapCredentials {
+        host: "127.0.0.1".to_string(),
+        port: serve(mailbox.clone()),
+        username: ACCOUNT.to_string(),
+        auth: ImapAuth::Password("secret".to_string()),
+        insecure: true,
+        folders: Vec::new(),
+    })
+    .unwrap()
+}
+
+fn id(uid_validity: u64, uid: u64, folder: &str) -> String {
+    format!("imap:{ACCOUNT}:{uid_validity}:{uid}:{folder}")
+}
+
+fn sorted(mut ids: Vec<String>) -> Vec<String> {
+    ids.sort();
+    ids
+}
+
+/// Deletes, flags and adds a message in the inbox, the changes both kinds of servers report.
+fn change_inbox(mailbox: &Arc<Mutex<FakeMailbox>>) {
+    let mut mailbox = mailbox.lock().unwrap();
+    mailbox.expunge("INBOX", 2);
+    mailbox.flag("INBOX", 3, "\\Flagged");
+    mailbox.add("INBOX");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_deletions_and_flag_changes_without_condstore() {
+    let mailbox = mailbox(false);
+    let mut source = source(&mailbox);
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    change_inbox(&mailbox);
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert_eq!(
+        sorted(changes.changed),
+        [id(1, 3, "INBOX"), id(1, 4, "INBOX")]
+    );
+    assert_eq!(changes.deleted, [id(1, 2, "INBOX")]);
+
+    let unchanged = source.changes(&changes.checkpoint).await.unwrap();
+    assert!(unchanged.changed.is_empty() && unchanged.deleted.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_deletions_and_flag_changes_with_condstore() {
+    let mailbox = mailbox(true);
+    let mut source = source(&mailbox);
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    change_inbox(&mailbox);
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert_eq!(
+        sorted(changes.changed),
+        [id(1, 3, "INBOX"), id(1, 4, "INBOX")]
+    );
+    assert_eq!(changes.deleted, [id(1, 2, "INBOX")]);
+    let commands = mailbox.lock().unwrap().commands.clone();
+    assert!(
+        commands
+            .iter()
+            .any(|command| command.contains("CHANGEDSINCE"))
+    );
+    // flags are only kept in the checkpoint when the server can not report their changes.
+    assert!(!changes.checkpoint.contains("flags"));
+
+    let unchanged = source.changes(&changes.checkpoint).await.unwrap();
+    assert!(unchanged.changed.is_empty() && unchanged.deleted.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_the_old_messages_of_a_recreated_folder() {
+    let mailbox = mailbox(false);
+    let mut source = source(&mailbox);
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        let inbox = mailbox.folders.get_mut("INBOX").unwrap();
+        inbox.uid_validity = 2;
+        inbox.messages.remove(&1);
+    }
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert_eq!(
+        sorted(changes.changed),
+        [id(2, 2, "INBOX"), id(2, 3, "INBOX")]
+    );
+    assert_eq!(
+        sorted(changes.deleted),
+        [id(1, 1, "INBOX"), id(1, 2, "INBOX"), id(1, 3, "INBOX")]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_the_messages_of_a_deleted_folder() {
+    let mailbox = mailbox(false);
+    let mut source = source(&mailbox);
+
+    let checkpoint = source.checkpoint().await.unwrap();
+    mailbox.lock().unwrap().folders.remove("Sent");
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert!(changes.changed.is_empty());
+    assert_eq!(
+        sorted(changes.deleted),
+        [id(1, 1, "Sent"), id(1, 2, "Sent")]
+    );
+    assert!(!changes.checkpoint.contains("Sent"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fetches_messages_with_their_labels() {
+    let mailbox = mailbox(false);
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        mailbox.flag("INBOX", 1, "\\Flagged");
+        mailbox.flag("INBOX", 1, "\\Answered");
+    }
+    let mut source = source(&mailbox);
+
+    let ids = vec![
+        id(1, 1, "INBOX"),
+        "imap:nonsense".to_string(),
+        id(9, 2, "INBOX"),
+    ];
+    let fetched = source.fetch(&ids).await.unwrap();
+
+    assert_eq!(fetched.messages.len(), 1);
+    let message = &fetched.messages[0];
+    assert_eq!(message.id, id(1, 1, "INBOX"));
+    assert_eq!(
+        message.metadata.labels,
+        [LABEL_INBOX, LABEL_UNREAD, LABEL_STARRED, LABEL_REPLIED]
+    );
+    assert!(message.content.starts_with(b"From: alice@example.com"));
+    assert_eq!(fetched.failures.len(), 1);
+    assert_eq!(fetched.failures[0].message_id, "imap:nonsense");
+}
+
+#[test]
+fn parses_response_values() {
+    let values =
+        parse_values(b"3 FETCH (UID 7 FLAGS (\\Seen $Important) BODY[] {5}\r\nhe\"lo ENVELOPE (NIL \"a \\\"b\\\"\"))\r\n")
+            .unwrap();
+
+    assert_eq!(values[0], Value::Atom("3".to_string()));
+    assert_eq!(values[1], Value::Atom("FETCH".to_string()));
+    let Value::List(items) = &values[2] else {
+        panic!("expected a list, got {:?}", values[2]);
+    };
+    assert_eq!(
+        items[..4],
+        [
+            Value::Atom("UID".to_string()),
+            Value::Atom("7".to_string()),
+            Value::Atom("FLAGS".to_string()),
+            Value::List(vec![
+                Value::Atom("\\Seen".to_string()),
+                Value::Atom("$Important".to_string()),
+            ]),
+        ]
+    );
+    assert_eq!(items[4], Value::Atom("BODY[]".to_string()));
+    assert_eq!(items[5], Value::String(b"he\"lo".to_vec()));
+    assert_eq!(
+        items[7],
+        Value::List(vec![Value::Nil, Value::String(b"a \"b\"".to_vec())])
+    );
+
+    assert!(parse_values(b"BODY[] {10}\r\nshort").is_err());
+}
+
+#[test]
+fn reads_literal_lengths() {
+    assert_eq!(literal_length(b"* 1 FETCH (BODY[] {42}\r\n"), Some(42));
+    assert_eq!(literal_length(b"A0001 LOGIN {5+}\n"), Some(5));
+    assert_eq!(literal_length(b"* 1 FETCH (UID 1)\r\n"), None);
+    assert_eq!(literal_length(b"* OK {12}"), None);
+    assert_eq!(literal_length(b"* OK {twelve}\r\n"), None);
+}
+
+#[test]
+fn expands_and_collapses_uid_sets() {
+    assert_eq!(parse_uid_set("1:3,7"), [1, 2, 3, 7]);
+    assert_eq!(parse_uid_set("5:3"), [3, 4, 5]);
+    assert_eq!(parse_uid_set("2,x,4:*"), [2]);
+    assert!(parse_uid_set("").is_empty());
+
+    assert_eq!(format_uid_set(&[7, 1, 2, 3, 3, 9, 10]), "1:3,7,9:10");
+    assert_eq!(format_uid_set(&[]), "");
+    assert_eq!(
+        parse_uid_set(&format_uid_set(&[4, 5, 6, 11])),
+        [4, 5, 6, 11]
+    );
+}
+
+#[test]
+fn parses_message_ids() {
+    assert_eq!(
+        parse_message_id("imap:me@example.com:3857529045:12:INBOX"),
+        Some((3857529045, 12, "INBOX"))
+    );
+    assert_eq!(
+        parse_message_id("imap:me@example.com:1:2:Archive:2024"),
+        Some((1, 2, "Archive:2024"))
+    );
+    assert_eq!(parse_message_id("imap:me@example.com:1:two:INBOX"), None);
+    assert_eq!(parse_message_id("imap:me@example.com:1:2"), None);
+    assert_eq!(parse_message_id("gmail:1:2:INBOX"), None);
+}
@@ -0,0 +1,131 @@
+use mail::{Mail, MemoryIndex, Query, SearchIndex};
+
+fn mail(id: &str, subject: &str, body: &str, time: i64, labels: &[&str]) -> Mail {
+    Mail {
+        id: id.to_string(),
+        thread_id: id.to_string(),
+        subject: Some(subject.to_string()),
+        time: Some(time),
+        labels: labels.iter().map(|label| label.to_string()).collect(),
+        raw_body: format!("<p>{body}</p>"),
+        searchable_body: body.to_string(),
+        from: "alice@example.com".to_string(),
+        to: Some("me@example.com".to_string()),
+        source: "gmail".to_string(),
+        account: "me@example.com".to_string(),
+        accounts: vec!["gmail/me@example.com".to_string()],
+    }
+}
+
+async fn index() -> MemoryIndex {
+    let index = MemoryIndex::new();
+    index
+        .import(&[
+            mail(
+                "1",
+                "Invoice for March",
+                "Please pay by Friday",
+                100,
+                &["inbox"],
+            ),
+            mail(
+                "2",
+                "Lunch",
+                "The invoice is attached",
+                300,
+                &["inbox", "starred"],
+            ),
+            mail("3", "Receipts", "Nothing about money", 200, &["sent"]),
+        ])
+        .await
+        .unwrap();
+    index
+}
+
+async fn search(index: &MemoryIndex, query: Query) -> Vec<String> {
+    index.search(&query).await.unwrap()
+}
+
+#[tokio::test]
+async fn import_replaces_documents_by_id() {
+    let index = index().await;
+    index
+        .import(&[mail("1", "Invoice for April", "", 400, &[])])
+        .await
+        .unwrap();
+
+    assert_eq!(index.len(), 3);
+    let replaced = index.get("1").unwrap();
+    assert_eq!(replaced.subject.as_deref(), Some("Invoice for April"));
+}
+
+#[tokio::test]
+async fn delete_ignores_unknown_ids() {
+    let index = index().await;
+    index
+        .delete(&["2".to_string(), "unknown".to_string()])
+        .await
+        .unwrap();
+
+    assert_eq!(index.ids(), ["1", "3"]);
+}
+
+#[tokio::test]
+async fn everything_is_newest_first() {
+    let index = index().await;
+    assert_eq!(search(&index, Query::all()).await, ["2", "3", "1"]);
+}
+
+#[tokio::test]
+async fn subject_matches_rank_above_body_matches() {
+    let index = index().await;
+    assert_eq!(search(&index, Query::new("invoice")).await, ["1", "2"]);
+    // prefixes match like in Typesense.
+    assert_eq!(search(&index, Query::new("invo")).await, ["1", "2"]);
+}
+
+#[tokio::test]
+async fn every_word_has_to_match() {
+    let index = index().await;
+    assert_eq!(search(&index, Query::new("invoice friday")).await, ["1"]);
+    assert_eq!(search(&index, Query::new("invoice -friday")).await, ["2"]);
+    assert!(
+        search(&index, Query::new("invoice dinner"))
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn filters_by_labels_and_time() {
+    let index = index().await;
+
+    let starred = Query::all().filter_by("labels:=starred");
+    assert_eq!(search(&index, starred).await, ["2"]);
+
+    let either = Query::all().filter_by("labels:[starred,sent]");
+    assert_eq!(search(&index, either).await, ["2", "3"]);
+
+    let recent_inbox = Query::new("invoice").filter_by("labels:=inbox && time:>150");
+    assert_eq!(search(&index, recent_inbox).await, ["2"]);
+
+    let range = Query::all().filter_by("time:[100..200]");
+    assert_eq!(search(&index, range).await, ["3", "1"]);
+
+    let not_sent = Query::all().filter_by("labels:!=sent");
+    assert_eq!(search(&index, not_sent).await, ["2", "1"]);
+}
+
+#[tokio::test]
+async fn rejects_filters_it_cannot_evaluate() {
+    let index = index().await;
+
+    for filter_by in [
+        "labels:=inbox || labels:=sent",
+        "size:>10",
+        "time:>yesterday",
+    ] {
+        let query = Query::all().filter_by(filter_by);
+        assert!(index.search(&query).await.is_err(), "{filter_by}");
+    }
+}
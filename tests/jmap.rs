@@ -0,0 +1,335 @@
+use mail::jmap::{JmapAuth, JmapCredentials, JmapSource};
+use mail::search::{LABEL_INBOX, LABEL_REPLIED, LABEL_STARRED, LABEL_UNREAD};
+use mail::{
+    MailError, MailSource, MemoryIndex, MessageCache, Query, SearchIndex, StateStore, SyncEngine,
+};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::TestDirectory;
+
+const ACCOUNT: &str = "a1";
+const MAX_OBJECTS_IN_GET: usize = 2;
+
+/// Email id to its keywords, with every change since the first state. `Email/changes` can only
+/// be calculated from states after `forgotten`.
+#[derive(Default)]
+struct Mailbox {
+    emails: BTreeMap<String, Vec<String>>,
+    changes: Vec<(String, &'static str)>,
+    forgotten: usize,
+    /// The number of ids of each `Email/get` call.
+    gets: Vec<usize>,
+}
+
+impl Mailbox {
+    fn add(&mut self, id: &str, keywords: &[&str]) {
+        let keywords = keywords.iter().map(|keyword| keyword.to_string()).collect();
+        self.emails.insert(id.to_string(), keywords);
+        self.changes.push((id.to_string(), "created"));
+    }
+
+    fn update(&mut self, id: &str, keyword: &str) {
+        self.emails.get_mut(id).unwrap().push(keyword.to_string());
+        self.changes.push((id.to_string(), "updated"));
+    }
+
+    fn destroy(&mut self, id: &str) {
+        self.emails.remove(id);
+        self.changes.push((id.to_string(), "destroyed"));
+    }
+
+    fn state(&self) -> String {
+        format!("s{}", self.changes.len())
+    }
+
+    fn email(&self, id: &str) -> Value {
+        let keywords: BTreeMap<&str, bool> = self.emails[id]
+            .iter()
+            .map(|keyword| (keyword.as_str(), true))
+            .collect();
+        json!({
+            "id": id,
+            "blobId": format!("blob-{id}"),
+            "threadId": format!("thread-{id}"),
+            "mailboxIds": { "mb-inbox": true },
+            "keywords": keywords,
+        })
+    }
+
+    fn call(&mut self, method: &str, arguments: &Value) -> (String, Value) {
+        match method {
+            "Mailbox/get" => (
+                method.to_string(),
+                json!({
+                    "state": "m1",
+                    "list": [{ "id": "mb-inbox", "name": "Inbox", "role": "inbox" }],
+                }),
+            ),
+            "Email/query" => {
+                let ids: Vec<&String> = self.emails.keys().rev().collect();
+                (method.to_string(), json!({ "ids": ids }))
+            }
+            "Email/get" => {
+                let ids: Vec<String> =
+                    serde_json::from_value(arguments["ids"].clone()).unwrap_or_default();
+                if ids.len() > MAX_OBJECTS_IN_GET {
+                    return ("error".to_string(), json!({ "type": "requestTooLarge" }));
+                }
+                self.gets.push(ids.len());
+                let list: Vec<Value> = ids
+                    .iter()
+                    .filter(|id| self.emails.contains_key(*id))
+                    .map(|id| self.email(id))
+                    .collect();
+                (
+                    method.to_string(),
+                    json!({ "state": self.state(), "list": list }),
+                )
+            }
+            "Email/changes" => {
+                let since: usize = arguments["sinceState"].as_str().unwrap()[1..]
+                    .parse()
+                    .unwrap();
+                if since < self.forgotten {
+                    return (
+                        "error".to_string(),
+                        json!({ "type": "cannotCalculateChanges" }),
+                    );
+                }
+                let mut changes = BTreeMap::<&str, Vec<&String>>::new();
+                for (id, kind) in &self.changes[since..] {
+                    changes.entry(kind).or_default().push(id);
+                }
+                (
+                    method.to_string(),
+                    json!({
+                        "oldState": format!("s{since}"),
+                        "newState": self.state(),
+                        "hasMoreChanges": false,
+                        "created": changes.remove("created").unwrap_or_default(),
+                        "updated": changes.remove("updated").unwrap_or_default(),
+                        "destroyed": changes.remove("destroyed").unwrap_or_default(),
+                    }),
+                )
+            }
+            _ => ("error".to_string(), json!({ "type": "unknownMethod" })),
+        }
+    }
+
+    fn route(&mut self, base_url: &str, path: &str, body: &[u8]) -> (u16, String) {
+        match path {
+            "/session" => (
+                200,
+                json!({
+                    "username": "me@example.com",
+                    "apiUrl": format!("{base_url}/api"),
+                    "downloadUrl": format!("{base_url}/download/{{accountId}}/{{blobId}}/{{name}}?type={{type}}"),
+                    "primaryAccounts": { "urn:ietf:params:jmap:mail": ACCOUNT },
+                    "capabilities": {
+                        "urn:ietf:params:jmap:core": { "maxObjectsInGet": MAX_OBJECTS_IN_GET },
+                    },
+                })
+                .to_string(),
+            ),
+            "/api" => {
+                let request: Value = serde_json::from_slice(body).unwrap();
+                let (method, arguments, call_id) = &request["methodCalls"][0]
+                    .as_array()
+                    .map(|call| (call[0].as_str().unwrap(), &call[1], &call[2]))
+                    .unwrap();
+                let (name, response) = self.call(method, arguments);
+                (
+                    200,
+                    json!({ "methodResponses": [[name, response, call_id]], "sessionState": "1" })
+                        .to_string(),
+                )
+            }
+            path => match path
+                .strip_prefix(&format!("/download/{ACCOUNT}/blob-"))
+                .and_then(|rest| rest.split('/').next())
+            {
+                Some(id) if self.emails.contains_key(id) => (
+                    200,
+                    format!(
+                        "From: alice@example.com\r\nTo: me@example.com\r\nSubject: Message {id}\r\nMessage-ID: <{id}@example.com>\r\n\r\nhello {id}\r\n"
+                    ),
+                ),
+                _ => (404, String::new()),
+            },
+        }
+    }
+}
+
+/// Serves `mailbox` like a JMAP server on a local port, one request per connection.
+async fn serve(mailbox: Arc<Mutex<Mailbox>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let url = base_url.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let body_start = loop {
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break request.len(),
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..body_start]).to_string();
+            let content_length: usize = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse().ok())?
+                })
+                .unwrap_or_default();
+            while request.len() < body_start + content_length {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let path = head.split_whitespace().nth(1).unwrap_or_default();
+
+            let (status, body) = mailbox
+                .lock()
+                .unwrap()
+                .route(&url, path, &request[body_start..]);
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    base_url
+}
+
+async fn source(mailbox: &Arc<Mutex<Mailbox>>) -> JmapSource {
+    let base_url = serve(mailbox.clone()).await;
+    JmapSource::connect_with(JmapCredentials {
+        session_url: format!("{base_url}/session"),
+        auth: JmapAuth::Bearer("token".to_string()),
+    })
+    .await
+    .unwrap()
+}
+
+fn mailbox() -> Arc<Mutex<Mailbox>> {
+    let mut mailbox = Mailbox::default();
+    mailbox.add("e1", &["$seen", "$answered"]);
+    mailbox.add("e2", &["$flagged"]);
+    mailbox.add("e3", &["$seen"]);
+    Arc::new(Mutex::new(mailbox))
+}
+
+fn id(email_id: &str) -> String {
+    format!("jmap:{ACCOUNT}:{email_id}")
+}
+
+#[tokio::test]
+async fn fetches_no_more_emails_at_once_than_the_server_allows() {
+    let mailbox = mailbox();
+    let mut source = source(&mailbox).await;
+
+    let ids = source.list(10).await.unwrap();
+    assert_eq!(ids, [id("e3"), id("e2"), id("e1")]);
+
+    let fetched = source.fetch(&ids).await.unwrap();
+    assert!(fetched.failures.is_empty());
+    assert_eq!(mailbox.lock().unwrap().gets, [2, 1]);
+
+    let labels: BTreeMap<String, Vec<String>> = fetched
+        .messages
+        .into_iter()
+        .map(|message| (message.id, message.metadata.labels))
+        .collect();
+    assert_eq!(labels[&id("e1")], [LABEL_INBOX, LABEL_REPLIED]);
+    assert_eq!(
+        labels[&id("e2")],
+        [LABEL_INBOX, LABEL_STARRED, LABEL_UNREAD]
+    );
+    assert_eq!(labels[&id("e3")], [LABEL_INBOX]);
+}
+
+#[tokio::test]
+async fn follows_email_changes() {
+    let mailbox = mailbox();
+    let mut source = source(&mailbox).await;
+    let checkpoint = source.checkpoint().await.unwrap();
+
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        mailbox.add("e4", &[]);
+        mailbox.update("e2", "$seen");
+        mailbox.destroy("e3");
+        // created and destroyed in between, so it is only a deletion.
+        mailbox.add("e5", &[]);
+        mailbox.destroy("e5");
+    }
+
+    let changes = source.changes(&checkpoint).await.unwrap();
+    assert_eq!(changes.changed, [id("e4"), id("e2")]);
+    assert_eq!(changes.deleted, [id("e3"), id("e5")]);
+    assert_eq!(changes.checkpoint, mailbox.lock().unwrap().state());
+}
+
+#[tokio::test]
+async fn syncs_from_scratch_when_changes_cannot_be_calculated() {
+    let directory = TestDirectory::new("jmap-cannot-calculate");
+    let mailbox = mailbox();
+    let index = MemoryIndex::new();
+    let mut engine = SyncEngine::builder()
+        .source(source(&mailbox).await)
+        .index(index.clone())
+        .cache(MessageCache::open(&directory.0.join("cache")).unwrap())
+        .state(StateStore::open(&directory.0.join("cache").join("state.sqlite3")).unwrap())
+        .limit(100)
+        .build()
+        .unwrap();
+    assert_eq!(engine.sync().await.unwrap().indexed, 3);
+
+    {
+        let mut mailbox = mailbox.lock().unwrap();
+        mailbox.update("e3", "$flagged");
+        mailbox.add("e4", &[]);
+        mailbox.forgotten = mailbox.changes.len();
+    }
+    let checkpoint = engine
+        .state()
+        .checkpoint("jmap", "me@example.com")
+        .unwrap()
+        .unwrap();
+    let error = source(&mailbox)
+        .await
+        .changes(&checkpoint)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, MailError::Checkpoint(_)), "{error:?}");
+
+    // everything is fetched again, so the flag on e3 is not missed either.
+    assert_eq!(engine.sync().await.unwrap().indexed, 4);
+    assert_eq!(
+        index
+            .search(&Query::all().filter_by("labels:=starred"))
+            .await
+            .unwrap(),
+        [id("e2"), id("e3")]
+    );
+    assert_eq!(index.search(&Query::new("e4")).await.unwrap(), [id("e4")]);
+}
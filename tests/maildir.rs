@@ -0,0 +1,69 @@
+use mail::MailSource;
+use mail::maildir::MaildirSource;
+use std::fs;
+
+mod common;
+
+use common::TestDirectory;
+
+const MESSAGE: &str = "From: alice@example.com\r\nSubject: Hello\r\n\r\nhello\r\n";
+
+#[tokio::test]
+async fn maps_flags_and_folders_to_labels_once() {
+    let directory = TestDirectory::new("maildir-flags");
+    let maildir = directory.0.join("Maildir");
+    for folder in ["", ".Flagged"] {
+        for subdirectory in ["cur", "new", "tmp"] {
+            fs::create_dir_all(maildir.join(folder).join(subdirectory)).unwrap();
+        }
+    }
+    fs::write(maildir.join("cur").join("1.host:2,RS"), MESSAGE).unwrap();
+    fs::write(maildir.join(".Flagged/cur").join("2.host:2,F"), MESSAGE).unwrap();
+
+    let mut source = MaildirSource::new(&maildir).unwrap();
+    let ids = source.list(10).await.unwrap();
+    let mut labels: Vec<Vec<String>> = source
+        .fetch(&ids)
+        .await
+        .unwrap()
+        .messages
+        .into_iter()
+        .map(|message| message.metadata.labels)
+        .collect();
+    labels.sort();
+
+    assert_eq!(
+        labels,
+        [vec!["inbox", "replied"], vec!["starred", "unread"]]
+    );
+}
+
+#[tokio::test]
+async fn reports_messages_that_vanished() {
+    let directory = TestDirectory::new("maildir-vanished");
+    let maildir = directory.0.join("Maildir");
+    for subdirectory in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.join(subdirectory)).unwrap();
+    }
+    fs::write(maildir.join("new").join("1.host"), MESSAGE).unwrap();
+    fs::write(maildir.join("new").join("2.host"), MESSAGE).unwrap();
+
+    let mut source = MaildirSource::new(&maildir).unwrap();
+    let mut ids = source.list(10).await.unwrap();
+    ids.sort();
+    // moved away after it was listed.
+    fs::remove_file(maildir.join("new").join("1.host")).unwrap();
+
+    let fetched = source.fetch(&ids).await.unwrap();
+    assert_eq!(fetched.messages.len(), 1);
+    assert_eq!(fetched.messages[0].id, "maildir:Maildir:2.host");
+    assert_eq!(fetched.failures.len(), 1);
+    assert_eq!(fetched.failures[0].message_id, "maildir:Maildir:1.host");
+
+    let fetched = source
+        .fetch(&["maildir:Maildir:3.host".to_string()])
+        .await
+        .unwrap();
+    assert!(fetched.messages.is_empty());
+    assert_eq!(fetched.failures[0].message_id, "maildir:Maildir:3.host");
+}
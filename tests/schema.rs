@@ -0,0 +1,39 @@
+use mail::schema::diff;
+use typesense::field::Field;
+
+fn field(name: &str, optional: bool) -> Field {
+    Field {
+        name: name.to_string(),
+        r#type: "string".to_string(),
+        optional: optional.then_some(true),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn patches_added_optional_and_removed_fields() {
+    let live = [field("subject", false), field("folder", false)];
+    let wanted = [field("subject", false), field("to", true)];
+
+    let diff = diff(&live, &wanted);
+    assert_eq!(diff.removed, ["folder"]);
+    assert!(diff.is_compatible());
+}
+
+#[test]
+fn rebuilds_for_added_required_fields() {
+    let live = [field("subject", false)];
+    let wanted = [field("subject", false), field("account", false)];
+
+    assert!(!diff(&live, &wanted).is_compatible());
+}
+
+#[test]
+fn rebuilds_for_changed_fields() {
+    let live = [field("subject", false)];
+    let wanted = [field("subject", true)];
+
+    let diff = diff(&live, &wanted);
+    assert_eq!(diff.changed.len(), 1);
+    assert!(!diff.is_compatible());
+}
@@ -0,0 +1,130 @@
+use mail::search::{ImportFailure, ImportReport};
+use mail::state::{STAGE_CONVERT, STAGE_INDEX};
+use mail::{Mail, StateStore};
+
+mod common;
+
+use common::TestDirectory;
+
+fn mail(id: &str, subject: &str) -> Mail {
+    Mail {
+        id: id.to_string(),
+        thread_id: id.to_string(),
+        subject: Some(subject.to_string()),
+        time: Some(100),
+        labels: vec!["inbox".to_string()],
+        raw_body: "hello".to_string(),
+        searchable_body: "hello".to_string(),
+        from: "alice@example.com".to_string(),
+        to: Some("me@example.com".to_string()),
+        source: "gmail".to_string(),
+        account: "me@example.com".to_string(),
+        accounts: vec!["gmail/me@example.com".to_string()],
+    }
+}
+
+fn rejected(id: &str, error: &str) -> ImportReport {
+    ImportReport {
+        imported: 0,
+        failures: vec![ImportFailure {
+            id: id.to_string(),
+            error: error.to_string(),
+        }],
+    }
+}
+
+#[test]
+fn checkpoints_are_replaced_and_kept() {
+    let directory = TestDirectory::new("state-checkpoints");
+    let path = directory.0.join("state.sqlite3");
+
+    let state = StateStore::open(&path).unwrap();
+    state
+        .set_checkpoint("gmail", "me@example.com", "1")
+        .unwrap();
+    state
+        .set_checkpoint("gmail", "me@example.com", "2")
+        .unwrap();
+    state
+        .set_checkpoint("gmail", "me@example.com", "2")
+        .unwrap();
+    state
+        .set_checkpoint("imap", "me@example.com", "{}")
+        .unwrap();
+    drop(state);
+
+    let state = StateStore::open(&path).unwrap();
+    assert_eq!(
+        state.checkpoint("gmail", "me@example.com").unwrap(),
+        Some("2".to_string())
+    );
+    assert_eq!(
+        state.checkpoint("imap", "me@example.com").unwrap(),
+        Some("{}".to_string())
+    );
+    assert_eq!(state.checkpoint("gmail", "you@example.com").unwrap(), None);
+}
+
+#[test]
+fn recording_an_import_twice_changes_nothing() {
+    let state = StateStore::open_in_memory().unwrap();
+    let mails = [mail("1", "Lunch"), mail("2", "Invoice")];
+    let report = ImportReport {
+        imported: 2,
+        failures: Vec::new(),
+    };
+
+    state.record_import(&mails, &report).unwrap();
+    let hash = state.indexed_hash("1").unwrap();
+    state.record_import(&mails, &report).unwrap();
+
+    assert_eq!(state.indexed_hash("1").unwrap(), hash);
+    assert!(mails.iter().all(|mail| state.is_indexed(mail).unwrap()));
+    assert!(!state.is_indexed(&mail("1", "Dinner")).unwrap());
+    assert!(state.failures().unwrap().is_empty());
+}
+
+#[test]
+fn a_rejected_document_fails_once_until_it_is_imported() {
+    let state = StateStore::open_in_memory().unwrap();
+    let mails = [mail("1", "Lunch")];
+
+    state
+        .record_import(&mails, &rejected("1", "bad field"))
+        .unwrap();
+    state
+        .record_import(&mails, &rejected("1", "still a bad field"))
+        .unwrap();
+    let failures = state.failures().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].stage, STAGE_INDEX);
+    assert_eq!(failures[0].error, "still a bad field");
+    assert!(!state.is_indexed(&mails[0]).unwrap());
+
+    let imported = ImportReport {
+        imported: 1,
+        failures: Vec::new(),
+    };
+    state.record_import(&mails, &imported).unwrap();
+    assert!(state.failures().unwrap().is_empty());
+    assert!(state.is_indexed(&mails[0]).unwrap());
+}
+
+#[test]
+fn failures_are_kept_once_per_stage() {
+    let state = StateStore::open_in_memory().unwrap();
+
+    state.record_failure("1", STAGE_CONVERT, "no From").unwrap();
+    state.record_failure("1", STAGE_CONVERT, "no From").unwrap();
+    state.record_failure("1", STAGE_INDEX, "rejected").unwrap();
+    assert_eq!(state.failures().unwrap().len(), 2);
+
+    state.clear_failure("1", STAGE_CONVERT).unwrap();
+    state.clear_failure("1", STAGE_CONVERT).unwrap();
+    let failures = state.failures().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].stage, STAGE_INDEX);
+
+    state.remove_indexed(&["1".to_string()]).unwrap();
+    assert!(state.failures().unwrap().is_empty());
+}
@@ -0,0 +1,286 @@
+use mail::client::GmailClient;
+use mail::eml;
+use mail::gmail::{Format, GmailSource};
+use mail::maildir::MaildirSource;
+use mail::mock_gmail::{Fault, Mailbox, MockGmail, MockMessage};
+use mail::state::{STAGE_CONVERT, STAGE_FETCH};
+use mail::{
+    MailError, MailSource, MemoryIndex, MessageCache, Query, SearchIndex, StateStore, SyncEngine,
+};
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+use common::TestDirectory;
+
+fn message(subject: &str, body: &str, date: &str) -> String {
+    format!(
+        "From: alice@example.com\r\nTo: me@example.com\r\nSubject: {subject}\r\nDate: {date}\r\nMessage-ID: <{}@example.com>\r\nMIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary=alt\r\n\r\n--alt\r\nContent-Type: text/plain\r\n\r\n{body}\r\n--alt\r\nContent-Type: text/html\r\n\r\n<p>{body}</p>\r\n--alt--\r\n",
+        subject.to_lowercase().replace(' ', "-")
+    )
+}
+
+fn engine<S: MailSource>(source: S, index: &MemoryIndex, directory: &Path) -> SyncEngine<S> {
+    SyncEngine::builder()
+        .source(source)
+        .index(index.clone())
+        .cache(MessageCache::open(&directory.join("cache")).unwrap())
+        .state(StateStore::open(&directory.join("cache").join("state.sqlite3")).unwrap())
+        .limit(100)
+        .build()
+        .unwrap()
+}
+
+async fn search(index: &MemoryIndex, query: Query) -> Vec<String> {
+    index.search(&query).await.unwrap()
+}
+
+// on a current-thread runtime, which blocking sources have to work on as well.
+#[tokio::test]
+async fn syncs_a_maildir() {
+    let directory = TestDirectory::new("sync-maildir");
+    let maildir = directory.0.join("Maildir");
+    for folder in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.join(folder)).unwrap();
+    }
+    let deliver = |name: &str, content: &str| fs::write(maildir.join("new").join(name), content);
+    deliver(
+        "1.host",
+        &message("Lunch", "Friday at noon?", "Mon, 1 Jan 2024 10:00:00 +0000"),
+    )
+    .unwrap();
+    deliver(
+        "2.host",
+        &message("Invoice", "Due on Friday", "Tue, 2 Jan 2024 10:00:00 +0000"),
+    )
+    .unwrap();
+    // no From header, so it cannot be converted.
+    deliver(
+        "3.host",
+        "To: me@example.com\r\nSubject: Anonymous\r\n\r\nonly text\r\n",
+    )
+    .unwrap();
+
+    let index = MemoryIndex::new();
+    let mut engine = engine(MaildirSource::new(&maildir).unwrap(), &index, &directory.0);
+
+    let report = engine.sync().await.unwrap();
+    assert_eq!(report.indexed, 2);
+    assert_eq!(
+        search(&index, Query::new("friday")).await,
+        ["maildir:Maildir:2.host", "maildir:Maildir:1.host"]
+    );
+    let failures = engine.state().failures().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].message_id, "maildir:Maildir:3.host");
+    assert_eq!(failures[0].stage, STAGE_CONVERT);
+
+    // nothing changed, so nothing is imported again.
+    assert_eq!(engine.sync().await.unwrap().indexed, 0);
+
+    fs::remove_file(maildir.join("new").join("1.host")).unwrap();
+    let report = engine.sync().await.unwrap();
+    assert_eq!(report.deleted, ["maildir:Maildir:1.host"]);
+    assert_eq!(index.ids(), ["maildir:Maildir:2.host"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn syncs_gmail_and_follows_its_history() {
+    let directory = TestDirectory::new("sync-gmail");
+    let server = MockGmail::start(Mailbox::load(Path::new("tests/fixtures/gmail")).unwrap())
+        .await
+        .unwrap();
+    let client = GmailClient::with_credentials(server.credentials());
+    let source = GmailSource::new(client, Format::Raw).await.unwrap();
+
+    let index = MemoryIndex::new();
+    let mut engine = engine(source, &index, &directory.0);
+
+    let report = engine.sync().await.unwrap();
+    assert_eq!(report.indexed, 3);
+    assert_eq!(index.ids(), ["1001", "1002", "1003"]);
+    assert_eq!(
+        search(&index, Query::all().filter_by("labels:=starred")).await,
+        ["1002"]
+    );
+    // sent to Bcc only, which no longer keeps it out of the index.
+    assert_eq!(index.get("1002").unwrap().to, None);
+
+    server.update(|mailbox| {
+        let raw = message(
+            "Dinner",
+            "Saturday instead?",
+            "Thu, 4 Jan 2024 10:00:00 +0000",
+        );
+        mailbox.add(MockMessage::new("1004", raw.as_bytes()));
+        mailbox.delete("1001");
+    });
+
+    let report = engine.sync().await.unwrap();
+    assert_eq!(report.deleted, ["1001"]);
+    assert_eq!(index.ids(), ["1002", "1003", "1004"]);
+    assert_eq!(search(&index, Query::new("saturday")).await, ["1004"]);
+}
+
+#[tokio::test]
+async fn says_which_source_failed() {
+    let directory = TestDirectory::new("sync-failed");
+    let maildir = directory.0.join("Maildir");
+    for folder in ["cur", "new", "tmp"] {
+        fs::create_dir_all(maildir.join(folder)).unwrap();
+    }
+
+    let index = MemoryIndex::new();
+    let mut engine = engine(MaildirSource::new(&maildir).unwrap(), &index, &directory.0);
+    fs::remove_dir_all(&maildir).unwrap();
+
+    let error = engine.sync().await.unwrap_err();
+    let MailError::Sync { source_name, .. } = &error else {
+        panic!("expected a sync error, got {error:?}");
+    };
+    assert_eq!(source_name, "maildir");
+    assert!(matches!(error.root(), MailError::Io(_)), "{error:?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn syncs_from_scratch_once_the_history_expired() {
+    let directory = TestDirectory::new("sync-expired");
+    let server = MockGmail::start(Mailbox::load(Path::new("tests/fixtures/gmail")).unwrap())
+        .await
+        .unwrap();
+    let client = GmailClient::with_credentials(server.credentials());
+    let source = GmailSource::new(client, Format::Raw).await.unwrap();
+
+    let index = MemoryIndex::new();
+    let mut engine = engine(source, &index, &directory.0);
+    assert_eq!(engine.sync().await.unwrap().indexed, 3);
+
+    server.update(|mailbox| {
+        mailbox.delete("1001");
+        mailbox.expire_history();
+    });
+
+    let report = engine.sync().await.unwrap();
+    assert_eq!(report.deleted, ["1001"]);
+    assert_eq!(index.ids(), ["1002", "1003"]);
+    let checkpoint = engine
+        .state()
+        .checkpoint("gmail", "me@example.com")
+        .unwrap();
+    assert_eq!(
+        checkpoint,
+        Some(server.update(|mailbox| mailbox.history_id()).to_string())
+    );
+
+    // any other failure is reported and leaves the checkpoint as it was.
+    server.inject(Fault::Unauthorized, 10);
+    let error = engine.sync().await.unwrap_err();
+    assert!(matches!(error.root(), MailError::Auth(_)), "{error:?}");
+    assert_eq!(
+        engine
+            .state()
+            .checkpoint("gmail", "me@example.com")
+            .unwrap(),
+        checkpoint
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn records_messages_that_could_not_be_fetched() {
+    let directory = TestDirectory::new("sync-fetch-failure");
+    let server = MockGmail::start(Mailbox::load(Path::new("tests/fixtures/gmail")).unwrap())
+        .await
+        .unwrap();
+    let client = GmailClient::with_credentials(server.credentials());
+    let source = GmailSource::new(client, Format::Raw).await.unwrap();
+    server.inject(Fault::MalformedPart, 1);
+
+    let index = MemoryIndex::new();
+    let mut engine = engine(source, &index, &directory.0);
+    assert_eq!(engine.sync().await.unwrap().indexed, 2);
+    let failures = engine.state().failures().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].message_id, "1003");
+    assert_eq!(failures[0].stage, STAGE_FETCH);
+
+    // it is still among the most recent messages, so the next sync fetches it again.
+    assert_eq!(engine.sync().await.unwrap().indexed, 1);
+    assert!(engine.state().failures().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn records_files_that_could_not_be_imported() {
+    let directory = TestDirectory::new("sync-import-failures");
+    let lunch = directory.0.join("lunch.eml");
+    fs::write(
+        &lunch,
+        message("Lunch", "Friday at noon?", "Mon, 1 Jan 2024 10:00:00 +0000"),
+    )
+    .unwrap();
+    // no header parses, so it cannot be converted.
+    let broken = directory.0.join("broken.eml");
+    fs::write(&broken, "not a header\r\n\r\nbody\r\n").unwrap();
+    let missing = directory.0.join("missing.eml");
+
+    let index = MemoryIndex::new();
+    let mut engine = SyncEngine::builder()
+        .index(index.clone())
+        .cache(MessageCache::open(&directory.0.join("cache")).unwrap())
+        .state(StateStore::open(&directory.0.join("cache").join("state.sqlite3")).unwrap())
+        .build()
+        .unwrap();
+
+    let messages = [&lunch, &broken, &missing]
+        .into_iter()
+        .map(|path| eml::fetched_message(path, "archive"));
+    assert_eq!(engine.import(messages).await.unwrap().indexed, 1);
+
+    let mut failures = engine.state().failures().unwrap();
+    failures.sort_by(|a, b| a.stage.cmp(&b.stage));
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0].stage, STAGE_CONVERT);
+    assert!(failures[0].message_id.starts_with("eml:"));
+    assert_eq!(failures[1].stage, STAGE_FETCH);
+    assert_eq!(failures[1].message_id, missing.display().to_string());
+}
+
+#[tokio::test]
+async fn collapses_the_same_message_from_several_accounts_into_one_document() {
+    let directory = TestDirectory::new("sync-duplicates");
+    let lunch = message("Lunch", "Friday at noon?", "Mon, 1 Jan 2024 10:00:00 +0000");
+    let mut maildirs = Vec::new();
+    for account in ["Personal", "Shared"] {
+        let maildir = directory.0.join(account);
+        for folder in ["cur", "new", "tmp"] {
+            fs::create_dir_all(maildir.join(folder)).unwrap();
+        }
+        fs::write(maildir.join("new").join(format!("{account}.host")), &lunch).unwrap();
+        maildirs.push(maildir);
+    }
+    let archived = directory.0.join("lunch.eml");
+    fs::write(&archived, &lunch).unwrap();
+
+    let index = MemoryIndex::new();
+    for maildir in &maildirs {
+        let mut engine = engine(MaildirSource::new(maildir).unwrap(), &index, &directory.0);
+        engine.sync().await.unwrap();
+    }
+    let mut engine = SyncEngine::builder()
+        .index(index.clone())
+        .cache(MessageCache::open(&directory.0.join("cache")).unwrap())
+        .state(StateStore::open(&directory.0.join("cache").join("state.sqlite3")).unwrap())
+        .build()
+        .unwrap();
+    engine
+        .import([eml::fetched_message(&archived, "archive")].into_iter())
+        .await
+        .unwrap();
+
+    assert_eq!(index.ids(), ["maildir:Personal:Personal.host"]);
+    let mail = index.get("maildir:Personal:Personal.host").unwrap();
+    assert_eq!(
+        mail.accounts,
+        ["maildir/Personal", "maildir/Shared", "eml/archive"]
+    );
+}
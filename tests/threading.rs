@@ -0,0 +1,143 @@
+use mail::MessageCache;
+use mail::cache::{MessageFormat, MessageMetadata};
+use std::path::Path;
+
+mod common;
+
+use common::TestDirectory;
+
+/// Caches a raw message with `headers`, returns the messages whose thread changed.
+fn put(cache: &mut MessageCache, id: &str, headers: &str) -> Vec<String> {
+    let raw = format!("From: alice@example.com\r\n{headers}\r\n\r\nhello\r\n");
+    cache
+        .put(
+            id,
+            MessageMetadata::default(),
+            MessageFormat::Raw,
+            raw.as_bytes(),
+        )
+        .unwrap()
+}
+
+fn thread(cache: &MessageCache, id: &str) -> String {
+    cache.thread_id(id).unwrap()
+}
+
+fn open(path: &Path) -> MessageCache {
+    MessageCache::open(&path.join("cache")).unwrap()
+}
+
+#[test]
+fn merges_threads_once_a_missing_parent_arrives() {
+    let directory = TestDirectory::new("threading-parent");
+    let mut cache = open(&directory.0);
+
+    put(
+        &mut cache,
+        "1",
+        "Message-ID: <a@example.com>\r\nSubject: Plans",
+    );
+    put(
+        &mut cache,
+        "3",
+        "Message-ID: <c@example.com>\r\nIn-Reply-To: <b@example.com>\r\nSubject: Tuesday",
+    );
+    assert_ne!(thread(&cache, "1"), thread(&cache, "3"));
+
+    let regrouped = put(
+        &mut cache,
+        "2",
+        "Message-ID: <b@example.com>\r\nIn-Reply-To: <a@example.com>\r\nSubject: Monday",
+    );
+    assert_eq!(regrouped, ["3"]);
+    assert_eq!(thread(&cache, "1"), thread(&cache, "2"));
+    assert_eq!(thread(&cache, "1"), thread(&cache, "3"));
+}
+
+#[test]
+fn keeps_thread_ids_across_imports() {
+    let directory = TestDirectory::new("threading-stable");
+
+    let mut cache = open(&directory.0);
+    put(
+        &mut cache,
+        "1",
+        "Message-ID: <a@example.com>\r\nSubject: Plans",
+    );
+    put(
+        &mut cache,
+        "2",
+        "Message-ID: <b@example.com>\r\nReferences: <a@example.com>\r\nSubject: Re: Plans",
+    );
+    let thread_id = thread(&cache, "1");
+    cache.save().unwrap();
+
+    let mut cache = open(&directory.0);
+    assert_eq!(thread(&cache, "2"), thread_id);
+    let regrouped = put(
+        &mut cache,
+        "3",
+        "Message-ID: <c@example.com>\r\nReferences: <a@example.com> <b@example.com>\r\nSubject: Re: Plans",
+    );
+    assert!(regrouped.is_empty());
+    assert_eq!(thread(&cache, "1"), thread_id);
+    assert_eq!(thread(&cache, "3"), thread_id);
+}
+
+#[test]
+fn groups_replies_without_references_by_subject() {
+    let directory = TestDirectory::new("threading-subject");
+    let mut cache = open(&directory.0);
+
+    put(
+        &mut cache,
+        "1",
+        "Message-ID: <a@example.com>\r\nSubject: Lunch on Friday",
+    );
+    put(
+        &mut cache,
+        "2",
+        "Message-ID: <b@example.com>\r\nSubject: RE: Re[2]:  lunch on   friday",
+    );
+    put(
+        &mut cache,
+        "3",
+        "Message-ID: <c@example.com>\r\nSubject: Re: Dinner",
+    );
+    assert_eq!(thread(&cache, "1"), thread(&cache, "2"));
+    assert_ne!(thread(&cache, "1"), thread(&cache, "3"));
+
+    // the reply came first and joins the message that started the subject.
+    let regrouped = put(
+        &mut cache,
+        "4",
+        "Message-ID: <d@example.com>\r\nSubject: Dinner",
+    );
+    assert_eq!(regrouped, ["3"]);
+    assert_eq!(thread(&cache, "3"), thread(&cache, "4"));
+}
+
+#[test]
+fn survives_reference_loops() {
+    let directory = TestDirectory::new("threading-loops");
+    let mut cache = open(&directory.0);
+
+    put(
+        &mut cache,
+        "1",
+        "Message-ID: <a@example.com>\r\nIn-Reply-To: <b@example.com>\r\nSubject: One",
+    );
+    put(
+        &mut cache,
+        "2",
+        "Message-ID: <b@example.com>\r\nIn-Reply-To: <a@example.com>\r\nSubject: Two",
+    );
+    assert_eq!(thread(&cache, "1"), thread(&cache, "2"));
+
+    put(
+        &mut cache,
+        "3",
+        "Message-ID: <c@example.com>\r\nReferences: <c@example.com> <a@example.com> <b@example.com> <a@example.com>\r\nSubject: Three",
+    );
+    assert_eq!(thread(&cache, "3"), thread(&cache, "1"));
+}